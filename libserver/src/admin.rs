@@ -0,0 +1,92 @@
+use serde_json::Value;
+
+/// A chat line starting with `/`, typed by an admin to change their own
+/// account while testing.
+#[derive(Debug)]
+pub enum AdminCommand {
+    /// `/gold [amount]`
+    Gold(i32),
+    /// `/gems [amount]`
+    Gems(i32),
+    /// `/th <level>`
+    TownHall(i32),
+    /// `/skip <seconds>`: lets the time pass for the home, like being away.
+    Skip(i32),
+    /// `/reset`: back to the starting home and a fresh avatar.
+    Reset,
+    /// `/log <account id> [count]`: the account's latest commands. Only the
+    /// low part of the id is given, the high one is this server's.
+    Log { account_low_id: i32, count: i32 },
+    /// `/rotate <account id>`: replaces the account's pass token, logging
+    /// out every device that knows the old one.
+    Rotate { account_low_id: i32 },
+}
+
+impl AdminCommand {
+    const DEFAULT_GOLD: i32 = 1_000_000;
+    const DEFAULT_GEMS: i32 = 100_000;
+    const MAX_TOWN_HALL_LEVEL: i32 = 11;
+    const DEFAULT_LOG_LENGTH: i32 = 10;
+    const MAX_LOG_LENGTH: i32 = 50;
+
+    /// `None` if `text` isn't a command at all, so it should be posted as is.
+    pub fn parse(text: &str) -> Option<Result<Self, String>> {
+        let mut words = text.strip_prefix('/')?.split_whitespace();
+        let name = words.next().unwrap_or_default();
+
+        let arguments = match words.map(str::parse::<i32>).collect::<Result<Vec<_>, _>>() {
+            Ok(arguments) if arguments.iter().all(|argument| *argument >= 0) => arguments,
+            Ok(_) => return Some(Err(format!("negative argument for /{name}"))),
+            Err(err) => return Some(Err(format!("invalid argument for /{name}: {err}"))),
+        };
+
+        let command = match (name, arguments.as_slice()) {
+            ("gold", []) => Ok(Self::Gold(Self::DEFAULT_GOLD)),
+            ("gold", [amount]) => Ok(Self::Gold(*amount)),
+            ("gems", []) => Ok(Self::Gems(Self::DEFAULT_GEMS)),
+            ("gems", [amount]) => Ok(Self::Gems(*amount)),
+            ("th", [level @ 1..=Self::MAX_TOWN_HALL_LEVEL]) => Ok(Self::TownHall(*level)),
+            ("th", _) => Err(format!("/th needs a level from 1 to {}", Self::MAX_TOWN_HALL_LEVEL)),
+            ("skip", [seconds]) => Ok(Self::Skip(*seconds)),
+            ("skip", _) => Err("/skip needs a number of seconds".to_string()),
+            ("reset", []) => Ok(Self::Reset),
+            ("log", [account_low_id]) => Ok(Self::Log {
+                account_low_id: *account_low_id,
+                count: Self::DEFAULT_LOG_LENGTH,
+            }),
+            ("log", [account_low_id, count]) => Ok(Self::Log {
+                account_low_id: *account_low_id,
+                count: (*count).min(Self::MAX_LOG_LENGTH),
+            }),
+            ("log", _) => Err("/log needs an account id and optionally a count".to_string()),
+            ("rotate", [account_low_id]) => Ok(Self::Rotate {
+                account_low_id: *account_low_id,
+            }),
+            ("rotate", _) => Err("/rotate needs an account id".to_string()),
+            ("gold" | "gems" | "reset", _) => Err(format!("too many arguments for /{name}")),
+            _ => Err(format!("unknown command /{name}")),
+        };
+
+        Some(command)
+    }
+}
+
+/// Returns the home with its town hall at `level`, or `None` if it has no
+/// town hall.
+pub fn set_town_hall_level(home_json: &str, level: i32) -> Option<String> {
+    /// Global id of the town hall in `logic/buildings.csv`.
+    const TOWN_HALL_DATA_ID: i64 = 1_000_001;
+
+    let mut home = serde_json::from_str::<Value>(home_json).ok()?;
+
+    let town_hall = home
+        .get_mut("buildings")?
+        .as_array_mut()?
+        .iter_mut()
+        .find(|building| building.get("data").and_then(Value::as_i64) == Some(TOWN_HALL_DATA_ID))?;
+
+    // Levels are stored zero-based.
+    town_hall["lvl"] = Value::from(level - 1);
+
+    Some(home.to_string())
+}
@@ -1,3 +1,5 @@
+use crate::malloc;
+
 #[repr(C)]
 pub struct LogicArrayList<T> {
     pub data: *const T,
@@ -12,4 +14,25 @@ where
     pub fn as_slice(&self) -> &[T] {
         unsafe { std::slice::from_raw_parts(self.data, self.count) }
     }
+
+    /// Builds the list in libg-owned memory, so it can be handed to a
+    /// message that frees it after sending.
+    pub fn new_on_heap(items: Vec<T>) -> *const Self {
+        let count = items.len();
+        let data = malloc(std::mem::size_of::<T>() * count.max(1)) as *mut T;
+        for (i, item) in items.into_iter().enumerate() {
+            unsafe { data.add(i).write(item) }
+        }
+
+        let list = malloc(std::mem::size_of::<Self>()) as *mut Self;
+        unsafe {
+            list.write(Self {
+                data,
+                _capacity: count,
+                count,
+            })
+        };
+
+        list
+    }
 }
@@ -0,0 +1,250 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    ServerState,
+    database::{DatabaseConnection, PlayerSaveData},
+    math::LogicLong,
+    online::OnlinePlayers,
+    time_util::get_current_timestamp,
+};
+
+const BUNDLE_FORMAT: &str = "magic-rs/player";
+const BUNDLE_VERSION: i32 = 1;
+
+const UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize)]
+pub struct PlayerBundle {
+    pub format: String,
+    pub version: i32,
+    pub id: BundleAccountId,
+    pub home: serde_json::Value,
+    pub avatar: BundleAvatar,
+    pub score: i32,
+    pub last_save_timestamp: i64,
+    pub exported_at: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BundleAccountId {
+    pub high: i32,
+    pub low: i32,
+}
+
+/// `blob` is what gets imported; the other fields are decoded from it
+/// only so the bundle can be read without the game.
+#[derive(Serialize, Deserialize)]
+pub struct BundleAvatar {
+    pub blob: String,
+    pub name: Option<String>,
+    pub exp_level: i32,
+    pub town_hall_level: i32,
+    pub score: i32,
+    pub diamonds: i32,
+}
+
+impl PlayerBundle {
+    pub fn from_save_data(data: &PlayerSaveData) -> io::Result<Self> {
+        let logic_client_avatar = data.decode_client_avatar().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "client avatar blob is not valid base64",
+            )
+        })?;
+
+        Ok(Self {
+            format: BUNDLE_FORMAT.to_string(),
+            version: BUNDLE_VERSION,
+            id: BundleAccountId {
+                high: data.id.higher_int,
+                low: data.id.lower_int,
+            },
+            home: serde_json::from_str(&data.home_json)?,
+            avatar: BundleAvatar {
+                blob: data.client_avatar_blob.clone(),
+                name: logic_client_avatar.get_name(),
+                exp_level: logic_client_avatar.get_exp_level(),
+                town_hall_level: logic_client_avatar.get_town_hall_level(),
+                score: logic_client_avatar.get_score(),
+                diamonds: logic_client_avatar.get_diamonds(),
+            },
+            score: data.score,
+            last_save_timestamp: data.last_save_timestamp,
+            exported_at: get_current_timestamp(),
+        })
+    }
+
+    pub fn into_save_data(self) -> io::Result<PlayerSaveData> {
+        if self.format != BUNDLE_FORMAT || self.version != BUNDLE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported bundle {} v{}", self.format, self.version),
+            ));
+        }
+
+        let player_data = PlayerSaveData {
+            id: LogicLong::new(self.id.high, self.id.low),
+            pass_token_hash: String::new(),
+            home_json: self.home.to_string(),
+            client_avatar_blob: self.avatar.blob,
+            score: self.score,
+            last_save_timestamp: self.last_save_timestamp,
+            name: self.avatar.name.unwrap_or_default(),
+            exp_level: self.avatar.exp_level,
+            shield_end_timestamp: 0,
+            guard_end_timestamp: 0,
+            // Imported accounts never come with admin rights.
+            is_admin: false,
+        };
+
+        player_data
+            .validate()
+            .map_err(|reason| io::Error::new(io::ErrorKind::InvalidData, reason))?;
+
+        Ok(player_data)
+    }
+}
+
+pub fn export_player(db: &DatabaseConnection, id: &LogicLong, dir: &Path) -> io::Result<PathBuf> {
+    let player_data = db
+        .fetch_player(id)
+        .map_err(io::Error::other)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("player {id} not found")))?;
+
+    let bundle = PlayerBundle::from_save_data(&player_data)?;
+
+    let dir = dir.join("export");
+    fs::create_dir_all(&dir)?;
+
+    let path = dir.join(format!("{}-{}.json", id.higher_int, id.lower_int));
+    fs::write(&path, serde_json::to_vec_pretty(&bundle)?)?;
+
+    info!("exported player {id} to {}", path.display());
+    Ok(path)
+}
+
+/// Exports every player requested by an empty `export/{high}-{low}.request`
+/// file, then imports every bundle dropped into `import/` (keeping its
+/// account id) or `import_as_new/` (allocating a new one). Processed files
+/// are renamed so they are only applied once; credentials of newly created
+/// accounts are written next to them. Imports over an account that is
+/// online or being attacked are left for a later pass.
+pub fn process_pending_bundles(db: &DatabaseConnection, online_players: &OnlinePlayers, dir: &Path) {
+    if let Ok(entries) = fs::read_dir(dir.join("export")) {
+        for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+            if path
+                .extension()
+                .is_none_or(|extension| extension != "request")
+            {
+                continue;
+            }
+
+            let id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.split_once('-'))
+                .and_then(|(high, low)| {
+                    Some(LogicLong::new(high.parse().ok()?, low.parse().ok()?))
+                });
+
+            let Some(id) = id else {
+                error!("malformed export request {}", path.display());
+                continue;
+            };
+
+            if let Err(err) = export_player(db, &id, dir) {
+                error!("failed to export player {id}: {err}");
+            }
+
+            let _ = fs::remove_file(&path);
+        }
+    }
+
+    for (subdir, preserve_id) in [("import", true), ("import_as_new", false)] {
+        let Ok(entries) = fs::read_dir(dir.join(subdir)) else {
+            continue;
+        };
+
+        for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+
+            match import_bundle(db, online_players, &path, preserve_id) {
+                Ok(true) => {
+                    let _ = fs::rename(&path, path.with_extension("json.imported"));
+                }
+                Ok(false) => (),
+                Err(err) => {
+                    error!("failed to import bundle {}: {err}", path.display());
+                    let _ = fs::rename(&path, path.with_extension("json.failed"));
+                }
+            }
+        }
+    }
+}
+
+/// Picks up bundles dropped while the server runs, forever.
+pub fn run_timer(state: &ServerState, dir: &Path) {
+    info!("bundle timer started");
+
+    loop {
+        thread::sleep(UPDATE_INTERVAL);
+        process_pending_bundles(&state.db, &state.online_players, dir);
+    }
+}
+
+/// Returns `false` if the import has to wait for the account to be free.
+fn import_bundle(
+    db: &DatabaseConnection,
+    online_players: &OnlinePlayers,
+    path: &Path,
+    preserve_id: bool,
+) -> io::Result<bool> {
+    let bundle: PlayerBundle = serde_json::from_slice(&fs::read(path)?)?;
+    let player_data = bundle.into_save_data()?;
+
+    // A live session or a defense save would undo the import, so the
+    // account is held like a defender until it's written.
+    let attack_id = match preserve_id.then(|| online_players.try_begin_attack(&player_data.id)) {
+        Some(None) => {
+            info!("import of {} deferred: player {} is busy", path.display(), player_data.id);
+            return Ok(false);
+        }
+        attack_id => attack_id.flatten(),
+    };
+
+    let imported = db.import_player_data(&player_data, preserve_id);
+
+    if let Some(attack_id) = attack_id {
+        online_players.end_attack(&player_data.id, attack_id);
+    }
+
+    let Some((id, pass_token)) = imported.map_err(io::Error::other)? else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "account id {} doesn't belong to this server",
+                player_data.id
+            ),
+        ));
+    };
+
+    if let Some(pass_token) = pass_token {
+        fs::write(
+            path.with_extension("credentials"),
+            format!("account_id={}\npass_token={pass_token}\n", id.to_long()),
+        )?;
+    }
+
+    info!("imported bundle {} as player {id}", path.display());
+    Ok(true)
+}
@@ -1,25 +1,459 @@
-use rand::distr::{Alphanumeric, SampleString};
+use std::sync::{
+    Mutex, MutexGuard,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use rand::{
+    RngCore,
+    distr::{Alphanumeric, SampleString},
+};
 use rusqlite::{params, Connection, Result};
-use tracing::error;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tracing::{error, info, warn};
 
 use crate::{
-    byte_stream::ByteStream, logic::avatar::LogicClientAvatar, math::LogicLong,
+    byte_stream::ByteStream, logic::{avatar::LogicClientAvatar, data::LogicResourceData}, math::LogicLong,
     resources::ResourceManager, sc_string::StringBuilder, time_util::get_current_timestamp
 };
 
-pub struct DatabaseConnection(Connection);
+pub struct DatabaseConnection {
+    writer: Mutex<Connection>,
+    readers: Vec<Mutex<Connection>>,
+    next_reader: AtomicUsize,
+    high_id: i32,
+}
 
+#[derive(Clone)]
 pub struct PlayerSaveData {
     pub id: LogicLong,
-    pub pass_token: String,
+    pub pass_token_hash: String,
     pub home_json: String,
     pub client_avatar_blob: String,
     pub score: i32,
-    pub last_save_timestamp: i64
+    pub last_save_timestamp: i64,
+    pub name: String,
+    pub exp_level: i32,
+    pub shield_end_timestamp: i64,
+    pub guard_end_timestamp: i64,
+    /// Lets the player use chat commands. Only ever set by hand in the database.
+    pub is_admin: bool,
+}
+
+pub struct PlayerRankingData {
+    pub id: LogicLong,
+    pub name: String,
+    pub exp_level: i32,
+    pub score: i32,
+}
+
+/// Values match the client's alliance roles.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AllianceRole {
+    Member = 1,
+    Leader = 2,
+    Elder = 3,
+    CoLeader = 4,
+}
+
+impl AllianceRole {
+    pub fn from_i32(value: i32) -> Option<Self> {
+        match value {
+            1 => Some(Self::Member),
+            2 => Some(Self::Leader),
+            3 => Some(Self::Elder),
+            4 => Some(Self::CoLeader),
+            _ => None,
+        }
+    }
+
+    /// Members can only manage members of a lower rank.
+    pub fn rank(self) -> i32 {
+        match self {
+            Self::Member => 0,
+            Self::Elder => 1,
+            Self::CoLeader => 2,
+            Self::Leader => 3,
+        }
+    }
+}
+
+/// What the leader and co-leaders can change after creation.
+pub struct AllianceSettings {
+    pub description: String,
+    pub badge_id: i32,
+    /// 1: anyone can join, 2: invite only, 3: closed.
+    pub alliance_type: i32,
+    pub required_score: i32,
+}
+
+pub struct AllianceData {
+    pub id: LogicLong,
+    pub name: String,
+    pub settings: AllianceSettings,
+}
+
+/// An alliance with what the lists show next to it.
+pub struct AllianceHeaderData {
+    pub alliance: AllianceData,
+    pub member_count: i32,
+    pub score: i32,
+}
+
+/// What a search narrows the alliances down to; `None` fields don't filter.
+#[derive(Default)]
+pub struct AllianceSearchFilter {
+    pub name: Option<String>,
+    pub min_score: i32,
+    pub min_member_count: i32,
+    pub max_member_count: Option<i32>,
+    /// Only alliances a player with this score can join right away.
+    pub joinable_with_score: Option<i32>,
+}
+
+#[derive(Clone)]
+pub struct AllianceMemberData {
+    pub id: LogicLong,
+    pub role: AllianceRole,
+    pub name: String,
+    pub exp_level: i32,
+    pub score: i32,
+}
+
+/// Values match the client's alliance event stream entry types.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AllianceEventType {
+    Kicked = 1,
+    Accepted = 2,
+    Joined = 3,
+    Left = 4,
+    Promoted = 5,
+    Demoted = 6,
+}
+
+impl AllianceEventType {
+    pub fn from_i32(value: i32) -> Option<Self> {
+        match value {
+            1 => Some(Self::Kicked),
+            2 => Some(Self::Accepted),
+            3 => Some(Self::Joined),
+            4 => Some(Self::Left),
+            5 => Some(Self::Promoted),
+            6 => Some(Self::Demoted),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum AllianceStreamEntryKind {
+    Chat {
+        message: String,
+    },
+    /// A clan castle request, `used_capacity` grows with each donation.
+    DonateRequest {
+        message: String,
+        total_capacity: i32,
+        used_capacity: i32,
+    },
+    /// The sender is the member the event is about, the event avatar is
+    /// whoever caused it (themselves for joining and leaving).
+    Event {
+        event_type: AllianceEventType,
+        event_avatar_id: LogicLong,
+        event_avatar_name: String,
+    },
+}
+
+/// One entry of an alliance's shared stream. Sender fields are copied at
+/// the time of posting, like the client shows them.
+#[derive(Clone)]
+pub struct AllianceStreamEntryData {
+    /// Assigned by the database when the entry is saved.
+    pub id: i64,
+    pub alliance_id: LogicLong,
+    pub sender_id: LogicLong,
+    pub sender_name: String,
+    pub sender_exp_level: i32,
+    pub sender_role: AllianceRole,
+    pub created_at: i64,
+    pub kind: AllianceStreamEntryKind,
+}
+
+impl AllianceStreamEntryData {
+    pub fn new(alliance_id: &LogicLong, sender: &AllianceMemberData, kind: AllianceStreamEntryKind) -> Self {
+        Self {
+            id: 0,
+            alliance_id: alliance_id.clone(),
+            sender_id: sender.id.clone(),
+            sender_name: sender.name.clone(),
+            sender_exp_level: sender.exp_level,
+            sender_role: sender.role,
+            created_at: get_current_timestamp(),
+            kind,
+        }
+    }
+}
+
+/// Until when a defender can't be matched against: first by their shield,
+/// then by the guard that follows it.
+pub struct ShieldTimers {
+    pub shield_end_timestamp: i64,
+    pub guard_end_timestamp: i64,
+}
+
+/// One finished battle, NPC or multiplayer. The JSON columns hold what libg
+/// generated for each side's log and are sent to the client as is.
+pub struct BattleLogEntry {
+    /// Assigned by the database when the entry is saved.
+    pub id: i64,
+    pub attacker_id: LogicLong,
+    /// `None` for NPC battles.
+    pub defender_id: Option<LogicLong>,
+    pub attacker_name: String,
+    pub attacker_exp_level: i32,
+    pub defender_name: String,
+    pub defender_exp_level: i32,
+    pub start_timestamp: i64,
+    pub end_timestamp: i64,
+    pub stars: i32,
+    pub destruction_percentage: i32,
+    pub stolen_gold: i32,
+    pub stolen_elixir: i32,
+    pub stolen_dark_elixir: i32,
+    pub attacker_score_delta: i32,
+    pub defender_score_delta: i32,
+    pub attacker_log_json: String,
+    pub defender_log_json: String,
+    /// Whether the defender already took revenge for this battle.
+    pub revenge_used: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WarState {
+    Preparation = 1,
+    Battle = 2,
+    Ended = 3,
+}
+
+impl WarState {
+    pub fn from_i32(value: i32) -> Option<Self> {
+        match value {
+            1 => Some(Self::Preparation),
+            2 => Some(Self::Battle),
+            3 => Some(Self::Ended),
+            _ => None,
+        }
+    }
+}
+
+/// Stored per side once the war is over; 0 until then.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WarResult {
+    Won = 1,
+    Lost = 2,
+    Draw = 3,
+}
+
+/// An alliance waiting for a war opponent.
+pub struct WarSearchData {
+    pub alliance_id: LogicLong,
+    pub war_data_id: i32,
+    pub team_size: i32,
+    pub started_at: i64,
+}
+
+pub struct WarData {
+    pub id: i64,
+    pub state: WarState,
+    pub battle_start_timestamp: i64,
+    pub end_timestamp: i64,
+    /// Both sides, side 0 first.
+    pub sides: Vec<WarSideData>,
+}
+
+pub struct WarSideData {
+    pub side: i32,
+    /// `None` for bot alliances.
+    pub alliance_id: Option<LogicLong>,
+    pub name: String,
+    pub badge_id: i32,
+    /// Best stars and average best destruction against the other side's bases.
+    pub stars: i32,
+    pub destruction_percentage: i32,
+}
+
+/// A member's home as it was when the war started, and how they are doing.
+pub struct WarMemberData {
+    /// 1 for the strongest base of the side.
+    pub position: i32,
+    pub player_id: LogicLong,
+    pub name: String,
+    pub exp_level: i32,
+    pub score: i32,
+    pub home_json: String,
+    pub client_avatar_blob: String,
+    pub attacks_used: i32,
+    /// Best attack against this member's base.
+    pub best_stars: i32,
+    pub best_destruction_percentage: i32,
+}
+
+impl WarMemberData {
+    /// The avatar as it was when the war started.
+    pub fn decode_client_avatar(&self) -> Option<LogicClientAvatar> {
+        let data = rbase64::decode(&self.client_avatar_blob).ok()?;
+
+        let mut logic_client_avatar = LogicClientAvatar::new();
+        logic_client_avatar.decode(&mut ByteStream::from(&data));
+        logic_client_avatar.set_id(&self.player_id);
+
+        Some(logic_client_avatar)
+    }
+}
+
+/// The two sides of a war about to start. Members are in position order.
+pub struct WarSetup {
+    pub war_data_id: i32,
+    pub battle_start_timestamp: i64,
+    pub end_timestamp: i64,
+    pub sides: [WarSideSetup; 2],
+}
+
+pub struct WarSideSetup {
+    pub alliance_id: Option<LogicLong>,
+    pub name: String,
+    pub badge_id: i32,
+    pub members: Vec<PlayerSaveData>,
+}
+
+/// A client command as the server executed it.
+pub struct CommandLogEntry {
+    pub player_id: LogicLong,
+    pub command_type: i32,
+    /// The decoded command, as JSON.
+    pub parameters_json: String,
+    pub execute_sub_tick: i32,
+    pub timestamp: i64,
+    /// How many commands ran in the same sub tick. The resources are read
+    /// before and after the whole sub tick, so they're shared by all of them.
+    pub sub_tick_command_count: i32,
+    /// Before and after the sub-tick the command ran in.
+    pub resources_before: AvatarResources,
+    pub resources_after: AvatarResources,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AvatarResources {
+    pub gold: i32,
+    pub elixir: i32,
+    pub dark_elixir: i32,
+    pub diamonds: i32,
+}
+
+impl AvatarResources {
+    pub fn from_avatar(avatar: &LogicClientAvatar) -> Self {
+        Self {
+            gold: avatar.get_resource_count(&LogicResourceData::gold()),
+            elixir: avatar.get_resource_count(&LogicResourceData::elixir()),
+            dark_elixir: avatar.get_resource_count(&LogicResourceData::dark_elixir()),
+            diamonds: avatar.get_diamonds(),
+        }
+    }
+}
+
+/// Avatar fields mirrored into `t_player_data` on every save, so rankings,
+/// matchmaking and admin queries don't have to decode avatar blobs.
+struct AvatarColumns {
+    name: String,
+    exp_level: i32,
+    town_hall_level: i32,
+    score: i32,
+}
+
+impl AvatarColumns {
+    fn from_avatar(avatar: &LogicClientAvatar) -> Self {
+        Self {
+            name: avatar.get_name().unwrap_or_default(),
+            exp_level: avatar.get_exp_level(),
+            town_hall_level: avatar.get_town_hall_level(),
+            score: avatar.get_score(),
+        }
+    }
+}
+
+impl PlayerSaveData {
+    pub fn decode_client_avatar(&self) -> Option<LogicClientAvatar> {
+        let data = rbase64::decode(&self.client_avatar_blob).ok()?;
+
+        let mut logic_client_avatar = LogicClientAvatar::new();
+        logic_client_avatar.decode(&mut ByteStream::from(&data));
+        logic_client_avatar.set_id(&self.id);
+
+        Some(logic_client_avatar)
+    }
+
+    /// Catches what would otherwise crash a session (or libg) on login.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        match rbase64::decode(&self.client_avatar_blob) {
+            Ok(data) if data.is_empty() => return Err("client avatar blob is empty".to_string()),
+            Ok(_) => (),
+            Err(err) => return Err(format!("client avatar blob is not valid base64: {err:?}")),
+        }
+
+        match serde_json::from_str::<serde_json::Value>(&self.home_json) {
+            Ok(serde_json::Value::Object(_)) => Ok(()),
+            Ok(_) => Err("home json is not an object".to_string()),
+            Err(err) => Err(format!("home json doesn't parse: {err}")),
+        }
+    }
+
+    pub fn verify_pass_token(&self, pass_token: &str) -> bool {
+        let Some((salt, hash)) = self.pass_token_hash.split_once('$') else {
+            return false;
+        };
+
+        let (Ok(salt), Ok(hash)) = (rbase64::decode(salt), rbase64::decode(hash)) else {
+            return false;
+        };
+
+        hash_pass_token_with_salt(&salt, pass_token)
+            .ct_eq(&hash)
+            .into()
+    }
+}
+
+fn generate_pass_token() -> String {
+    const PASS_TOKEN_LENGTH: usize = 40;
+    Alphanumeric.sample_string(&mut rand::rng(), PASS_TOKEN_LENGTH)
+}
+
+// Stored as "{salt}${hash}", both base64. The base64 alphabet has no '$',
+// so legacy plaintext tokens (alphanumeric) are told apart by its absence.
+fn hash_pass_token(pass_token: &str) -> String {
+    const SALT_LENGTH: usize = 16;
+
+    let mut salt = [0u8; SALT_LENGTH];
+    rand::rng().fill_bytes(&mut salt);
+
+    let hash = hash_pass_token_with_salt(&salt, pass_token);
+    format!("{}${}", rbase64::encode(&salt), rbase64::encode(&hash))
+}
+
+fn hash_pass_token_with_salt(salt: &[u8], pass_token: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(pass_token.as_bytes());
+    hasher.finalize().to_vec()
 }
 
 impl DatabaseConnection {
-    pub fn connect(path: &str) -> Result<Self> {
+    /// How many attacks and how many defenses a player's battle log shows.
+    pub const BATTLE_LOG_LENGTH: usize = 20;
+    const READER_COUNT: usize = 4;
+    const STATEMENT_CACHE_CAPACITY: usize = 32;
+
+    pub fn connect(path: &str, high_id: i32) -> Result<Self> {
         const INIT_QUERY: &str = r#"
             CREATE TABLE IF NOT EXISTS t_player_data (
                 id INTEGER PRIMARY KEY,
@@ -27,117 +461,2172 @@ impl DatabaseConnection {
                 home_json TEXT NOT NULL,
                 client_avatar_blob TEXT NOT NULL,
                 score INTEGER NOT NULL,
-                last_save_timestamp BIGINT NOT NULL
+                last_save_timestamp BIGINT NOT NULL,
+                name TEXT NOT NULL DEFAULT '',
+                exp_level INTEGER NOT NULL DEFAULT 0,
+                town_hall_level INTEGER NOT NULL DEFAULT 0,
+                shield_end_timestamp BIGINT NOT NULL DEFAULT 0,
+                guard_end_timestamp BIGINT NOT NULL DEFAULT 0,
+                is_admin INTEGER NOT NULL DEFAULT 0
+            );
+
+            CREATE TABLE IF NOT EXISTS t_player_data_quarantine (
+                id INTEGER PRIMARY KEY,
+                pass_token TEXT NOT NULL,
+                home_json TEXT NOT NULL,
+                client_avatar_blob TEXT NOT NULL,
+                score INTEGER NOT NULL,
+                last_save_timestamp BIGINT NOT NULL,
+                reason TEXT NOT NULL,
+                quarantined_at BIGINT NOT NULL,
+                name TEXT NOT NULL DEFAULT '',
+                exp_level INTEGER NOT NULL DEFAULT 0,
+                town_hall_level INTEGER NOT NULL DEFAULT 0,
+                shield_end_timestamp BIGINT NOT NULL DEFAULT 0,
+                guard_end_timestamp BIGINT NOT NULL DEFAULT 0,
+                is_admin INTEGER NOT NULL DEFAULT 0
+            );
+
+            -- Last id handed out per id kind and high id. Unlike MAX(id) it
+            -- never goes back, so the id of a deleted or quarantined row
+            -- isn't given to a new one.
+            CREATE TABLE IF NOT EXISTS t_id_sequence (
+                name TEXT NOT NULL,
+                base_id INTEGER NOT NULL,
+                last_id INTEGER NOT NULL,
+                PRIMARY KEY (name, base_id)
+            );
+
+            CREATE TABLE IF NOT EXISTS t_battle_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                attacker_id INTEGER NOT NULL,
+                defender_id INTEGER,
+                attacker_name TEXT NOT NULL,
+                attacker_exp_level INTEGER NOT NULL,
+                defender_name TEXT NOT NULL,
+                defender_exp_level INTEGER NOT NULL,
+                start_timestamp BIGINT NOT NULL,
+                end_timestamp BIGINT NOT NULL,
+                stars INTEGER NOT NULL,
+                destruction_percentage INTEGER NOT NULL,
+                stolen_gold INTEGER NOT NULL,
+                stolen_elixir INTEGER NOT NULL,
+                stolen_dark_elixir INTEGER NOT NULL,
+                attacker_score_delta INTEGER NOT NULL,
+                defender_score_delta INTEGER NOT NULL,
+                attacker_log_json TEXT NOT NULL,
+                defender_log_json TEXT NOT NULL,
+                revenge_used INTEGER NOT NULL DEFAULT 0
+            );
+
+            CREATE TABLE IF NOT EXISTS t_alliance (
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                description TEXT NOT NULL,
+                badge_id INTEGER NOT NULL,
+                type INTEGER NOT NULL,
+                required_score INTEGER NOT NULL,
+                created_at BIGINT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS t_alliance_member (
+                player_id INTEGER PRIMARY KEY,
+                alliance_id INTEGER NOT NULL REFERENCES t_alliance (id),
+                role INTEGER NOT NULL,
+                joined_at BIGINT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS t_battle_replay (
+                battle_log_id INTEGER PRIMARY KEY REFERENCES t_battle_log (id),
+                replay BLOB NOT NULL
+            );
+
+            -- Alliance score as the client expects it: the best 10 members
+            -- count for 50% of their trophies, the next ranks for 25%, 12%,
+            -- 10% and 3%.
+            CREATE VIEW IF NOT EXISTS v_alliance_score AS
+            SELECT alliance_id, COUNT(*) AS member_count,
+                SUM(score * CASE
+                    WHEN position <= 10 THEN 50
+                    WHEN position <= 20 THEN 25
+                    WHEN position <= 30 THEN 12
+                    WHEN position <= 40 THEN 10
+                    ELSE 3
+                END) / 100 AS score
+            FROM (
+                SELECT m.alliance_id, p.score,
+                    ROW_NUMBER() OVER (PARTITION BY m.alliance_id ORDER BY p.score DESC) AS position
+                FROM t_alliance_member m JOIN t_player_data p ON p.id = m.player_id
             )
+            GROUP BY alliance_id;
+
+            CREATE TABLE IF NOT EXISTS t_war_search (
+                alliance_id INTEGER PRIMARY KEY REFERENCES t_alliance (id),
+                war_data_id INTEGER NOT NULL,
+                team_size INTEGER NOT NULL,
+                started_at BIGINT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS t_war (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                war_data_id INTEGER NOT NULL,
+                state INTEGER NOT NULL,
+                battle_start_timestamp BIGINT NOT NULL,
+                end_timestamp BIGINT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS t_war_side (
+                war_id INTEGER NOT NULL REFERENCES t_war (id),
+                side INTEGER NOT NULL,
+                alliance_id INTEGER,
+                name TEXT NOT NULL,
+                badge_id INTEGER NOT NULL,
+                result INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (war_id, side)
+            );
+
+            CREATE TABLE IF NOT EXISTS t_war_member (
+                war_id INTEGER NOT NULL REFERENCES t_war (id),
+                side INTEGER NOT NULL,
+                position INTEGER NOT NULL,
+                player_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                exp_level INTEGER NOT NULL,
+                score INTEGER NOT NULL,
+                home_json TEXT NOT NULL,
+                client_avatar_blob TEXT NOT NULL,
+                attacks_used INTEGER NOT NULL DEFAULT 0,
+                stars_earned INTEGER NOT NULL DEFAULT 0,
+                best_stars INTEGER NOT NULL DEFAULT 0,
+                best_destruction_percentage INTEGER NOT NULL DEFAULT 0,
+                reward_gold INTEGER NOT NULL DEFAULT 0,
+                reward_elixir INTEGER NOT NULL DEFAULT 0,
+                reward_claimed INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (war_id, side, position)
+            );
+
+            CREATE TABLE IF NOT EXISTS t_war_attack (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                war_id INTEGER NOT NULL REFERENCES t_war (id),
+                attacker_side INTEGER NOT NULL,
+                attacker_position INTEGER NOT NULL,
+                defender_position INTEGER NOT NULL,
+                stars INTEGER NOT NULL,
+                new_stars INTEGER NOT NULL,
+                destruction_percentage INTEGER NOT NULL,
+                timestamp BIGINT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS t_command_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                player_id INTEGER NOT NULL,
+                command_type INTEGER NOT NULL,
+                parameters_json TEXT NOT NULL,
+                execute_sub_tick INTEGER NOT NULL,
+                timestamp BIGINT NOT NULL,
+                gold_before INTEGER NOT NULL,
+                elixir_before INTEGER NOT NULL,
+                dark_elixir_before INTEGER NOT NULL,
+                diamonds_before INTEGER NOT NULL,
+                gold_after INTEGER NOT NULL,
+                elixir_after INTEGER NOT NULL,
+                dark_elixir_after INTEGER NOT NULL,
+                diamonds_after INTEGER NOT NULL,
+                sub_tick_command_count INTEGER NOT NULL DEFAULT 1
+            );
+
+            CREATE TABLE IF NOT EXISTS t_alliance_stream (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                alliance_id INTEGER NOT NULL,
+                sender_id INTEGER NOT NULL,
+                sender_name TEXT NOT NULL,
+                sender_exp_level INTEGER NOT NULL,
+                sender_role INTEGER NOT NULL,
+                message TEXT,
+                event_type INTEGER,
+                event_avatar_id INTEGER,
+                event_avatar_name TEXT,
+                donate_total_capacity INTEGER,
+                donate_used_capacity INTEGER,
+                created_at BIGINT NOT NULL
+            );
+        "#;
+
+        const INDEX_QUERY: &str = r#"
+            CREATE INDEX IF NOT EXISTS idx_player_data_score ON t_player_data (score DESC);
+            CREATE INDEX IF NOT EXISTS idx_player_data_name ON t_player_data (name);
+            CREATE INDEX IF NOT EXISTS idx_battle_log_attacker ON t_battle_log (attacker_id);
+            CREATE INDEX IF NOT EXISTS idx_battle_log_defender ON t_battle_log (defender_id);
+            CREATE INDEX IF NOT EXISTS idx_alliance_member_alliance ON t_alliance_member (alliance_id);
+            CREATE INDEX IF NOT EXISTS idx_alliance_stream_alliance ON t_alliance_stream (alliance_id, id);
+            CREATE INDEX IF NOT EXISTS idx_war_state ON t_war (state);
+            CREATE INDEX IF NOT EXISTS idx_war_side_alliance ON t_war_side (alliance_id);
+            CREATE INDEX IF NOT EXISTS idx_war_member_player ON t_war_member (player_id);
+            CREATE INDEX IF NOT EXISTS idx_command_log_player ON t_command_log (player_id, id);
+            CREATE INDEX IF NOT EXISTS idx_command_log_timestamp ON t_command_log (timestamp);
         "#;
 
-        let connection = rusqlite::Connection::open(path)?;
-        connection.execute(INIT_QUERY, [])?;
+        let mut writer = Self::open(path)?;
+        writer.pragma_update(None, "journal_mode", "WAL")?;
+        writer.execute_batch(INIT_QUERY)?;
+        Self::migrate_plaintext_pass_tokens(&mut writer)?;
+        Self::add_column_if_missing(
+            &writer,
+            "t_player_data",
+            "shield_end_timestamp",
+            "BIGINT NOT NULL DEFAULT 0",
+        )?;
+        Self::add_column_if_missing(
+            &writer,
+            "t_player_data",
+            "guard_end_timestamp",
+            "BIGINT NOT NULL DEFAULT 0",
+        )?;
+        Self::add_column_if_missing(
+            &writer,
+            "t_player_data",
+            "is_admin",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        Self::add_column_if_missing(
+            &writer,
+            "t_battle_log",
+            "revenge_used",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        Self::add_column_if_missing(
+            &writer,
+            "t_alliance_stream",
+            "donate_total_capacity",
+            "INTEGER",
+        )?;
+        Self::add_column_if_missing(
+            &writer,
+            "t_alliance_stream",
+            "donate_used_capacity",
+            "INTEGER",
+        )?;
+        Self::add_column_if_missing(
+            &writer,
+            "t_command_log",
+            "sub_tick_command_count",
+            "INTEGER NOT NULL DEFAULT 1",
+        )?;
+        Self::migrate_avatar_columns(&mut writer)?;
+        Self::migrate_quarantine_columns(&writer)?;
+        writer.execute_batch(INDEX_QUERY)?;
+
+        let readers = (0..Self::READER_COUNT)
+            .map(|_| {
+                let reader = Self::open(path)?;
+                reader.pragma_update(None, "query_only", true)?;
+                Ok(Mutex::new(reader))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            writer: Mutex::new(writer),
+            readers,
+            next_reader: AtomicUsize::new(0),
+            high_id,
+        })
+    }
 
-        Ok(Self(connection))
+    fn open(path: &str) -> Result<Connection> {
+        let connection = Connection::open(path)?;
+        connection.busy_timeout(std::time::Duration::from_secs(5))?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.set_prepared_statement_cache_capacity(Self::STATEMENT_CACHE_CAPACITY);
+
+        Ok(connection)
     }
 
-    pub fn fetch_or_create_player(&self, id: &LogicLong) -> Result<Option<PlayerSaveData>> {
-        if id.is_zero() {
-            Ok(Some(self.create_new_player_data()?))
-        } else {
-            self.load_existing_player_data(id)
+    fn migrate_plaintext_pass_tokens(connection: &mut Connection) -> Result<()> {
+        const SELECT_QUERY: &str =
+            r#"SELECT id, pass_token FROM t_player_data WHERE instr(pass_token, '$') = 0"#;
+        const UPDATE_QUERY: &str = r#"UPDATE t_player_data SET pass_token = ?1 WHERE id = ?2"#;
+
+        let transaction = connection.transaction()?;
+
+        let plaintext_tokens = transaction
+            .prepare(SELECT_QUERY)?
+            .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<Result<Vec<_>>>()?;
+
+        {
+            let mut statement = transaction.prepare(UPDATE_QUERY)?;
+            for (id, pass_token) in plaintext_tokens.iter() {
+                statement.execute(params![hash_pass_token(pass_token), id])?;
+            }
+        }
+
+        transaction.commit()?;
+
+        if !plaintext_tokens.is_empty() {
+            info!("hashed {} plaintext pass tokens", plaintext_tokens.len());
         }
+
+        Ok(())
     }
 
-    pub fn fetch_player(&self, id: &LogicLong) -> Result<Option<PlayerSaveData>> {
-        if !id.is_zero() {
-            self.load_existing_player_data(id)
-        } else {
-            Ok(None)
-        } 
+    fn add_column_if_missing(
+        connection: &Connection,
+        table: &str,
+        column: &str,
+        definition: &str,
+    ) -> Result<bool> {
+        let has_column = connection
+            .prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")?
+            .exists(params![table, column])?;
+
+        if !has_column {
+            connection.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"))?;
+        }
+
+        Ok(!has_column)
     }
 
-    pub fn save_player_data(
-        &self,
-        id: &LogicLong,
-        home_json: &str,
-        avatar: &LogicClientAvatar,
-    ) -> Result<()> {
-        const UPDATE_QUERY: &str =
-            r#"UPDATE t_player_data SET home_json = ?1, client_avatar_blob = ?2, last_save_timestamp = ?3 WHERE id = ?4"#;
+    fn migrate_avatar_columns(connection: &mut Connection) -> Result<()> {
+        const AVATAR_COLUMNS: &[(&str, &str)] = &[
+            ("name", "TEXT NOT NULL DEFAULT ''"),
+            ("exp_level", "INTEGER NOT NULL DEFAULT 0"),
+            ("town_hall_level", "INTEGER NOT NULL DEFAULT 0"),
+        ];
+        const SELECT_QUERY: &str = r#"SELECT * FROM t_player_data"#;
 
-        let mut byte_stream = ByteStream::new(10);
-        avatar.encode(&mut byte_stream);
-        let client_avatar_blob = rbase64::encode(byte_stream.get_byte_array());
+        let transaction = connection.transaction()?;
 
-        let timestamp = get_current_timestamp();
+        let mut added = false;
+        for (column, definition) in AVATAR_COLUMNS {
+            added |= Self::add_column_if_missing(&transaction, "t_player_data", column, definition)?;
+        }
 
-        self.0.execute(
-            UPDATE_QUERY,
-            params![home_json, &client_avatar_blob, timestamp, id.lower_int],
-        )?;
+        if !added {
+            return Ok(());
+        }
+
+        let player_data = transaction
+            .prepare(SELECT_QUERY)?
+            .query_map([], Self::read_player_data)?
+            .collect::<Result<Vec<_>>>()?;
+
+        for player_data in player_data.iter() {
+            // Corrupted rows are left for the integrity check to quarantine.
+            if player_data.validate().is_err() {
+                continue;
+            }
+
+            if let Some(avatar) = player_data.decode_client_avatar() {
+                Self::update_avatar_columns(&transaction, &player_data.id, &AvatarColumns::from_avatar(&avatar))?;
+            }
+        }
+
+        transaction.commit()?;
+        info!("backfilled avatar columns of {} players", player_data.len());
 
         Ok(())
     }
 
-    fn create_new_player_data(&self) -> Result<PlayerSaveData> {
-        const INSERT_QUERY: &str = r#"
-            INSERT INTO t_player_data (pass_token, home_json, client_avatar_blob, score, last_save_timestamp)
-            values (?1, ?2, ?3, ?4, ?5) RETURNING *
+    /// Quarantined rows keep every column `t_player_data` gained since the
+    /// quarantine table was created.
+    fn migrate_quarantine_columns(connection: &Connection) -> Result<()> {
+        const QUARANTINE_COLUMNS: &[(&str, &str)] = &[
+            ("name", "TEXT NOT NULL DEFAULT ''"),
+            ("exp_level", "INTEGER NOT NULL DEFAULT 0"),
+            ("town_hall_level", "INTEGER NOT NULL DEFAULT 0"),
+            ("shield_end_timestamp", "BIGINT NOT NULL DEFAULT 0"),
+            ("guard_end_timestamp", "BIGINT NOT NULL DEFAULT 0"),
+            ("is_admin", "INTEGER NOT NULL DEFAULT 0"),
+        ];
+
+        for (column, definition) in QUARANTINE_COLUMNS {
+            Self::add_column_if_missing(connection, "t_player_data_quarantine", column, definition)?;
+        }
+
+        Ok(())
+    }
+
+    fn update_avatar_columns(connection: &Connection, id: &LogicLong, columns: &AvatarColumns) -> Result<()> {
+        const UPDATE_QUERY: &str = r#"
+            UPDATE t_player_data SET score = ?1, name = ?2, exp_level = ?3, town_hall_level = ?4 WHERE id = ?5
         "#;
 
-        let pass_token = Alphanumeric.sample_string(&mut rand::rng(), 40);
+        connection.prepare_cached(UPDATE_QUERY)?.execute(params![
+            columns.score,
+            &columns.name,
+            columns.exp_level,
+            columns.town_hall_level,
+            id.to_long()
+        ])?;
 
-        let mut sb = StringBuilder::new();
-        ResourceManager::get_json("level/starting_home.json").write_to_string(&mut sb);
-        let home_json = sb.to_string();
+        Ok(())
+    }
 
-        let logic_client_avatar = LogicClientAvatar::get_default_avatar();
-        let mut byte_stream = ByteStream::new(10);
-        logic_client_avatar.encode(&mut byte_stream);
-        let client_avatar_blob = rbase64::encode(byte_stream.get_byte_array());
+    /// Moves every player record that fails [`PlayerSaveData::validate`]
+    /// into `t_player_data_quarantine`. Returns the number of quarantined rows.
+    pub fn check_integrity(&self) -> Result<usize> {
+        const SELECT_QUERY: &str = r#"SELECT * FROM t_player_data"#;
 
-        let timestamp = get_current_timestamp();
+        let mut writer = self.writer();
+        let transaction = writer.transaction()?;
 
-        let id: i32 = self
-            .0
-            .prepare(INSERT_QUERY)
-            .inspect_err(|err| {
-                error!("db::prepare `insert into t_player_data` failed: {err}");
-            })?
-            .query_map(
-                params![&pass_token, &home_json, &client_avatar_blob, 0, timestamp],
-                |row| row.get(0),
-            )?
-            .next()
-            .expect("query didn't return inserted data")?;
+        let mut checked = 0;
+        let mut corrupted = Vec::new();
 
-        Ok(PlayerSaveData {
-            id: LogicLong::new(0, id),
-            pass_token,
-            home_json,
-            client_avatar_blob,
-            score: 0,
-            last_save_timestamp : timestamp
-        })
+        {
+            let mut statement = transaction.prepare(SELECT_QUERY)?;
+            let mut rows = statement.query([])?;
+            while let Some(row) = rows.next()? {
+                let player_data = Self::read_player_data(row)?;
+                checked += 1;
+
+                if let Err(reason) = player_data.validate() {
+                    corrupted.push((player_data.id, reason));
+                }
+            }
+        }
+
+        for (id, reason) in corrupted.iter() {
+            warn!("quarantining player {id}: {reason}");
+            Self::quarantine_player_data(&transaction, id, reason)?;
+        }
+
+        transaction.commit()?;
+
+        info!(
+            "integrity check: {checked} players checked, {} quarantined",
+            corrupted.len()
+        );
+
+        Ok(corrupted.len())
     }
 
-    fn load_existing_player_data(&self, id: &LogicLong) -> Result<Option<PlayerSaveData>> {
-        const SELECT_QUERY: &str = r#"SELECT * FROM t_player_data WHERE id = (?1)"#;
+    pub fn quarantine_player(&self, id: &LogicLong, reason: &str) -> Result<()> {
+        let mut writer = self.writer();
+        let transaction = writer.transaction()?;
+        Self::quarantine_player_data(&transaction, id, reason)?;
+        transaction.commit()
+    }
 
-        self.0
-            .prepare(SELECT_QUERY)
-            .inspect_err(|err| {
-                error!("db::prepare `select from t_player_data` failed: {err}");
-            })?
-            .query_map(params![id.lower_int], |row| {
-                Ok(PlayerSaveData {
-                    id: LogicLong::new(0, row.get(0)?),
-                    pass_token: row.get(1)?,
-                    home_json: row.get(2)?,
-                    client_avatar_blob: row.get(3)?,
-                    score: row.get(4)?,
-                    last_save_timestamp: row.get(5)?
+    pub fn is_player_quarantined(&self, id: &LogicLong) -> Result<bool> {
+        const SELECT_QUERY: &str = r#"SELECT 1 FROM t_player_data_quarantine WHERE id = ?1"#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .exists(params![id.to_long()])
+    }
+
+    fn quarantine_player_data(connection: &Connection, id: &LogicLong, reason: &str) -> Result<()> {
+        const MOVE_QUERY: &str = r#"
+            INSERT OR REPLACE INTO t_player_data_quarantine
+                (id, pass_token, home_json, client_avatar_blob, score, last_save_timestamp, reason, quarantined_at,
+                name, exp_level, town_hall_level, shield_end_timestamp, guard_end_timestamp, is_admin)
+            SELECT id, pass_token, home_json, client_avatar_blob, score, last_save_timestamp, ?2, ?3,
+                name, exp_level, town_hall_level, shield_end_timestamp, guard_end_timestamp, is_admin
+            FROM t_player_data WHERE id = ?1
+        "#;
+        const DELETE_QUERY: &str = r#"DELETE FROM t_player_data WHERE id = ?1"#;
+
+        connection
+            .prepare_cached(MOVE_QUERY)?
+            .execute(params![id.to_long(), reason, get_current_timestamp()])?;
+        connection
+            .prepare_cached(DELETE_QUERY)?
+            .execute(params![id.to_long()])?;
+
+        Ok(())
+    }
+
+    fn writer(&self) -> MutexGuard<'_, Connection> {
+        self.writer.lock().unwrap()
+    }
+
+    fn reader(&self) -> MutexGuard<'_, Connection> {
+        let start = self.next_reader.fetch_add(1, Ordering::Relaxed);
+
+        (0..self.readers.len())
+            .map(|i| &self.readers[(start + i) % self.readers.len()])
+            .find_map(|reader| reader.try_lock().ok())
+            .unwrap_or_else(|| self.readers[start % self.readers.len()].lock().unwrap())
+    }
+
+    pub fn fetch_player(&self, id: &LogicLong) -> Result<Option<PlayerSaveData>> {
+        if id.is_zero() {
+            return Ok(None);
+        }
+
+        if id.higher_int != self.high_id {
+            warn!(
+                "rejecting account id {id}: high id doesn't match this server ({})",
+                self.high_id
+            );
+            return Ok(None);
+        }
+
+        self.load_existing_player_data(id)
+    }
+
+    /// The `limit` best players, best first.
+    pub fn fetch_players_by_score(&self, limit: usize) -> Result<Vec<PlayerRankingData>> {
+        const SELECT_QUERY: &str = r#"
+            SELECT id, name, exp_level, score FROM t_player_data ORDER BY score DESC, id ASC LIMIT ?1
+        "#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(params![limit], |row| {
+                Ok(PlayerRankingData {
+                    id: LogicLong::from_long(row.get(0)?),
+                    name: row.get(1)?,
+                    exp_level: row.get(2)?,
+                    score: row.get(3)?,
                 })
             })?
-            .into_iter()
+            .collect()
+    }
+
+    /// The player's position in [`Self::fetch_players_by_score`] order.
+    pub fn fetch_player_rank(&self, id: &LogicLong) -> Result<Option<i32>> {
+        const SELECT_QUERY: &str = r#"
+            SELECT (
+                SELECT COUNT(*) FROM t_player_data o
+                WHERE o.score > p.score OR (o.score = p.score AND o.id < p.id)
+            ) + 1
+            FROM t_player_data p WHERE p.id = ?1
+        "#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(params![id.to_long()], |row| row.get(0))?
             .next()
             .transpose()
     }
+
+    /// Picks a random opponent among the players closest to `score`, skipping
+    /// shielded or guarded players and everyone in `excluded_ids`.
+    pub fn find_opponent(&self, score: i32, excluded_ids: &[i64]) -> Result<Option<PlayerSaveData>> {
+        const CANDIDATE_COUNT: usize = 10;
+        const SELECT_QUERY: &str = r#"
+            SELECT * FROM t_player_data
+            WHERE shield_end_timestamp <= ?1 AND guard_end_timestamp <= ?1 AND id NOT IN (SELECT value FROM json_each(?2))
+            ORDER BY ABS(score - ?3) ASC LIMIT ?4
+        "#;
+
+        let excluded_ids = serde_json::to_string(excluded_ids).unwrap();
+
+        let mut candidates = self
+            .reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(
+                params![get_current_timestamp(), excluded_ids, score, CANDIDATE_COUNT],
+                Self::read_player_data,
+            )?
+            .collect::<Result<Vec<_>>>()?;
+
+        if candidates.is_empty() {
+            return Ok(None);
+        }
+
+        let index = rand::random_range(0..candidates.len());
+        Ok(Some(candidates.swap_remove(index)))
+    }
+
+    pub fn save_player_data(
+        &self,
+        id: &LogicLong,
+        home_json: &str,
+        avatar: &LogicClientAvatar,
+    ) -> Result<()> {
+        Self::update_player_data(&self.writer(), id, home_json, avatar)
+    }
+
+    /// Saves the defender's home as it was left by an attack, together with
+    /// the shield and guard they were granted, the battle log entry and its
+    /// compressed replay, in one transaction. The avatar is reloaded and only
+    /// loses the stolen resources and trophies, so whatever was added to it
+    /// during the battle is kept. A longer shield or guard the defender
+    /// already had is kept too. Returns `false`, saving nothing, if the
+    /// defender's avatar can no longer be loaded.
+    pub fn save_defense_result(
+        &self,
+        id: &LogicLong,
+        home_json: &str,
+        shield_timers: &ShieldTimers,
+        battle_log_entry: &mut BattleLogEntry,
+        replay: &[u8],
+    ) -> Result<bool> {
+        const SELECT_QUERY: &str = r#"SELECT * FROM t_player_data WHERE id = ?1"#;
+        const UPDATE_QUERY: &str = r#"
+            UPDATE t_player_data SET shield_end_timestamp = MAX(shield_end_timestamp, ?1),
+                guard_end_timestamp = MAX(guard_end_timestamp, ?2)
+            WHERE id = ?3
+        "#;
+
+        let mut writer = self.writer();
+        let transaction = writer.transaction()?;
+
+        let player_data = transaction
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(params![id.to_long()], Self::read_player_data)?
+            .next()
+            .transpose()?;
+
+        let Some(mut avatar) = player_data.and_then(|player_data| player_data.decode_client_avatar()) else {
+            return Ok(false);
+        };
+
+        let stolen_resources = [
+            (LogicResourceData::gold(), battle_log_entry.stolen_gold),
+            (LogicResourceData::elixir(), battle_log_entry.stolen_elixir),
+            (LogicResourceData::dark_elixir(), battle_log_entry.stolen_dark_elixir),
+        ];
+        for (data, stolen) in stolen_resources {
+            let count = avatar.get_resource_count(&data);
+            avatar.set_resource_count(&data, count.saturating_sub(stolen).max(0));
+        }
+        avatar.set_score(avatar.get_score().saturating_add(battle_log_entry.defender_score_delta).max(0));
+
+        Self::update_player_data(&transaction, id, home_json, &avatar)?;
+        transaction
+            .prepare_cached(UPDATE_QUERY)?
+            .execute(params![
+                shield_timers.shield_end_timestamp,
+                shield_timers.guard_end_timestamp,
+                id.to_long()
+            ])?;
+        Self::insert_battle_log_entry(&transaction, battle_log_entry)?;
+        Self::insert_battle_replay(&transaction, battle_log_entry.id, replay)?;
+
+        transaction.commit()?;
+        Ok(true)
+    }
+
+    /// Attacking someone breaks the attacker's shield, but not their guard.
+    pub fn clear_shield(&self, id: &LogicLong) -> Result<()> {
+        const UPDATE_QUERY: &str = r#"UPDATE t_player_data SET shield_end_timestamp = 0 WHERE id = ?1"#;
+
+        self.writer()
+            .prepare_cached(UPDATE_QUERY)?
+            .execute(params![id.to_long()])?;
+
+        Ok(())
+    }
+
+    pub fn save_battle_log_entry(&self, entry: &mut BattleLogEntry) -> Result<()> {
+        Self::insert_battle_log_entry(&self.writer(), entry)
+    }
+
+    fn insert_battle_log_entry(connection: &Connection, entry: &mut BattleLogEntry) -> Result<()> {
+        const INSERT_QUERY: &str = r#"
+            INSERT INTO t_battle_log (
+                attacker_id, defender_id, attacker_name, attacker_exp_level, defender_name, defender_exp_level,
+                start_timestamp, end_timestamp, stars, destruction_percentage, stolen_gold, stolen_elixir,
+                stolen_dark_elixir, attacker_score_delta, defender_score_delta, attacker_log_json, defender_log_json
+            ) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)
+        "#;
+
+        connection.prepare_cached(INSERT_QUERY)?.execute(params![
+            entry.attacker_id.to_long(),
+            entry.defender_id.as_ref().map(LogicLong::to_long),
+            &entry.attacker_name,
+            entry.attacker_exp_level,
+            &entry.defender_name,
+            entry.defender_exp_level,
+            entry.start_timestamp,
+            entry.end_timestamp,
+            entry.stars,
+            entry.destruction_percentage,
+            entry.stolen_gold,
+            entry.stolen_elixir,
+            entry.stolen_dark_elixir,
+            entry.attacker_score_delta,
+            entry.defender_score_delta,
+            &entry.attacker_log_json,
+            &entry.defender_log_json
+        ])?;
+
+        entry.id = connection.last_insert_rowid();
+        Ok(())
+    }
+
+    fn insert_battle_replay(connection: &Connection, battle_log_id: i64, replay: &[u8]) -> Result<()> {
+        const INSERT_QUERY: &str = r#"INSERT INTO t_battle_replay (battle_log_id, replay) values (?1, ?2)"#;
+
+        connection
+            .prepare_cached(INSERT_QUERY)?
+            .execute(params![battle_log_id, replay])?;
+
+        Ok(())
+    }
+
+    pub fn fetch_battle_replay(&self, battle_log_id: i64) -> Result<Option<Vec<u8>>> {
+        const SELECT_QUERY: &str = r#"SELECT replay FROM t_battle_replay WHERE battle_log_id = ?1"#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(params![battle_log_id], |row| row.get(0))?
+            .next()
+            .transpose()
+    }
+
+    /// The player's latest multiplayer attacks and defenses, newest first
+    /// within each. NPC battles are recorded but not part of the log.
+    pub fn fetch_battle_log(&self, id: &LogicLong) -> Result<Vec<BattleLogEntry>> {
+        // Attacks and defenses are limited separately but listed together,
+        // newest first.
+        const SELECT_QUERY: &str = r#"
+            SELECT * FROM (
+                SELECT * FROM (
+                    SELECT * FROM t_battle_log WHERE attacker_id = ?1 AND defender_id IS NOT NULL
+                    ORDER BY id DESC LIMIT ?2
+                )
+                UNION ALL
+                SELECT * FROM (
+                    SELECT * FROM t_battle_log WHERE defender_id = ?1 ORDER BY id DESC LIMIT ?2
+                )
+            ) ORDER BY end_timestamp DESC, id DESC
+        "#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(
+                params![id.to_long(), Self::BATTLE_LOG_LENGTH],
+                Self::read_battle_log_entry,
+            )?
+            .collect()
+    }
+
+    pub fn fetch_battle_log_entry(&self, id: i64) -> Result<Option<BattleLogEntry>> {
+        const SELECT_QUERY: &str = r#"SELECT * FROM t_battle_log WHERE id = ?1"#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(params![id], Self::read_battle_log_entry)?
+            .next()
+            .transpose()
+    }
+
+    /// Returns `false` if revenge was already taken for this entry.
+    pub fn use_revenge(&self, battle_log_id: i64) -> Result<bool> {
+        const UPDATE_QUERY: &str =
+            r#"UPDATE t_battle_log SET revenge_used = 1 WHERE id = ?1 AND revenge_used = 0"#;
+
+        let updated = self
+            .writer()
+            .prepare_cached(UPDATE_QUERY)?
+            .execute(params![battle_log_id])?;
+
+        Ok(updated != 0)
+    }
+
+    /// Gives back a revenge taken by `use_revenge` whose attack never started.
+    pub fn release_revenge(&self, battle_log_id: i64) -> Result<()> {
+        const UPDATE_QUERY: &str = r#"UPDATE t_battle_log SET revenge_used = 0 WHERE id = ?1"#;
+
+        self.writer()
+            .prepare_cached(UPDATE_QUERY)?
+            .execute(params![battle_log_id])?;
+
+        Ok(())
+    }
+
+    fn read_battle_log_entry(row: &rusqlite::Row) -> Result<BattleLogEntry> {
+        Ok(BattleLogEntry {
+            id: row.get("id")?,
+            attacker_id: LogicLong::from_long(row.get("attacker_id")?),
+            defender_id: row
+                .get::<_, Option<i64>>("defender_id")?
+                .map(LogicLong::from_long),
+            attacker_name: row.get("attacker_name")?,
+            attacker_exp_level: row.get("attacker_exp_level")?,
+            defender_name: row.get("defender_name")?,
+            defender_exp_level: row.get("defender_exp_level")?,
+            start_timestamp: row.get("start_timestamp")?,
+            end_timestamp: row.get("end_timestamp")?,
+            stars: row.get("stars")?,
+            destruction_percentage: row.get("destruction_percentage")?,
+            stolen_gold: row.get("stolen_gold")?,
+            stolen_elixir: row.get("stolen_elixir")?,
+            stolen_dark_elixir: row.get("stolen_dark_elixir")?,
+            attacker_score_delta: row.get("attacker_score_delta")?,
+            defender_score_delta: row.get("defender_score_delta")?,
+            attacker_log_json: row.get("attacker_log_json")?,
+            defender_log_json: row.get("defender_log_json")?,
+            revenge_used: row.get("revenge_used")?,
+        })
+    }
+
+    /// Saves an avatar changed while its owner is offline. Unlike
+    /// [`Self::save_player_data`] the home and last save time are left as
+    /// they are, so the owner's offline progress isn't lost.
+    pub fn save_player_avatar(&self, id: &LogicLong, avatar: &LogicClientAvatar) -> Result<()> {
+        Self::update_player_avatar(&self.writer(), id, avatar)
+    }
+
+    fn update_player_avatar(connection: &Connection, id: &LogicLong, avatar: &LogicClientAvatar) -> Result<()> {
+        const UPDATE_QUERY: &str = r#"
+            UPDATE t_player_data SET client_avatar_blob = ?1,
+                score = ?2, name = ?3, exp_level = ?4, town_hall_level = ?5
+            WHERE id = ?6
+        "#;
+
+        let avatar_columns = AvatarColumns::from_avatar(avatar);
+
+        let mut byte_stream = ByteStream::new(10);
+        avatar.encode(&mut byte_stream);
+        let client_avatar_blob = rbase64::encode(byte_stream.get_byte_array());
+
+        connection.prepare_cached(UPDATE_QUERY)?.execute(params![
+            &client_avatar_blob,
+            avatar_columns.score,
+            &avatar_columns.name,
+            avatar_columns.exp_level,
+            avatar_columns.town_hall_level,
+            id.to_long()
+        ])?;
+
+        Ok(())
+    }
+
+    fn update_player_data(
+        connection: &Connection,
+        id: &LogicLong,
+        home_json: &str,
+        avatar: &LogicClientAvatar,
+    ) -> Result<()> {
+        const UPDATE_QUERY: &str = r#"
+            UPDATE t_player_data SET home_json = ?1, client_avatar_blob = ?2, last_save_timestamp = ?3,
+                score = ?4, name = ?5, exp_level = ?6, town_hall_level = ?7
+            WHERE id = ?8
+        "#;
+
+        let avatar_columns = AvatarColumns::from_avatar(avatar);
+
+        let mut byte_stream = ByteStream::new(10);
+        avatar.encode(&mut byte_stream);
+        let client_avatar_blob = rbase64::encode(byte_stream.get_byte_array());
+
+        let timestamp = get_current_timestamp();
+
+        connection.prepare_cached(UPDATE_QUERY)?.execute(params![
+            home_json,
+            &client_avatar_blob,
+            timestamp,
+            avatar_columns.score,
+            &avatar_columns.name,
+            avatar_columns.exp_level,
+            avatar_columns.town_hall_level,
+            id.to_long()
+        ])?;
+
+        Ok(())
+    }
+
+    /// Returns the account's new plaintext pass token, or `None` if there is
+    /// no such account.
+    pub fn rotate_pass_token(&self, id: &LogicLong) -> Result<Option<String>> {
+        const UPDATE_QUERY: &str = r#"UPDATE t_player_data SET pass_token = ?1 WHERE id = ?2"#;
+
+        let pass_token = generate_pass_token();
+        let updated = self
+            .writer()
+            .prepare_cached(UPDATE_QUERY)?
+            .execute(params![hash_pass_token(&pass_token), id.to_long()])?;
+
+        Ok((updated != 0).then_some(pass_token))
+    }
+
+    /// Returns the new player together with its plaintext pass token,
+    /// which is only ever known at this point.
+    pub fn create_new_player_data(&self) -> Result<(PlayerSaveData, String)> {
+        let mut sb = StringBuilder::new();
+        ResourceManager::get_json("level/starting_home.json").write_to_string(&mut sb);
+        let home_json = sb.to_string();
+
+        let logic_client_avatar = LogicClientAvatar::get_default_avatar();
+        let mut byte_stream = ByteStream::new(10);
+        logic_client_avatar.encode(&mut byte_stream);
+        let client_avatar_blob = rbase64::encode(byte_stream.get_byte_array());
+        let avatar_columns = AvatarColumns::from_avatar(&logic_client_avatar);
+
+        let timestamp = get_current_timestamp();
+
+        let (id, pass_token_hash, pass_token) = self.insert_player_data(
+            &self.writer(),
+            None,
+            &home_json,
+            &client_avatar_blob,
+            &avatar_columns,
+            timestamp,
+        )?;
+
+        Ok((
+            PlayerSaveData {
+                id,
+                pass_token_hash,
+                home_json,
+                client_avatar_blob,
+                score: avatar_columns.score,
+                last_save_timestamp: timestamp,
+                name: avatar_columns.name,
+                exp_level: avatar_columns.exp_level,
+                shield_end_timestamp: 0,
+                guard_end_timestamp: 0,
+                is_admin: false,
+            },
+            pass_token,
+        ))
+    }
+
+    /// Writes `data` either under its own id (overwriting the existing
+    /// account, which keeps its pass token) or under a freshly allocated one.
+    /// The plaintext pass token is returned only when an account was created.
+    pub fn import_player_data(
+        &self,
+        data: &PlayerSaveData,
+        preserve_id: bool,
+    ) -> Result<Option<(LogicLong, Option<String>)>> {
+        const UPDATE_QUERY: &str = r#"
+            UPDATE t_player_data SET home_json = ?1, client_avatar_blob = ?2, last_save_timestamp = ?3
+            WHERE id = ?4
+        "#;
+
+        if preserve_id && data.id.higher_int != self.high_id {
+            warn!(
+                "rejecting import of {}: high id doesn't match this server ({})",
+                data.id, self.high_id
+            );
+            return Ok(None);
+        }
+
+        let Some(avatar) = data.decode_client_avatar() else {
+            warn!("rejecting import of {}: client avatar blob doesn't decode", data.id);
+            return Ok(None);
+        };
+
+        let avatar_columns = AvatarColumns::from_avatar(&avatar);
+
+        let mut writer = self.writer();
+        let transaction = writer.transaction()?;
+
+        if preserve_id {
+            let updated = transaction.prepare_cached(UPDATE_QUERY)?.execute(params![
+                &data.home_json,
+                &data.client_avatar_blob,
+                data.last_save_timestamp,
+                data.id.to_long()
+            ])?;
+
+            if updated != 0 {
+                Self::update_avatar_columns(&transaction, &data.id, &avatar_columns)?;
+                transaction.commit()?;
+                return Ok(Some((data.id.clone(), None)));
+            }
+        }
+
+        let (id, _, pass_token) = self.insert_player_data(
+            &transaction,
+            preserve_id.then_some(&data.id),
+            &data.home_json,
+            &data.client_avatar_blob,
+            &avatar_columns,
+            data.last_save_timestamp,
+        )?;
+
+        transaction.commit()?;
+
+        Ok(Some((id, Some(pass_token))))
+    }
+
+    fn insert_player_data(
+        &self,
+        connection: &Connection,
+        id: Option<&LogicLong>,
+        home_json: &str,
+        client_avatar_blob: &str,
+        avatar_columns: &AvatarColumns,
+        last_save_timestamp: i64,
+    ) -> Result<(LogicLong, String, String)> {
+        // Low ids are allocated per high id, so several servers' databases
+        // never hand out the same full 64-bit id. Quarantined rows still own
+        // their id, as do the battle log and alliance rows left behind them.
+        const NEXT_ID_QUERY: &str = r#"
+            INSERT INTO t_id_sequence (name, base_id, last_id)
+            SELECT 'player', ?1, IFNULL(MAX(id), ?1) + 1 FROM (
+                SELECT id FROM t_player_data WHERE id > ?1 AND id <= ?1 + 4294967295
+                UNION ALL
+                SELECT id FROM t_player_data_quarantine WHERE id > ?1 AND id <= ?1 + 4294967295
+            ) WHERE true
+            ON CONFLICT (name, base_id) DO UPDATE SET last_id = MAX(last_id + 1, excluded.last_id)
+            RETURNING last_id
+        "#;
+
+        const INSERT_QUERY: &str = r#"
+            INSERT INTO t_player_data
+                (id, pass_token, home_json, client_avatar_blob, score, last_save_timestamp, name, exp_level, town_hall_level)
+            values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9) RETURNING id
+        "#;
+
+        let pass_token = generate_pass_token();
+        let pass_token_hash = hash_pass_token(&pass_token);
+
+        let id = match id {
+            Some(id) => id.to_long(),
+            None => {
+                let base_id = LogicLong::new(self.high_id, 0).to_long();
+                Self::next_id(connection, NEXT_ID_QUERY, base_id)?
+            }
+        };
+
+        let id: i64 = connection
+            .prepare_cached(INSERT_QUERY)
+            .inspect_err(|err| {
+                error!("db::prepare `insert into t_player_data` failed: {err}");
+            })?
+            .query_map(
+                params![
+                    id,
+                    &pass_token_hash,
+                    home_json,
+                    client_avatar_blob,
+                    avatar_columns.score,
+                    last_save_timestamp,
+                    &avatar_columns.name,
+                    avatar_columns.exp_level,
+                    avatar_columns.town_hall_level
+                ],
+                |row| row.get(0),
+            )?
+            .next()
+            .expect("query didn't return inserted data")?;
+
+        Ok((LogicLong::from_long(id), pass_token_hash, pass_token))
+    }
+
+    /// Runs one of the `t_id_sequence` upserts and returns the id it reserved.
+    fn next_id(connection: &Connection, query: &str, base_id: i64) -> Result<i64> {
+        connection.prepare_cached(query)?.query_row(params![base_id], |row| row.get(0))
+    }
+
+    fn load_existing_player_data(&self, id: &LogicLong) -> Result<Option<PlayerSaveData>> {
+        const SELECT_QUERY: &str = r#"SELECT * FROM t_player_data WHERE id = (?1)"#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)
+            .inspect_err(|err| {
+                error!("db::prepare `select from t_player_data` failed: {err}");
+            })?
+            .query_map(params![id.to_long()], Self::read_player_data)?
+            .into_iter()
+            .next()
+            .transpose()
+    }
+
+    // Columns are read by name: tables migrated with ALTER TABLE don't
+    // share the column order of freshly created ones.
+    fn read_player_data(row: &rusqlite::Row) -> Result<PlayerSaveData> {
+        Ok(PlayerSaveData {
+            id: LogicLong::from_long(row.get("id")?),
+            pass_token_hash: row.get("pass_token")?,
+            home_json: row.get("home_json")?,
+            client_avatar_blob: row.get("client_avatar_blob")?,
+            score: row.get("score")?,
+            last_save_timestamp: row.get("last_save_timestamp")?,
+            name: row.get("name")?,
+            exp_level: row.get("exp_level")?,
+            shield_end_timestamp: row.get("shield_end_timestamp")?,
+            guard_end_timestamp: row.get("guard_end_timestamp")?,
+            is_admin: row.get("is_admin")?,
+        })
+    }
+}
+
+impl DatabaseConnection {
+    pub const MAX_ALLIANCE_MEMBERS: usize = 50;
+    pub const ALLIANCE_STREAM_LENGTH: usize = 50;
+
+    /// Creates the alliance with `leader_id` as its leader. Returns `None` if
+    /// they are already in an alliance.
+    pub fn create_alliance(
+        &self,
+        leader_id: &LogicLong,
+        name: &str,
+        settings: &AllianceSettings,
+    ) -> Result<Option<AllianceData>> {
+        // Alliance ids are allocated per high id, like player ids, and the id
+        // of a disbanded alliance isn't reused either.
+        const NEXT_ID_QUERY: &str = r#"
+            INSERT INTO t_id_sequence (name, base_id, last_id)
+            SELECT 'alliance', ?1, IFNULL(MAX(id), ?1) + 1 FROM t_alliance WHERE id > ?1 AND id <= ?1 + 4294967295
+            ON CONFLICT (name, base_id) DO UPDATE SET last_id = MAX(last_id + 1, excluded.last_id)
+            RETURNING last_id
+        "#;
+
+        const INSERT_QUERY: &str = r#"
+            INSERT INTO t_alliance (id, name, description, badge_id, type, required_score, created_at)
+            values (?1, ?2, ?3, ?4, ?5, ?6, ?7) RETURNING id
+        "#;
+
+        let mut writer = self.writer();
+        let transaction = writer.transaction()?;
+
+        if Self::get_alliance_membership(&transaction, leader_id)?.is_some() {
+            return Ok(None);
+        }
+
+        let base_id = LogicLong::new(self.high_id, 0).to_long();
+        let id = Self::next_id(&transaction, NEXT_ID_QUERY, base_id)?;
+
+        let id: i64 = transaction.prepare_cached(INSERT_QUERY)?.query_row(
+            params![
+                id,
+                name,
+                &settings.description,
+                settings.badge_id,
+                settings.alliance_type,
+                settings.required_score,
+                get_current_timestamp()
+            ],
+            |row| row.get(0),
+        )?;
+
+        let id = LogicLong::from_long(id);
+        Self::insert_alliance_member(&transaction, &id, leader_id, AllianceRole::Leader)?;
+        transaction.commit()?;
+
+        Ok(Some(AllianceData {
+            id,
+            name: name.to_string(),
+            settings: AllianceSettings {
+                description: settings.description.clone(),
+                ..*settings
+            },
+        }))
+    }
+
+    pub fn fetch_alliance(&self, id: &LogicLong) -> Result<Option<AllianceData>> {
+        const SELECT_QUERY: &str = r#"SELECT * FROM t_alliance WHERE id = ?1"#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(params![id.to_long()], Self::read_alliance_data)?
+            .next()
+            .transpose()
+    }
+
+    pub fn fetch_alliance_header(&self, id: &LogicLong) -> Result<Option<AllianceHeaderData>> {
+        const SELECT_QUERY: &str = r#"
+            SELECT a.*, s.member_count, s.score AS alliance_score
+            FROM t_alliance a JOIN v_alliance_score s ON s.alliance_id = a.id
+            WHERE a.id = ?1
+        "#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(params![id.to_long()], Self::read_alliance_header_data)?
+            .next()
+            .transpose()
+    }
+
+    /// Alliances matching `filter`, best score first.
+    pub fn search_alliances(
+        &self,
+        filter: &AllianceSearchFilter,
+        limit: usize,
+    ) -> Result<Vec<AllianceHeaderData>> {
+        const SELECT_QUERY: &str = r#"
+            SELECT a.*, s.member_count, s.score AS alliance_score
+            FROM t_alliance a JOIN v_alliance_score s ON s.alliance_id = a.id
+            WHERE (?1 IS NULL OR instr(lower(a.name), lower(?1)) > 0)
+                AND s.score >= ?2
+                AND s.member_count >= ?3
+                AND (?4 IS NULL OR s.member_count <= ?4)
+                AND (?5 IS NULL OR (a.type = 1 AND a.required_score <= ?5 AND s.member_count < ?6))
+            ORDER BY s.score DESC, a.id ASC
+            LIMIT ?7
+        "#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(
+                params![
+                    filter.name,
+                    filter.min_score,
+                    filter.min_member_count,
+                    filter.max_member_count,
+                    filter.joinable_with_score,
+                    Self::MAX_ALLIANCE_MEMBERS,
+                    limit
+                ],
+                Self::read_alliance_header_data,
+            )?
+            .collect()
+    }
+
+    /// Members with their current name, level and score, best score first.
+    pub fn fetch_alliance_members(&self, id: &LogicLong) -> Result<Vec<AllianceMemberData>> {
+        const SELECT_QUERY: &str = r#"
+            SELECT m.player_id, m.role, p.name, p.exp_level, p.score
+            FROM t_alliance_member m JOIN t_player_data p ON p.id = m.player_id
+            WHERE m.alliance_id = ?1
+            ORDER BY p.score DESC, m.joined_at ASC
+        "#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(params![id.to_long()], |row| {
+                Ok(AllianceMemberData {
+                    id: LogicLong::from_long(row.get(0)?),
+                    role: AllianceRole::from_i32(row.get(1)?).unwrap_or(AllianceRole::Member),
+                    name: row.get(2)?,
+                    exp_level: row.get(3)?,
+                    score: row.get(4)?,
+                })
+            })?
+            .collect()
+    }
+
+    /// The alliance the player is in, and their role in it.
+    pub fn fetch_player_alliance(
+        &self,
+        player_id: &LogicLong,
+    ) -> Result<Option<(AllianceData, AllianceRole)>> {
+        let Some((alliance_id, role)) = Self::get_alliance_membership(&self.reader(), player_id)?
+        else {
+            return Ok(None);
+        };
+
+        Ok(self.fetch_alliance(&alliance_id)?.map(|alliance| (alliance, role)))
+    }
+
+    /// Returns `false` if the player is already in an alliance or the
+    /// alliance is full.
+    pub fn join_alliance(&self, player_id: &LogicLong, alliance_id: &LogicLong) -> Result<bool> {
+        const COUNT_QUERY: &str = r#"SELECT COUNT(*) FROM t_alliance_member WHERE alliance_id = ?1"#;
+
+        let mut writer = self.writer();
+        let transaction = writer.transaction()?;
+
+        if Self::get_alliance_membership(&transaction, player_id)?.is_some() {
+            return Ok(false);
+        }
+
+        let member_count: usize = transaction
+            .prepare_cached(COUNT_QUERY)?
+            .query_row(params![alliance_id.to_long()], |row| row.get(0))?;
+
+        if member_count >= Self::MAX_ALLIANCE_MEMBERS {
+            return Ok(false);
+        }
+
+        Self::insert_alliance_member(&transaction, alliance_id, player_id, AllianceRole::Member)?;
+        transaction.commit()?;
+
+        Ok(true)
+    }
+
+    /// Removes the player from their alliance and returns its id, along with
+    /// the member promoted in their place. A leaving leader hands the alliance
+    /// over to the highest ranked, longest standing member; the last member
+    /// to leave deletes it.
+    pub fn leave_alliance(&self, player_id: &LogicLong) -> Result<Option<(LogicLong, Option<LogicLong>)>> {
+        const DELETE_MEMBER_QUERY: &str = r#"DELETE FROM t_alliance_member WHERE player_id = ?1"#;
+        const SUCCESSOR_QUERY: &str = r#"
+            SELECT player_id FROM t_alliance_member WHERE alliance_id = ?1
+            ORDER BY CASE role WHEN 4 THEN 0 WHEN 3 THEN 1 ELSE 2 END, joined_at ASC
+            LIMIT 1
+        "#;
+        const DELETE_ALLIANCE_QUERY: &str = r#"DELETE FROM t_alliance WHERE id = ?1"#;
+        const DELETE_STREAM_QUERY: &str = r#"DELETE FROM t_alliance_stream WHERE alliance_id = ?1"#;
+        const DELETE_WAR_SEARCH_QUERY: &str = r#"DELETE FROM t_war_search WHERE alliance_id = ?1"#;
+
+        let mut writer = self.writer();
+        let transaction = writer.transaction()?;
+
+        let Some((alliance_id, role)) = Self::get_alliance_membership(&transaction, player_id)?
+        else {
+            return Ok(None);
+        };
+
+        transaction
+            .prepare_cached(DELETE_MEMBER_QUERY)?
+            .execute(params![player_id.to_long()])?;
+
+        let successor_id: Option<i64> = transaction
+            .prepare_cached(SUCCESSOR_QUERY)?
+            .query_map(params![alliance_id.to_long()], |row| row.get(0))?
+            .next()
+            .transpose()?;
+
+        let mut new_leader_id = None;
+
+        match successor_id {
+            Some(successor_id) if role == AllianceRole::Leader => {
+                let successor_id = LogicLong::from_long(successor_id);
+                Self::update_alliance_member_role(&transaction, &successor_id, AllianceRole::Leader)?;
+                new_leader_id = Some(successor_id);
+            }
+            Some(_) => (),
+            None => {
+                transaction
+                    .prepare_cached(DELETE_ALLIANCE_QUERY)?
+                    .execute(params![alliance_id.to_long()])?;
+                transaction
+                    .prepare_cached(DELETE_STREAM_QUERY)?
+                    .execute(params![alliance_id.to_long()])?;
+                transaction
+                    .prepare_cached(DELETE_WAR_SEARCH_QUERY)?
+                    .execute(params![alliance_id.to_long()])?;
+            }
+        }
+
+        transaction.commit()?;
+
+        Ok(Some((alliance_id, new_leader_id)))
+    }
+
+    /// Making someone leader demotes the current leader to co-leader.
+    pub fn change_alliance_member_role(
+        &self,
+        alliance_id: &LogicLong,
+        member_id: &LogicLong,
+        role: AllianceRole,
+    ) -> Result<()> {
+        const DEMOTE_LEADER_QUERY: &str =
+            r#"UPDATE t_alliance_member SET role = ?1 WHERE alliance_id = ?2 AND role = ?3"#;
+
+        let mut writer = self.writer();
+        let transaction = writer.transaction()?;
+
+        if role == AllianceRole::Leader {
+            transaction.prepare_cached(DEMOTE_LEADER_QUERY)?.execute(params![
+                AllianceRole::CoLeader as i32,
+                alliance_id.to_long(),
+                AllianceRole::Leader as i32
+            ])?;
+        }
+
+        Self::update_alliance_member_role(&transaction, member_id, role)?;
+        transaction.commit()
+    }
+
+    pub fn update_alliance_settings(&self, id: &LogicLong, settings: &AllianceSettings) -> Result<()> {
+        const UPDATE_QUERY: &str = r#"
+            UPDATE t_alliance SET description = ?1, badge_id = ?2, type = ?3, required_score = ?4
+            WHERE id = ?5
+        "#;
+
+        self.writer().prepare_cached(UPDATE_QUERY)?.execute(params![
+            &settings.description,
+            settings.badge_id,
+            settings.alliance_type,
+            settings.required_score,
+            id.to_long()
+        ])?;
+
+        Ok(())
+    }
+
+    /// Saves the entry, assigning its id, and drops entries that fell out
+    /// of the last [`Self::ALLIANCE_STREAM_LENGTH`].
+    pub fn add_alliance_stream_entry(&self, entry: &mut AllianceStreamEntryData) -> Result<()> {
+        const INSERT_QUERY: &str = r#"
+            INSERT INTO t_alliance_stream (
+                alliance_id, sender_id, sender_name, sender_exp_level, sender_role,
+                message, event_type, event_avatar_id, event_avatar_name,
+                donate_total_capacity, donate_used_capacity, created_at
+            ) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+        "#;
+        const PRUNE_QUERY: &str = r#"
+            DELETE FROM t_alliance_stream WHERE alliance_id = ?1 AND id <= (
+                SELECT id FROM t_alliance_stream WHERE alliance_id = ?1
+                ORDER BY id DESC LIMIT 1 OFFSET ?2
+            )
+        "#;
+
+        let (mut message, mut event_type, mut event_avatar_id, mut event_avatar_name) =
+            (None, None, None, None);
+        let (mut donate_total_capacity, mut donate_used_capacity) = (None, None);
+
+        match &entry.kind {
+            AllianceStreamEntryKind::Chat { message: text } => message = Some(text.as_str()),
+            AllianceStreamEntryKind::DonateRequest {
+                message: text,
+                total_capacity,
+                used_capacity,
+            } => {
+                message = Some(text.as_str());
+                donate_total_capacity = Some(*total_capacity);
+                donate_used_capacity = Some(*used_capacity);
+            }
+            AllianceStreamEntryKind::Event {
+                event_type: entry_event_type,
+                event_avatar_id: entry_event_avatar_id,
+                event_avatar_name: entry_event_avatar_name,
+            } => {
+                event_type = Some(*entry_event_type as i32);
+                event_avatar_id = Some(entry_event_avatar_id.to_long());
+                event_avatar_name = Some(entry_event_avatar_name.as_str());
+            }
+        }
+
+        let mut writer = self.writer();
+        let transaction = writer.transaction()?;
+
+        transaction.prepare_cached(INSERT_QUERY)?.execute(params![
+            entry.alliance_id.to_long(),
+            entry.sender_id.to_long(),
+            &entry.sender_name,
+            entry.sender_exp_level,
+            entry.sender_role as i32,
+            message,
+            event_type,
+            event_avatar_id,
+            event_avatar_name,
+            donate_total_capacity,
+            donate_used_capacity,
+            entry.created_at
+        ])?;
+        entry.id = transaction.last_insert_rowid();
+
+        transaction.prepare_cached(PRUNE_QUERY)?.execute(params![
+            entry.alliance_id.to_long(),
+            Self::ALLIANCE_STREAM_LENGTH
+        ])?;
+
+        transaction.commit()
+    }
+
+    /// The alliance's latest stream entries, oldest first.
+    pub fn fetch_alliance_stream(&self, alliance_id: &LogicLong) -> Result<Vec<AllianceStreamEntryData>> {
+        const SELECT_QUERY: &str = r#"
+            SELECT * FROM (
+                SELECT * FROM t_alliance_stream WHERE alliance_id = ?1 ORDER BY id DESC LIMIT ?2
+            ) ORDER BY id ASC
+        "#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(
+                params![alliance_id.to_long(), Self::ALLIANCE_STREAM_LENGTH],
+                Self::read_alliance_stream_entry,
+            )?
+            .collect()
+    }
+
+    pub fn fetch_alliance_stream_entry(&self, id: i64) -> Result<Option<AllianceStreamEntryData>> {
+        const SELECT_QUERY: &str = r#"SELECT * FROM t_alliance_stream WHERE id = ?1"#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(params![id], Self::read_alliance_stream_entry)?
+            .next()
+            .transpose()
+    }
+
+    /// Deletes the sender's open clan castle requests, a new request replaces
+    /// them. Returns the ids of the deleted entries.
+    pub fn remove_donate_requests(&self, sender_id: &LogicLong) -> Result<Vec<i64>> {
+        const DELETE_QUERY: &str = r#"
+            DELETE FROM t_alliance_stream WHERE sender_id = ?1 AND donate_total_capacity IS NOT NULL
+            RETURNING id
+        "#;
+
+        self.writer()
+            .prepare_cached(DELETE_QUERY)?
+            .query_map(params![sender_id.to_long()], |row| row.get(0))?
+            .collect()
+    }
+
+    /// Reserves `housing_space` in the request. Returns `false` if the
+    /// request is gone or the unit doesn't fit anymore.
+    pub fn add_donation(&self, stream_entry_id: i64, housing_space: i32) -> Result<bool> {
+        const UPDATE_QUERY: &str = r#"
+            UPDATE t_alliance_stream SET donate_used_capacity = donate_used_capacity + ?2
+            WHERE id = ?1 AND donate_used_capacity + ?2 <= donate_total_capacity
+        "#;
+
+        let updated = self
+            .writer()
+            .prepare_cached(UPDATE_QUERY)?
+            .execute(params![stream_entry_id, housing_space])?;
+
+        Ok(updated != 0)
+    }
+
+    /// Undoes `add_donation` for a unit that couldn't be delivered.
+    pub fn remove_donation(&self, stream_entry_id: i64, housing_space: i32) -> Result<()> {
+        const UPDATE_QUERY: &str = r#"
+            UPDATE t_alliance_stream SET donate_used_capacity = MAX(donate_used_capacity - ?2, 0)
+            WHERE id = ?1
+        "#;
+
+        self.writer()
+            .prepare_cached(UPDATE_QUERY)?
+            .execute(params![stream_entry_id, housing_space])?;
+
+        Ok(())
+    }
+
+    fn get_alliance_membership(
+        connection: &Connection,
+        player_id: &LogicLong,
+    ) -> Result<Option<(LogicLong, AllianceRole)>> {
+        const SELECT_QUERY: &str =
+            r#"SELECT alliance_id, role FROM t_alliance_member WHERE player_id = ?1"#;
+
+        connection
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(params![player_id.to_long()], |row| {
+                Ok((
+                    LogicLong::from_long(row.get(0)?),
+                    AllianceRole::from_i32(row.get(1)?).unwrap_or(AllianceRole::Member),
+                ))
+            })?
+            .next()
+            .transpose()
+    }
+
+    fn insert_alliance_member(
+        connection: &Connection,
+        alliance_id: &LogicLong,
+        player_id: &LogicLong,
+        role: AllianceRole,
+    ) -> Result<()> {
+        const INSERT_QUERY: &str = r#"
+            INSERT INTO t_alliance_member (player_id, alliance_id, role, joined_at) values (?1, ?2, ?3, ?4)
+        "#;
+
+        connection.prepare_cached(INSERT_QUERY)?.execute(params![
+            player_id.to_long(),
+            alliance_id.to_long(),
+            role as i32,
+            get_current_timestamp()
+        ])?;
+
+        Ok(())
+    }
+
+    fn update_alliance_member_role(
+        connection: &Connection,
+        player_id: &LogicLong,
+        role: AllianceRole,
+    ) -> Result<()> {
+        const UPDATE_QUERY: &str = r#"UPDATE t_alliance_member SET role = ?1 WHERE player_id = ?2"#;
+
+        connection
+            .prepare_cached(UPDATE_QUERY)?
+            .execute(params![role as i32, player_id.to_long()])?;
+
+        Ok(())
+    }
+
+    fn read_alliance_stream_entry(row: &rusqlite::Row) -> Result<AllianceStreamEntryData> {
+        let event_type: Option<i32> = row.get("event_type")?;
+
+        let donate_total_capacity: Option<i32> = row.get("donate_total_capacity")?;
+        let message = row.get::<_, Option<String>>("message")?.unwrap_or_default();
+
+        let kind = match (event_type.and_then(AllianceEventType::from_i32), donate_total_capacity) {
+            (Some(event_type), _) => AllianceStreamEntryKind::Event {
+                event_type,
+                event_avatar_id: LogicLong::from_long(row.get("event_avatar_id")?),
+                event_avatar_name: row.get("event_avatar_name")?,
+            },
+            (None, Some(total_capacity)) => AllianceStreamEntryKind::DonateRequest {
+                message,
+                total_capacity,
+                used_capacity: row.get::<_, Option<i32>>("donate_used_capacity")?.unwrap_or_default(),
+            },
+            (None, None) => AllianceStreamEntryKind::Chat { message },
+        };
+
+        Ok(AllianceStreamEntryData {
+            id: row.get("id")?,
+            alliance_id: LogicLong::from_long(row.get("alliance_id")?),
+            sender_id: LogicLong::from_long(row.get("sender_id")?),
+            sender_name: row.get("sender_name")?,
+            sender_exp_level: row.get("sender_exp_level")?,
+            sender_role: AllianceRole::from_i32(row.get("sender_role")?).unwrap_or(AllianceRole::Member),
+            created_at: row.get("created_at")?,
+            kind,
+        })
+    }
+
+    fn read_alliance_header_data(row: &rusqlite::Row) -> Result<AllianceHeaderData> {
+        Ok(AllianceHeaderData {
+            alliance: Self::read_alliance_data(row)?,
+            member_count: row.get("member_count")?,
+            score: row.get("alliance_score")?,
+        })
+    }
+
+    fn read_alliance_data(row: &rusqlite::Row) -> Result<AllianceData> {
+        Ok(AllianceData {
+            id: LogicLong::from_long(row.get("id")?),
+            name: row.get("name")?,
+            settings: AllianceSettings {
+                description: row.get("description")?,
+                badge_id: row.get("badge_id")?,
+                alliance_type: row.get("type")?,
+                required_score: row.get("required_score")?,
+            },
+        })
+    }
+}
+
+impl DatabaseConnection {
+    /// Queues the alliance for a war. Returns `false` if it's already
+    /// searching or at war.
+    pub fn start_war_search(
+        &self,
+        alliance_id: &LogicLong,
+        war_data_id: i32,
+        team_size: i32,
+    ) -> Result<bool> {
+        const INSERT_QUERY: &str = r#"
+            INSERT OR IGNORE INTO t_war_search (alliance_id, war_data_id, team_size, started_at)
+            values (?1, ?2, ?3, ?4)
+        "#;
+
+        let mut writer = self.writer();
+        let transaction = writer.transaction()?;
+
+        if Self::get_alliance_war_id(&transaction, alliance_id)?.is_some() {
+            return Ok(false);
+        }
+
+        let inserted = transaction.prepare_cached(INSERT_QUERY)?.execute(params![
+            alliance_id.to_long(),
+            war_data_id,
+            team_size,
+            get_current_timestamp()
+        ])?;
+
+        transaction.commit()?;
+        Ok(inserted != 0)
+    }
+
+    pub fn cancel_war_search(&self, alliance_id: &LogicLong) -> Result<()> {
+        const DELETE_QUERY: &str = r#"DELETE FROM t_war_search WHERE alliance_id = ?1"#;
+
+        self.writer()
+            .prepare_cached(DELETE_QUERY)?
+            .execute(params![alliance_id.to_long()])?;
+
+        Ok(())
+    }
+
+    /// Searching alliances, longest waiting first.
+    pub fn fetch_war_searches(&self) -> Result<Vec<WarSearchData>> {
+        const SELECT_QUERY: &str = r#"SELECT * FROM t_war_search ORDER BY started_at ASC"#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map([], |row| {
+                Ok(WarSearchData {
+                    alliance_id: LogicLong::from_long(row.get("alliance_id")?),
+                    war_data_id: row.get("war_data_id")?,
+                    team_size: row.get("team_size")?,
+                    started_at: row.get("started_at")?,
+                })
+            })?
+            .collect()
+    }
+
+    /// The alliance's best `team_size` members by score.
+    pub fn fetch_war_team(&self, alliance_id: &LogicLong, team_size: i32) -> Result<Vec<PlayerSaveData>> {
+        const SELECT_QUERY: &str = r#"
+            SELECT p.* FROM t_player_data p JOIN t_alliance_member m ON m.player_id = p.id
+            WHERE m.alliance_id = ?1
+            ORDER BY p.score DESC LIMIT ?2
+        "#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(params![alliance_id.to_long(), team_size], Self::read_player_data)?
+            .collect()
+    }
+
+    /// Up to `team_size` players outside the alliance with a score close to
+    /// `score`, to defend for a bot alliance.
+    pub fn fetch_war_bot_team(
+        &self,
+        excluded_alliance_id: &LogicLong,
+        score: i32,
+        team_size: i32,
+    ) -> Result<Vec<PlayerSaveData>> {
+        const SELECT_QUERY: &str = r#"
+            SELECT * FROM t_player_data
+            WHERE id NOT IN (SELECT player_id FROM t_alliance_member WHERE alliance_id = ?1)
+            ORDER BY ABS(score - ?2) ASC LIMIT ?3
+        "#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(
+                params![excluded_alliance_id.to_long(), score, team_size],
+                Self::read_player_data,
+            )?
+            .collect()
+    }
+
+    /// Starts the war in its preparation day and ends the searches of the
+    /// alliances in it.
+    pub fn create_war(&self, setup: &WarSetup) -> Result<i64> {
+        const INSERT_WAR_QUERY: &str = r#"
+            INSERT INTO t_war (war_data_id, state, battle_start_timestamp, end_timestamp)
+            values (?1, ?2, ?3, ?4)
+        "#;
+        const INSERT_SIDE_QUERY: &str = r#"
+            INSERT INTO t_war_side (war_id, side, alliance_id, name, badge_id) values (?1, ?2, ?3, ?4, ?5)
+        "#;
+        const INSERT_MEMBER_QUERY: &str = r#"
+            INSERT INTO t_war_member (
+                war_id, side, position, player_id, name, exp_level, score, home_json, client_avatar_blob
+            ) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+        "#;
+        const DELETE_SEARCH_QUERY: &str = r#"DELETE FROM t_war_search WHERE alliance_id = ?1"#;
+
+        let mut writer = self.writer();
+        let transaction = writer.transaction()?;
+
+        transaction.prepare_cached(INSERT_WAR_QUERY)?.execute(params![
+            setup.war_data_id,
+            WarState::Preparation as i32,
+            setup.battle_start_timestamp,
+            setup.end_timestamp
+        ])?;
+        let war_id = transaction.last_insert_rowid();
+
+        for (side, side_setup) in setup.sides.iter().enumerate() {
+            let alliance_id = side_setup.alliance_id.as_ref().map(LogicLong::to_long);
+
+            transaction.prepare_cached(INSERT_SIDE_QUERY)?.execute(params![
+                war_id,
+                side,
+                alliance_id,
+                &side_setup.name,
+                side_setup.badge_id
+            ])?;
+
+            for (i, member) in side_setup.members.iter().enumerate() {
+                transaction.prepare_cached(INSERT_MEMBER_QUERY)?.execute(params![
+                    war_id,
+                    side,
+                    i + 1,
+                    member.id.to_long(),
+                    &member.name,
+                    member.exp_level,
+                    member.score,
+                    &member.home_json,
+                    &member.client_avatar_blob
+                ])?;
+            }
+
+            if let Some(alliance_id) = alliance_id {
+                transaction
+                    .prepare_cached(DELETE_SEARCH_QUERY)?
+                    .execute(params![alliance_id])?;
+            }
+        }
+
+        transaction.commit()?;
+        Ok(war_id)
+    }
+
+    /// Moves wars whose preparation day is over to their battle day.
+    pub fn start_war_battle_days(&self, timestamp: i64) -> Result<usize> {
+        const UPDATE_QUERY: &str = r#"
+            UPDATE t_war SET state = ?1 WHERE state = ?2 AND battle_start_timestamp <= ?3
+        "#;
+
+        self.writer().prepare_cached(UPDATE_QUERY)?.execute(params![
+            WarState::Battle as i32,
+            WarState::Preparation as i32,
+            timestamp
+        ])
+    }
+
+    /// Ids of wars whose battle day is over but that aren't settled yet.
+    pub fn fetch_finished_war_ids(&self, timestamp: i64) -> Result<Vec<i64>> {
+        const SELECT_QUERY: &str =
+            r#"SELECT id FROM t_war WHERE state = ?1 AND end_timestamp <= ?2"#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(params![WarState::Battle as i32, timestamp], |row| row.get(0))?
+            .collect()
+    }
+
+    pub fn fetch_war(&self, id: i64) -> Result<Option<WarData>> {
+        Self::read_war(&self.reader(), id)
+    }
+
+    /// The war the alliance is preparing for or fighting, if any.
+    pub fn fetch_alliance_war(&self, alliance_id: &LogicLong) -> Result<Option<WarData>> {
+        let reader = self.reader();
+        match Self::get_alliance_war_id(&reader, alliance_id)? {
+            Some(war_id) => Self::read_war(&reader, war_id),
+            None => Ok(None),
+        }
+    }
+
+    /// Members of one side, in position order.
+    pub fn fetch_war_members(&self, war_id: i64, side: i32) -> Result<Vec<WarMemberData>> {
+        const SELECT_QUERY: &str =
+            r#"SELECT * FROM t_war_member WHERE war_id = ?1 AND side = ?2 ORDER BY position ASC"#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(params![war_id, side], Self::read_war_member)?
+            .collect()
+    }
+
+    /// Takes one of the member's attacks. Returns `false` if they have none left.
+    pub fn use_war_attack(
+        &self,
+        war_id: i64,
+        side: i32,
+        position: i32,
+        attacks_per_member: i32,
+    ) -> Result<bool> {
+        const UPDATE_QUERY: &str = r#"
+            UPDATE t_war_member SET attacks_used = attacks_used + 1
+            WHERE war_id = ?1 AND side = ?2 AND position = ?3 AND attacks_used < ?4
+        "#;
+
+        let updated = self.writer().prepare_cached(UPDATE_QUERY)?.execute(params![
+            war_id,
+            side,
+            position,
+            attacks_per_member
+        ])?;
+
+        Ok(updated != 0)
+    }
+
+    /// Only stars beyond the best earlier attack on the same base count for
+    /// the attacker. Returns those new stars.
+    pub fn record_war_attack(
+        &self,
+        war_id: i64,
+        attacker_side: i32,
+        attacker_position: i32,
+        defender_position: i32,
+        stars: i32,
+        destruction_percentage: i32,
+    ) -> Result<i32> {
+        const SELECT_QUERY: &str = r#"
+            SELECT best_stars FROM t_war_member WHERE war_id = ?1 AND side = ?2 AND position = ?3
+        "#;
+        const UPDATE_DEFENDER_QUERY: &str = r#"
+            UPDATE t_war_member SET best_stars = MAX(best_stars, ?4),
+                best_destruction_percentage = MAX(best_destruction_percentage, ?5)
+            WHERE war_id = ?1 AND side = ?2 AND position = ?3
+        "#;
+        const UPDATE_ATTACKER_QUERY: &str = r#"
+            UPDATE t_war_member SET stars_earned = stars_earned + ?4
+            WHERE war_id = ?1 AND side = ?2 AND position = ?3
+        "#;
+        const INSERT_QUERY: &str = r#"
+            INSERT INTO t_war_attack (
+                war_id, attacker_side, attacker_position, defender_position,
+                stars, new_stars, destruction_percentage, timestamp
+            ) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+        "#;
+
+        let defender_side = 1 - attacker_side;
+
+        let mut writer = self.writer();
+        let transaction = writer.transaction()?;
+
+        let best_stars: i32 = transaction
+            .prepare_cached(SELECT_QUERY)?
+            .query_row(params![war_id, defender_side, defender_position], |row| row.get(0))?;
+        let new_stars = (stars - best_stars).max(0);
+
+        transaction.prepare_cached(UPDATE_DEFENDER_QUERY)?.execute(params![
+            war_id,
+            defender_side,
+            defender_position,
+            stars,
+            destruction_percentage
+        ])?;
+        transaction.prepare_cached(UPDATE_ATTACKER_QUERY)?.execute(params![
+            war_id,
+            attacker_side,
+            attacker_position,
+            new_stars
+        ])?;
+        transaction.prepare_cached(INSERT_QUERY)?.execute(params![
+            war_id,
+            attacker_side,
+            attacker_position,
+            defender_position,
+            stars,
+            new_stars,
+            destruction_percentage,
+            get_current_timestamp()
+        ])?;
+
+        transaction.commit()?;
+        Ok(new_stars)
+    }
+
+    /// Settles the war: each side's result, and a loot bonus for every
+    /// member of a real alliance of `loot_bonus_per_star[side]` per star they
+    /// earned.
+    pub fn finish_war(
+        &self,
+        war_id: i64,
+        results: [WarResult; 2],
+        loot_bonus_per_star: [i32; 2],
+    ) -> Result<()> {
+        const UPDATE_SIDE_QUERY: &str =
+            r#"UPDATE t_war_side SET result = ?3 WHERE war_id = ?1 AND side = ?2"#;
+        const UPDATE_MEMBER_QUERY: &str = r#"
+            UPDATE t_war_member SET reward_gold = stars_earned * ?3, reward_elixir = stars_earned * ?3
+            WHERE war_id = ?1 AND side = ?2
+                AND (SELECT alliance_id FROM t_war_side s WHERE s.war_id = ?1 AND s.side = ?2) IS NOT NULL
+        "#;
+        const UPDATE_WAR_QUERY: &str = r#"UPDATE t_war SET state = ?2 WHERE id = ?1"#;
+
+        let mut writer = self.writer();
+        let transaction = writer.transaction()?;
+
+        for side in 0..2 {
+            transaction
+                .prepare_cached(UPDATE_SIDE_QUERY)?
+                .execute(params![war_id, side, results[side] as i32])?;
+            transaction
+                .prepare_cached(UPDATE_MEMBER_QUERY)?
+                .execute(params![war_id, side, loot_bonus_per_star[side]])?;
+        }
+
+        transaction
+            .prepare_cached(UPDATE_WAR_QUERY)?
+            .execute(params![war_id, WarState::Ended as i32])?;
+
+        transaction.commit()
+    }
+
+    /// Pays out the loot bonuses of wars that ended since the player's last
+    /// login and marks them as received, in the same transaction that loads
+    /// the player. Returns the player with the rewards added to their avatar,
+    /// and the gold and elixir paid, or `None` if the player is gone.
+    pub fn claim_war_rewards(&self, player_id: &LogicLong) -> Result<Option<(PlayerSaveData, i32, i32)>> {
+        const SELECT_PLAYER_QUERY: &str = r#"SELECT * FROM t_player_data WHERE id = ?1"#;
+        const SELECT_REWARDS_QUERY: &str = r#"
+            SELECT IFNULL(SUM(m.reward_gold), 0), IFNULL(SUM(m.reward_elixir), 0)
+            FROM t_war_member m JOIN t_war_side s ON s.war_id = m.war_id AND s.side = m.side
+            WHERE m.player_id = ?1 AND s.alliance_id IS NOT NULL AND m.reward_claimed = 0
+                AND (m.reward_gold > 0 OR m.reward_elixir > 0)
+        "#;
+        const UPDATE_QUERY: &str = r#"
+            UPDATE t_war_member SET reward_claimed = 1
+            WHERE player_id = ?1 AND reward_claimed = 0 AND (reward_gold > 0 OR reward_elixir > 0)
+                AND (SELECT alliance_id FROM t_war_side s
+                    WHERE s.war_id = t_war_member.war_id AND s.side = t_war_member.side) IS NOT NULL
+        "#;
+
+        let mut writer = self.writer();
+        let transaction = writer.transaction()?;
+
+        let read_player = |transaction: &Connection| {
+            transaction
+                .prepare_cached(SELECT_PLAYER_QUERY)?
+                .query_map(params![player_id.to_long()], Self::read_player_data)?
+                .next()
+                .transpose()
+        };
+
+        let Some(player_data) = read_player(&transaction)? else {
+            return Ok(None);
+        };
+
+        let (gold, elixir): (i32, i32) = transaction
+            .prepare_cached(SELECT_REWARDS_QUERY)?
+            .query_row(params![player_id.to_long()], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        if gold == 0 && elixir == 0 {
+            return Ok(Some((player_data, 0, 0)));
+        }
+
+        let Some(mut avatar) = player_data.decode_client_avatar() else {
+            return Ok(None);
+        };
+
+        for (data, amount) in [(LogicResourceData::gold(), gold), (LogicResourceData::elixir(), elixir)] {
+            let count = avatar.get_resource_count(&data);
+            avatar.set_resource_count(&data, count.saturating_add(amount));
+        }
+
+        transaction
+            .prepare_cached(UPDATE_QUERY)?
+            .execute(params![player_id.to_long()])?;
+        Self::update_player_avatar(&transaction, player_id, &avatar)?;
+
+        let player_data = read_player(&transaction)?;
+        transaction.commit()?;
+
+        Ok(player_data.map(|player_data| (player_data, gold, elixir)))
+    }
+
+    fn get_alliance_war_id(connection: &Connection, alliance_id: &LogicLong) -> Result<Option<i64>> {
+        const SELECT_QUERY: &str = r#"
+            SELECT w.id FROM t_war w JOIN t_war_side s ON s.war_id = w.id
+            WHERE s.alliance_id = ?1 AND w.state != ?2
+            ORDER BY w.id DESC LIMIT 1
+        "#;
+
+        connection
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(params![alliance_id.to_long(), WarState::Ended as i32], |row| row.get(0))?
+            .next()
+            .transpose()
+    }
+
+    fn read_war(connection: &Connection, id: i64) -> Result<Option<WarData>> {
+        const SELECT_WAR_QUERY: &str = r#"SELECT * FROM t_war WHERE id = ?1"#;
+        // A side's stars and destruction are what it achieved against the
+        // other side's bases.
+        const SELECT_SIDES_QUERY: &str = r#"
+            SELECT s.*,
+                (SELECT IFNULL(SUM(best_stars), 0) FROM t_war_member m
+                    WHERE m.war_id = s.war_id AND m.side = 1 - s.side) AS stars,
+                (SELECT IFNULL(AVG(best_destruction_percentage), 0) FROM t_war_member m
+                    WHERE m.war_id = s.war_id AND m.side = 1 - s.side) AS destruction_percentage
+            FROM t_war_side s WHERE s.war_id = ?1 ORDER BY s.side ASC
+        "#;
+
+        let Some(mut war) = connection
+            .prepare_cached(SELECT_WAR_QUERY)?
+            .query_map(params![id], |row| {
+                Ok(WarData {
+                    id: row.get("id")?,
+                    state: WarState::from_i32(row.get("state")?).unwrap_or(WarState::Ended),
+                    battle_start_timestamp: row.get("battle_start_timestamp")?,
+                    end_timestamp: row.get("end_timestamp")?,
+                    sides: Vec::new(),
+                })
+            })?
+            .next()
+            .transpose()?
+        else {
+            return Ok(None);
+        };
+
+        war.sides = connection
+            .prepare_cached(SELECT_SIDES_QUERY)?
+            .query_map(params![id], |row| {
+                Ok(WarSideData {
+                    side: row.get("side")?,
+                    alliance_id: row
+                        .get::<_, Option<i64>>("alliance_id")?
+                        .map(LogicLong::from_long),
+                    name: row.get("name")?,
+                    badge_id: row.get("badge_id")?,
+                    stars: row.get("stars")?,
+                    destruction_percentage: row.get::<_, f64>("destruction_percentage")? as i32,
+                })
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Some(war))
+    }
+
+    fn read_war_member(row: &rusqlite::Row) -> Result<WarMemberData> {
+        Ok(WarMemberData {
+            position: row.get("position")?,
+            player_id: LogicLong::from_long(row.get("player_id")?),
+            name: row.get("name")?,
+            exp_level: row.get("exp_level")?,
+            score: row.get("score")?,
+            home_json: row.get("home_json")?,
+            client_avatar_blob: row.get("client_avatar_blob")?,
+            attacks_used: row.get("attacks_used")?,
+            best_stars: row.get("best_stars")?,
+            best_destruction_percentage: row.get("best_destruction_percentage")?,
+        })
+    }
+}
+
+impl DatabaseConnection {
+    pub fn add_command_log_entries(&self, entries: &[CommandLogEntry]) -> Result<()> {
+        const INSERT_QUERY: &str = r#"
+            INSERT INTO t_command_log (
+                player_id, command_type, parameters_json, execute_sub_tick, timestamp,
+                gold_before, elixir_before, dark_elixir_before, diamonds_before,
+                gold_after, elixir_after, dark_elixir_after, diamonds_after, sub_tick_command_count
+            ) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)
+        "#;
+
+        let mut writer = self.writer();
+        let transaction = writer.transaction()?;
+
+        for entry in entries.iter() {
+            let (before, after) = (&entry.resources_before, &entry.resources_after);
+
+            transaction.prepare_cached(INSERT_QUERY)?.execute(params![
+                entry.player_id.to_long(),
+                entry.command_type,
+                &entry.parameters_json,
+                entry.execute_sub_tick,
+                entry.timestamp,
+                before.gold,
+                before.elixir,
+                before.dark_elixir,
+                before.diamonds,
+                after.gold,
+                after.elixir,
+                after.dark_elixir,
+                after.diamonds,
+                entry.sub_tick_command_count
+            ])?;
+        }
+
+        transaction.commit()
+    }
+
+    /// The player's last `limit` commands, newest first.
+    pub fn fetch_command_log(&self, player_id: &LogicLong, limit: i32) -> Result<Vec<CommandLogEntry>> {
+        const SELECT_QUERY: &str =
+            r#"SELECT * FROM t_command_log WHERE player_id = ?1 ORDER BY id DESC LIMIT ?2"#;
+
+        self.reader()
+            .prepare_cached(SELECT_QUERY)?
+            .query_map(params![player_id.to_long(), limit], |row| {
+                Ok(CommandLogEntry {
+                    player_id: LogicLong::from_long(row.get("player_id")?),
+                    command_type: row.get("command_type")?,
+                    parameters_json: row.get("parameters_json")?,
+                    execute_sub_tick: row.get("execute_sub_tick")?,
+                    timestamp: row.get("timestamp")?,
+                    sub_tick_command_count: row.get("sub_tick_command_count")?,
+                    resources_before: AvatarResources {
+                        gold: row.get("gold_before")?,
+                        elixir: row.get("elixir_before")?,
+                        dark_elixir: row.get("dark_elixir_before")?,
+                        diamonds: row.get("diamonds_before")?,
+                    },
+                    resources_after: AvatarResources {
+                        gold: row.get("gold_after")?,
+                        elixir: row.get("elixir_after")?,
+                        dark_elixir: row.get("dark_elixir_after")?,
+                        diamonds: row.get("diamonds_after")?,
+                    },
+                })
+            })?
+            .collect()
+    }
+
+    /// Deletes commands older than `retention_seconds`. Returns how many.
+    pub fn prune_command_log(&self, retention_seconds: i64) -> Result<usize> {
+        const DELETE_QUERY: &str = r#"DELETE FROM t_command_log WHERE timestamp < ?1"#;
+
+        self.writer()
+            .prepare_cached(DELETE_QUERY)?
+            .execute(params![get_current_timestamp() - retention_seconds])
+    }
 }
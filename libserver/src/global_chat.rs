@@ -0,0 +1,77 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::math::LogicLong;
+
+/// A line of the server-wide chat room.
+#[derive(Clone)]
+pub struct GlobalChatLine {
+    pub message: String,
+    pub sender_id: LogicLong,
+    pub sender_name: String,
+    pub sender_exp_level: i32,
+    pub sender_alliance: Option<GlobalChatAlliance>,
+}
+
+#[derive(Clone)]
+pub struct GlobalChatAlliance {
+    pub id: LogicLong,
+    pub name: String,
+    pub badge_id: i32,
+}
+
+#[derive(Default)]
+struct ChatRoom {
+    history: VecDeque<GlobalChatLine>,
+    last_line_timestamps: HashMap<i64, Instant>,
+}
+
+/// Recent lines of the global chat, replayed to players as they log in,
+/// and when each player last posted.
+pub struct GlobalChat {
+    room: Mutex<ChatRoom>,
+    history_size: usize,
+}
+
+impl GlobalChat {
+    /// How long a player has to wait between two lines.
+    const MIN_LINE_INTERVAL: Duration = Duration::from_secs(2);
+
+    pub fn new(history_size: usize) -> Self {
+        Self {
+            room: Mutex::new(ChatRoom::default()),
+            history_size,
+        }
+    }
+
+    /// Adds the line to the history. Returns `false` if its sender posted
+    /// too recently.
+    pub fn try_post(&self, line: &GlobalChatLine) -> bool {
+        let mut room = self.room.lock().unwrap();
+        let now = Instant::now();
+
+        room.last_line_timestamps
+            .retain(|_, timestamp| now.duration_since(*timestamp) < Self::MIN_LINE_INTERVAL);
+
+        if room.last_line_timestamps.contains_key(&line.sender_id.to_long()) {
+            return false;
+        }
+
+        room.last_line_timestamps.insert(line.sender_id.to_long(), now);
+
+        room.history.push_back(line.clone());
+        while room.history.len() > self.history_size {
+            room.history.pop_front();
+        }
+
+        true
+    }
+
+    /// Oldest first.
+    pub fn get_history(&self) -> Vec<GlobalChatLine> {
+        self.room.lock().unwrap().history.iter().cloned().collect()
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::{error, info};
+
+use crate::{
+    database::{DatabaseConnection, PlayerRankingData},
+    math::LogicLong,
+};
+
+pub struct LeaderboardSnapshot {
+    pub top_players: Vec<PlayerRankingData>,
+    ranks: HashMap<i64, i32>,
+    previous_ranks: HashMap<i64, i32>,
+    created_at: Instant,
+}
+
+impl LeaderboardSnapshot {
+    pub fn get_rank(&self, id: &LogicLong) -> Option<i32> {
+        self.ranks.get(&id.to_long()).copied()
+    }
+
+    pub fn get_previous_rank(&self, id: &LogicLong) -> Option<i32> {
+        self.previous_ranks.get(&id.to_long()).copied()
+    }
+}
+
+/// Global avatar ranking, recomputed from `t_player_data` at most once per
+/// [`Leaderboard::REFRESH_INTERVAL`] instead of on every open. Only the top
+/// [`Leaderboard::SIZE`] players are ranked.
+pub struct Leaderboard(Mutex<Option<Arc<LeaderboardSnapshot>>>);
+
+impl Leaderboard {
+    const SIZE: usize = 200;
+    const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+    pub fn new() -> Self {
+        Self(Mutex::new(None))
+    }
+
+    pub fn get(&self, db: &DatabaseConnection) -> Option<Arc<LeaderboardSnapshot>> {
+        let previous = self.0.lock().unwrap().clone();
+
+        let is_stale = previous
+            .as_ref()
+            .is_none_or(|snapshot| snapshot.created_at.elapsed() >= Self::REFRESH_INTERVAL);
+
+        if !is_stale {
+            return previous;
+        }
+
+        // Built without the lock, so readers keep getting the previous board
+        // meanwhile.
+        match db.fetch_players_by_score(Self::SIZE) {
+            Ok(players) => {
+                let snapshot = Arc::new(Self::build_snapshot(players, previous.as_deref()));
+                *self.0.lock().unwrap() = Some(Arc::clone(&snapshot));
+                Some(snapshot)
+            }
+            // Keep serving the previous board, if any.
+            Err(err) => {
+                error!("failed to recompute leaderboard: {err}");
+                previous
+            }
+        }
+    }
+
+    fn build_snapshot(
+        players: Vec<PlayerRankingData>,
+        previous: Option<&LeaderboardSnapshot>,
+    ) -> LeaderboardSnapshot {
+        let ranks = players
+            .iter()
+            .enumerate()
+            .map(|(i, player)| (player.id.to_long(), i as i32 + 1))
+            .collect::<HashMap<_, _>>();
+
+        info!("leaderboard recomputed, {} ranked players", ranks.len());
+
+        LeaderboardSnapshot {
+            top_players: players,
+            previous_ranks: previous
+                .map(|previous| previous.ranks.clone())
+                .unwrap_or_else(|| ranks.clone()),
+            ranks,
+            created_at: Instant::now(),
+        }
+    }
+}
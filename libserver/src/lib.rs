@@ -2,12 +2,20 @@ use std::{
     ffi::c_void,
     net::{SocketAddr, TcpListener},
     os::fd::IntoRawFd,
-    sync::{Arc, LazyLock, Mutex},
+    path::Path,
+    sync::Arc,
     thread,
+    time::Duration,
 };
 
-use byte_stream::ByteStream;
-use database::DatabaseConnection;
+use database::{
+    AllianceData, AllianceEventType, AllianceHeaderData, AllianceMemberData, AllianceRole, AllianceSettings,
+    AllianceStreamEntryData, AllianceStreamEntryKind, AvatarResources, BattleLogEntry, CommandLogEntry,
+    DatabaseConnection,
+    PlayerSaveData, ShieldTimers, WarData, WarState,
+};
+use global_chat::{GlobalChat, GlobalChatAlliance, GlobalChatLine};
+use leaderboard::Leaderboard;
 use ffi_util::import;
 
 use logic::avatar::*;
@@ -17,24 +25,33 @@ use logic::mode::LogicGameMode;
 
 use math::LogicLong;
 use network::PiranhaMessage;
+use online::{Mailbox, OnlinePlayers, SessionEvent};
 use rand::RngCore;
+use replay::BattleReplay;
 use resources::ResourceManager;
 use sc_string::StringBuilder;
 use tracing::{error, info, warn};
 
+mod admin;
 mod array_list;
+mod bundle;
 mod byte_stream;
 mod database;
 mod ffi_util;
+mod global_chat;
 mod helper;
 mod jni_util;
+mod leaderboard;
 mod logic;
 mod math;
 mod message;
 mod network;
+mod online;
+mod replay;
 mod resources;
 mod sc_string;
 mod time_util;
+mod war;
 
 #[allow(non_snake_case)]
 #[unsafe(no_mangle)]
@@ -50,6 +67,10 @@ pub unsafe extern "system" fn JNI_OnLoad(vm: jni::JavaVM, _: *mut c_void) -> jni
     thread::spawn(move || {
         server_main(ServerConfig {
             database_path: format!("/data/data/{package_name}/magic.db"),
+            bundle_path: format!("/data/data/{package_name}/bundles"),
+            high_id: 0,
+            global_chat_history_size: 50,
+            command_log_retention_days: 7,
         })
     });
 
@@ -58,6 +79,12 @@ pub unsafe extern "system" fn JNI_OnLoad(vm: jni::JavaVM, _: *mut c_void) -> jni
 
 struct ServerConfig {
     pub database_path: String,
+    pub bundle_path: String,
+    pub high_id: i32,
+    /// Lines of global chat sent to players as they log in.
+    pub global_chat_history_size: usize,
+    /// How long executed client commands are kept in `t_command_log`.
+    pub command_log_retention_days: i64,
 }
 
 pub fn malloc(amount: usize) -> *const u8 {
@@ -83,12 +110,37 @@ fn server_main(config: ServerConfig) {
 
     info!("successfully initialized resources");
 
-    let db = DatabaseConnection::connect(&config.database_path).unwrap_or_else(|err| {
+    let db = DatabaseConnection::connect(&config.database_path, config.high_id).unwrap_or_else(|err| {
         error!("DatabaseConnection::connect failed: {err}");
         panic!();
     });
 
-    let db = Arc::new(Mutex::new(db));
+    if let Err(err) = db.check_integrity() {
+        error!("integrity check failed: {err}");
+    }
+
+    const DAY: i64 = 24 * 60 * 60;
+    let command_log_retention_seconds = config.command_log_retention_days * DAY;
+    prune_command_log(&db, command_log_retention_seconds);
+
+    let state = Arc::new(ServerState {
+        db,
+        leaderboard: Leaderboard::new(),
+        online_players: OnlinePlayers::new(),
+        global_chat: GlobalChat::new(config.global_chat_history_size),
+        command_log_retention_seconds,
+    });
+
+    bundle::process_pending_bundles(&state.db, &state.online_players, Path::new(&config.bundle_path));
+
+    let war_state = Arc::clone(&state);
+    thread::spawn(move || war::run_timer(&war_state));
+
+    let command_log_state = Arc::clone(&state);
+    thread::spawn(move || run_command_log_timer(&command_log_state));
+
+    let bundle_state = Arc::clone(&state);
+    thread::spawn(move || bundle::run_timer(&bundle_state, Path::new(&config.bundle_path)));
 
     let listener = TcpListener::bind(TCP_ADDR).unwrap();
     info!("server is listening at {TCP_ADDR}");
@@ -97,17 +149,14 @@ fn server_main(config: ServerConfig) {
         info!("new connection from {addr}");
 
         let fd = stream.into_raw_fd();
-        let db = Arc::clone(&db);
+        let state = Arc::clone(&state);
 
-        thread::spawn(move || receive_loop(fd, addr, db));
+        thread::spawn(move || receive_loop(fd, addr, state));
     }
 }
 
-fn receive_loop(fd: i32, addr: SocketAddr, db: Arc<Mutex<DatabaseConnection>>) {
-    use network::{LogicMagicMessageFactory, Messaging, RC4Encrypter};
-
-    static MESSAGE_FACTORY: LazyLock<LogicMagicMessageFactory> =
-        LazyLock::new(|| LogicMagicMessageFactory::new());
+fn receive_loop(fd: i32, addr: SocketAddr, state: Arc<ServerState>) {
+    use network::{LogicMagicMessageFactory, MESSAGE_FACTORY, Messaging, RC4Encrypter};
 
     let mut messaging = Messaging::new(fd);
     messaging.set_message_factory(&*MESSAGE_FACTORY);
@@ -121,89 +170,321 @@ fn receive_loop(fd: i32, addr: SocketAddr, db: Arc<Mutex<DatabaseConnection>>) {
         account_id: LogicLong::new(0, 0),
         logic_game_mode: None,
         saved_home_json: None,
+        battle: None,
+        mailbox: None,
+        is_admin: false,
     };
 
+    // How long a session waits for the client before checking its mailbox.
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
     while session.messaging.get_connection().is_connected {
-        session.messaging.on_receive();
-        while let Some(message) = session.messaging.next_message() {
-            handle_message(&mut session, db.as_ref(), message);
+        if session.messaging.wait_for_data(POLL_INTERVAL) {
+            session.messaging.on_receive();
+            while let Some(message) = session.messaging.next_message() {
+                handle_message(&mut session, state.as_ref(), message);
+            }
         }
+
+        handle_session_events(&mut session);
+    }
+
+    if let Some(mailbox) = session.mailbox.take() {
+        state.online_players.remove(&session.account_id, mailbox.session_id);
+    }
+
+    if let Some(battle) = session.battle.take()
+        && let Some(defender_id) = &battle.defender_id
+    {
+        state.online_players.end_attack(defender_id, battle.attack_id);
     }
 
     info!("client from {addr} disconnected");
 }
 
+struct ServerState {
+    pub db: DatabaseConnection,
+    pub leaderboard: Leaderboard,
+    pub online_players: OnlinePlayers,
+    pub global_chat: GlobalChat,
+    pub command_log_retention_seconds: i64,
+}
+
 struct PlayerSession {
     pub messaging: network::Messaging,
     pub account_id: LogicLong,
     pub logic_game_mode: Option<LogicGameMode>,
     pub saved_home_json: Option<String>,
+    pub battle: Option<Battle>,
+    /// Set once logged in.
+    pub mailbox: Option<Mailbox>,
+    pub is_admin: bool,
 }
 
-fn handle_message(
-    session: &mut PlayerSession,
-    db: &Mutex<DatabaseConnection>,
-    message: PiranhaMessage,
-) {
+/// Set while an attack, NPC, multiplayer or war, is in progress.
+struct Battle {
+    /// `None` for NPC and war battles.
+    pub defender_id: Option<LogicLong>,
+    /// What `try_begin_attack` returned for the defender, if there is one.
+    pub attack_id: u64,
+    pub start_timestamp: i64,
+    /// Only recorded for multiplayer battles.
+    pub replay: Option<BattleReplay>,
+    pub war_attack: Option<war::WarAttack>,
+}
+
+fn handle_message(session: &mut PlayerSession, state: &ServerState, message: PiranhaMessage) {
+    let db = &state.db;
+
     match message.get_message_type() {
-        10101 => handle_login_message(session, db, message),
+        10101 => handle_login_message(session, state, message),
         10108 => handle_keep_alive_message(session, message),
         10212 => handle_change_avatar_name_message(session, message),
-        14101 => handle_go_home_message(session, message),
-        14102 => handle_end_client_turn_message(session, db, message),
+        14101 => handle_go_home_message(session, state, message),
+        14102 => handle_end_client_turn_message(session, state, message),
+        14113 => handle_visit_home_message(session, db, message),
+        14114 => handle_home_battle_replay_message(session, db, message),
+        14123 => handle_attack_matched_home_message(session, state, message),
+        14127 => handle_revenge_attack_message(session, state, message),
         14134 => handle_attack_npc_message(session, message),
+        14301 => handle_create_alliance_message(session, db, message),
+        14302 => handle_ask_for_alliance_data_message(session, db, message),
+        14303 => handle_ask_for_joinable_alliances_list_message(session, db, message),
+        14305 => handle_join_alliance_message(session, state, message),
+        14306 => handle_change_alliance_member_role_message(session, state, message),
+        14308 => handle_leave_alliance_message(session, state, message),
+        14310 => handle_donate_alliance_unit_message(session, state, message),
+        14315 => handle_chat_to_alliance_stream_message(session, state, message),
+        14316 => handle_change_alliance_settings_message(session, state, message),
+        14317 => handle_request_alliance_units_message(session, state, message),
+        14324 => handle_search_alliances_message(session, db, message),
         14325 => handle_ask_for_avatar_profile_message(session, db, message),
+        14330 => handle_start_alliance_war_search_message(session, db, message),
+        14331 => handle_ask_for_alliance_war_data_message(session, db, message),
+        14332 => handle_attack_alliance_war_member_message(session, db, message),
+        14401 => handle_ask_for_alliance_ranking_list_message(session, db, message),
+        14403 => handle_ask_for_avatar_ranking_list_message(session, state, message),
+        14405 => handle_ask_for_avatar_stream_message(session, db, message),
+        14715 => handle_send_global_chat_line_message(session, state, message),
         unhandled => warn!("unhandled message: {unhandled}"),
     }
 
     session.messaging.on_wakeup();
 }
 
-fn handle_login_message(
-    session: &mut PlayerSession,
-    db: &Mutex<DatabaseConnection>,
-    message: PiranhaMessage,
-) {
+/// Sends whatever other sessions posted for this player since the last
+/// check.
+fn handle_session_events(session: &mut PlayerSession) {
+    use logic::command::{
+        LogicAllianceSettingsChangedCommand, LogicAllianceUnitReceivedCommand,
+        LogicChangeAllianceRoleCommand,
+    };
+    use logic::data::LogicDataTables;
+    use message::{
+        AllianceStreamEntryMessage, AllianceStreamEntryRemovedMessage,
+        AvailableServerCommandMessage,
+    };
+
+    let Some(mailbox) = session.mailbox.as_ref() else {
+        return;
+    };
+
+    let events = mailbox.receiver.try_iter().collect::<Vec<_>>();
+    if events.is_empty() {
+        return;
+    }
+
+    for event in events {
+        match event {
+            SessionEvent::AllianceStreamEntry(entry) => {
+                let mut alliance_stream_entry_message = AllianceStreamEntryMessage::new();
+                alliance_stream_entry_message
+                    .set_alliance_stream_entry(create_alliance_stream_entry(&entry));
+                session.messaging.send(alliance_stream_entry_message.0);
+            }
+            SessionEvent::AllianceStreamEntryRemoved(id) => {
+                let mut alliance_stream_entry_removed_message =
+                    AllianceStreamEntryRemovedMessage::new();
+                alliance_stream_entry_removed_message
+                    .set_stream_entry_id(&LogicLong::from_long(id));
+                session.messaging.send(alliance_stream_entry_removed_message.0);
+            }
+            SessionEvent::DonatedUnitReceived {
+                sender_name,
+                unit_data_id,
+                upgrade_level,
+            } => {
+                let Some(unit_data) = LogicDataTables::get_data_by_id(unit_data_id) else {
+                    error!("received alliance unit with unknown data id {unit_data_id}");
+                    continue;
+                };
+
+                let mut logic_alliance_unit_received_command = LogicAllianceUnitReceivedCommand::new();
+                logic_alliance_unit_received_command.set_sender_name(&sender_name);
+                logic_alliance_unit_received_command.set_unit_data(&unit_data);
+                logic_alliance_unit_received_command.set_upgrade_level(upgrade_level);
+
+                let mut available_server_command_message = AvailableServerCommandMessage::new();
+                available_server_command_message
+                    .set_server_command(&logic_alliance_unit_received_command.0);
+                session.messaging.send(available_server_command_message.0);
+            }
+            SessionEvent::GlobalChatLine(line) => {
+                session.messaging.send(create_global_chat_line_message(&line).0);
+            }
+            SessionEvent::AllianceRoleChanged { alliance_id, role } => {
+                let mut logic_change_alliance_role_command = LogicChangeAllianceRoleCommand::new();
+                logic_change_alliance_role_command.set_alliance_id(&alliance_id);
+                logic_change_alliance_role_command.set_alliance_role(role);
+
+                let mut available_server_command_message = AvailableServerCommandMessage::new();
+                available_server_command_message
+                    .set_server_command(&logic_change_alliance_role_command.0);
+                session.messaging.send(available_server_command_message.0);
+            }
+            SessionEvent::AllianceSettingsChanged {
+                alliance_id,
+                badge_id,
+            } => {
+                let mut logic_alliance_settings_changed_command =
+                    LogicAllianceSettingsChangedCommand::new();
+                logic_alliance_settings_changed_command.set_alliance_id(&alliance_id);
+                logic_alliance_settings_changed_command
+                    .set_alliance_badge_data(LogicDataTables::get_data_by_id(badge_id));
+
+                let mut available_server_command_message = AvailableServerCommandMessage::new();
+                available_server_command_message
+                    .set_server_command(&logic_alliance_settings_changed_command.0);
+                session.messaging.send(available_server_command_message.0);
+            }
+            SessionEvent::Disconnect => {
+                info!("disconnecting {}", session.account_id);
+                session.messaging.disconnect();
+                return;
+            }
+        }
+    }
+
+    session.messaging.on_wakeup();
+}
+
+fn handle_login_message(session: &mut PlayerSession, state: &ServerState, message: PiranhaMessage) {
     use message::{ExtendedSetEncryptionMessage, LoginMessage, LoginOkMessage, OwnHomeDataMessage};
     use network::{LogicMagicMessageFactory, RC4Encrypter};
 
+    const ACCOUNT_NOT_FOUND_REASON: &str = "Account not found.";
+    const ACCOUNT_LOCKED_REASON: &str = "Your account is locked while its data is being checked.";
+
+    let db = &state.db;
     let login_message = LoginMessage(message);
 
-    info!(
-        "LoginMessage received, account_id: {}, pass_token: {:?}",
-        login_message.get_account_id(),
-        login_message.get_pass_token()
-    );
+    // The pass token is never logged, it's as good as the account.
+    info!("LoginMessage received, account_id: {}", login_message.get_account_id());
 
-    let Ok(Some(player_data)) = db
-        .lock()
-        .unwrap()
-        .fetch_or_create_player(&login_message.get_account_id())
-    else {
-        warn!(
-            "Login: player with id {} was not found in the database",
-            login_message.get_account_id()
-        );
-        return;
-    };
+    let account_id = login_message.get_account_id();
+
+    let (player_data, pass_token) = if account_id.is_zero() {
+        match db.create_new_player_data() {
+            Ok(created) => created,
+            Err(err) => {
+                error!("Login: failed to create new player: {err}");
+                send_login_failed(session, "The account couldn't be created, please try again later.");
+                return;
+            }
+        }
+    } else {
+        let Ok(Some(player_data)) = db.fetch_player(account_id) else {
+            if let Ok(true) = db.is_player_quarantined(account_id) {
+                warn!("Login: player with id {account_id} is quarantined");
+                send_login_failed(session, ACCOUNT_LOCKED_REASON);
+            } else {
+                warn!("Login: player with id {account_id} was not found in the database");
+                send_login_failed(session, ACCOUNT_NOT_FOUND_REASON);
+            }
+            return;
+        };
 
-    if !login_message.get_account_id().is_zero() {
         let Some(pass_token) = login_message.get_pass_token() else {
-            error!(
-                "Login: received null pass token with non-zero account id: {}",
-                login_message.get_account_id()
-            );
+            error!("Login: received null pass token with non-zero account id: {account_id}");
+            send_login_failed(session, ACCOUNT_NOT_FOUND_REASON);
             return;
         };
 
-        if pass_token.to_string() != player_data.pass_token {
-            warn!(
-                "Login: pass token mismatch, account id: {}",
-                login_message.get_account_id()
-            );
+        let pass_token = pass_token.to_string();
+        if !player_data.verify_pass_token(&pass_token) {
+            warn!("Login: pass token mismatch, account id: {account_id}");
+            send_login_failed(session, ACCOUNT_NOT_FOUND_REASON);
             return;
         }
-    }
+
+        if let Err(reason) = player_data.validate() {
+            error!("Login: player {account_id} is corrupted: {reason}");
+            if let Err(err) = db.quarantine_player(account_id, &reason) {
+                error!("Login: failed to quarantine player {account_id}: {err}");
+            }
+            send_login_failed(session, ACCOUNT_LOCKED_REASON);
+            return;
+        }
+
+        (player_data, pass_token)
+    };
+
+    let mailbox = match state.online_players.try_add(&player_data.id) {
+        Ok(mailbox) => mailbox,
+        Err(remaining) => {
+            warn!("Login: home of player {} is being attacked", player_data.id);
+
+            let mut login_failed_message = message::LoginFailedMessage::new();
+            login_failed_message.set_error_code(message::LoginFailedMessage::ERROR_CODE_SERVER_MAINTENANCE);
+            login_failed_message.set_reason("Your home is under attack!");
+            login_failed_message.set_seconds_until_maintenance_end(remaining.as_secs() as i32 + 1);
+            session.messaging.send(login_failed_message.0);
+            return;
+        }
+    };
+
+    // Only claimed once the session is registered: no attack can start and
+    // overwrite the rewards, and any other session finds them claimed.
+    let player_data = match db.claim_war_rewards(&player_data.id) {
+        Ok(Some((player_data, 0, 0))) => player_data,
+        Ok(Some((player_data, gold, elixir))) => {
+            info!("Login: {} received {gold} gold and {elixir} elixir from wars", player_data.id);
+            player_data
+        }
+        Ok(None) => {
+            error!("Login: player {} is gone or doesn't decode", player_data.id);
+            state.online_players.remove(&player_data.id, mailbox.session_id);
+            send_login_failed(session, ACCOUNT_LOCKED_REASON);
+            return;
+        }
+        Err(err) => {
+            error!("Login: failed to claim war rewards of {}: {err}", player_data.id);
+            player_data
+        }
+    };
+
+    let (Some(mut logic_client_avatar), Some(mut own_home_client_avatar)) = (
+        player_data.decode_client_avatar(),
+        player_data.decode_client_avatar(),
+    ) else {
+        error!("Login: failed to decode avatar of player {}", player_data.id);
+        state.online_players.remove(&player_data.id, mailbox.session_id);
+        send_login_failed(session, ACCOUNT_LOCKED_REASON);
+        return;
+    };
+
+    // The membership may have changed while the player was offline.
+    let alliance = match db.fetch_player_alliance(&player_data.id) {
+        Ok(alliance) => {
+            set_avatar_alliance(&mut logic_client_avatar, alliance.as_ref());
+            set_avatar_alliance(&mut own_home_client_avatar, alliance.as_ref());
+            alliance
+        }
+        Err(err) => {
+            error!("Login: failed to fetch alliance of player {}: {err}", player_data.id);
+            None
+        }
+    };
 
     let mut set_encryption_message = ExtendedSetEncryptionMessage::new();
     let mut nonce = [0u8; 64];
@@ -225,7 +506,7 @@ fn handle_login_message(
     let mut login_ok_message = LoginOkMessage::new();
     login_ok_message.set_account_id(player_data.id.clone());
     login_ok_message.set_home_id(player_data.id.clone());
-    login_ok_message.set_pass_token(&player_data.pass_token);
+    login_ok_message.set_pass_token(&pass_token);
     login_ok_message.set_server_major_version(8);
     login_ok_message.set_server_build(67);
     login_ok_message.set_content_version(0);
@@ -236,13 +517,6 @@ fn handle_login_message(
     let mut logic_client_home = LogicClientHome::new();
     logic_client_home.set_home_json(&player_data.home_json);
 
-    let mut logic_client_avatar = LogicClientAvatar::new();
-
-    let data = rbase64::decode(&player_data.client_avatar_blob).unwrap();
-    let mut byte_stream = ByteStream::from(&data);
-    logic_client_avatar.decode(&mut byte_stream);
-    logic_client_avatar.set_id(&player_data.id);
-
     let mut logic_game_mode = LogicGameMode::new();
     let timestamp = time_util::get_current_timestamp();
     let seconds_since_last_save = (timestamp - player_data.last_save_timestamp) as i32;
@@ -251,40 +525,90 @@ fn handle_login_message(
 
     own_home_data_message.set_seconds_since_last_save(seconds_since_last_save);
     own_home_data_message.set_current_timestamp(timestamp as i32);
+    // The guard only starts counting down once the shield is over.
+    let shield_duration_seconds = (player_data.shield_end_timestamp - timestamp).max(0);
+    let guard_duration_seconds =
+        (player_data.guard_end_timestamp - player_data.shield_end_timestamp.max(timestamp)).max(0);
+
     own_home_data_message.set_logic_client_home({
         let mut logic_client_home = LogicClientHome::new();
         logic_client_home.set_home_json(&player_data.home_json);
+        logic_client_home.set_shield_duration_seconds(shield_duration_seconds as i32);
+        logic_client_home.set_guard_duration_seconds(guard_duration_seconds as i32);
         logic_client_home
     });
 
-    own_home_data_message.set_logic_client_avatar({
-        let mut logic_client_avatar = LogicClientAvatar::new();
-        let data = rbase64::decode(&player_data.client_avatar_blob).unwrap();
-        let mut byte_stream = ByteStream::from(&data);
-        logic_client_avatar.decode(&mut byte_stream);
-        logic_client_avatar.set_id(&player_data.id);        
-        logic_client_avatar
-    });
+    own_home_data_message.set_logic_client_avatar(own_home_client_avatar);
 
     session.account_id = player_data.id;
     session.logic_game_mode = Some(logic_game_mode);
     session.saved_home_json = Some(player_data.home_json);
+    session.mailbox = Some(mailbox);
+    session.is_admin = player_data.is_admin;
 
     session.messaging.send(login_ok_message.0);
     session.messaging.send(own_home_data_message.0);
+    send_avatar_stream(session, db);
+
+    if let Some((alliance, _)) = alliance {
+        send_alliance_stream(session, db, &alliance.id);
+    }
+
+    for line in state.global_chat.get_history() {
+        session.messaging.send(create_global_chat_line_message(&line).0);
+    }
 
     info!("successfully logged in");
 }
 
+fn set_avatar_alliance(
+    logic_client_avatar: &mut LogicClientAvatar,
+    alliance: Option<&(AllianceData, AllianceRole)>,
+) {
+    use logic::data::LogicDataTables;
+
+    match alliance {
+        Some((alliance, role)) => {
+            logic_client_avatar.set_alliance_id(Some(&alliance.id));
+            logic_client_avatar.set_alliance_name(&alliance.name);
+            logic_client_avatar
+                .set_alliance_badge_data(LogicDataTables::get_data_by_id(alliance.settings.badge_id));
+            logic_client_avatar.set_alliance_role(*role as i32);
+        }
+        None => {
+            logic_client_avatar.set_alliance_id(None);
+            logic_client_avatar.set_alliance_badge_data(None);
+        }
+    }
+}
+
+/// Tells the client why it can't log in, instead of leaving it on the
+/// loading screen.
+fn send_login_failed(session: &mut PlayerSession, reason: &str) {
+    use message::LoginFailedMessage;
+
+    let mut login_failed_message = LoginFailedMessage::new();
+    login_failed_message.set_error_code(LoginFailedMessage::ERROR_CODE_ACCOUNT_LOCKED);
+    login_failed_message.set_reason(reason);
+    session.messaging.send(login_failed_message.0);
+}
+
 fn handle_keep_alive_message(session: &mut PlayerSession, _message: PiranhaMessage) {
     session
         .messaging
         .send(message::KeepAliveServerMessage::new().0);
 }
 
-fn handle_go_home_message(session: &mut PlayerSession, _message: PiranhaMessage) {
+fn handle_go_home_message(session: &mut PlayerSession, state: &ServerState, _message: PiranhaMessage) {
     use message::OwnHomeDataMessage;
 
+    // Leaving an attack before the battle was over.
+    if let Some(battle) = session.battle.take()
+        && let Some(defender_id) = &battle.defender_id
+    {
+        state.online_players.end_attack(defender_id, battle.attack_id);
+    }
+
     let Some(logic_game_mode) = session.logic_game_mode.as_mut() else {
         error!("received GoHomeMessage while LogicGameMode is NULL!");
         return;
@@ -324,11 +648,13 @@ fn handle_go_home_message(session: &mut PlayerSession, _message: PiranhaMessage)
 
 fn handle_end_client_turn_message(
     session: &mut PlayerSession,
-    db: &Mutex<DatabaseConnection>,
+    state: &ServerState,
     message: PiranhaMessage,
 ) {
     use message::{EndClientTurnMessage, OutOfSyncMessage};
 
+    let db = &state.db;
+
     let message = EndClientTurnMessage(message);
 
     let Some(logic_game_mode) = session.logic_game_mode.as_mut() else {
@@ -342,24 +668,75 @@ fn handle_end_client_turn_message(
         message.get_checksum()
     );
 
+    let mut command_log = Vec::new();
+
     let client_sub_tick = message.get_sub_tick();
     while logic_game_mode.get_level().get_time().sub_tick < client_sub_tick {
+        let first_logged = command_log.len();
+        let resources_before = get_player_resources(logic_game_mode);
+
         if let Some(commands) = message.get_commands() {
             let cur_sub_tick = logic_game_mode.get_level().get_time().sub_tick;
             for command in commands.as_slice().iter() {
                 if command.get_execute_sub_tick() == cur_sub_tick {
+                    let client_command = command.decode();
                     info!(
-                        "received command: {}, exec sub tick: {}",
+                        "received command: {} {client_command:?}, exec sub tick: {}",
                         command.get_command_type(),
                         command.get_execute_sub_tick()
                     );
 
+                    if let Err(reason) = client_command.validate() {
+                        warn!("suspected cheat: command of {}: {reason}", session.account_id);
+                    }
+
+                    // Filled in before the command manager, which frees the
+                    // command once executed, gets it.
+                    command_log.push(CommandLogEntry {
+                        player_id: session.account_id.clone(),
+                        command_type: command.get_command_type(),
+                        parameters_json: serde_json::to_string(&client_command).unwrap_or_default(),
+                        execute_sub_tick: cur_sub_tick,
+                        timestamp: time_util::get_current_timestamp(),
+                        sub_tick_command_count: 1,
+                        resources_before,
+                        resources_after: resources_before,
+                    });
+
+                    if let Some(replay) = session.battle.as_mut().and_then(|battle| battle.replay.as_mut()) {
+                        // The first deployed unit breaks the attacker's shield.
+                        if replay.is_empty()
+                            && let Err(err) = db.clear_shield(&session.account_id)
+                        {
+                            error!("failed to clear shield of {}: {err}", session.account_id);
+                        }
+
+                        replay.record_command(command);
+                    }
+
                     logic_game_mode.get_command_manager().add_command(command);
                 }
             }
         }
 
+        // Commands run inside the sub tick, the same way `BattleReplay::simulate`
+        // runs them, so the resources can only be read around all of it.
         logic_game_mode.update_one_sub_tick();
+
+        if command_log.len() > first_logged {
+            let resources_after = get_player_resources(logic_game_mode);
+            let sub_tick_command_count = (command_log.len() - first_logged) as i32;
+            for entry in command_log[first_logged..].iter_mut() {
+                entry.resources_after = resources_after;
+                entry.sub_tick_command_count = sub_tick_command_count;
+            }
+        }
+    }
+
+    if !command_log.is_empty()
+        && let Err(err) = db.add_command_log_entries(&command_log)
+    {
+        error!("failed to save command log of {}: {err}", session.account_id);
     }
 
     let mut debug_json = LogicJSONNode::new_json_object();
@@ -387,7 +764,7 @@ fn handle_end_client_turn_message(
 
         let home_json = string_builder.to_string();
 
-        if let Err(err) = db.lock().unwrap().save_player_data(
+        if let Err(err) = db.save_player_data(
             &session.account_id,
             &home_json,
             &logic_game_mode.get_cloned_home_owner().unwrap(),
@@ -397,6 +774,331 @@ fn handle_end_client_turn_message(
 
         session.saved_home_json = Some(home_json);
     }
+
+    if session.battle.is_some() && logic_game_mode.is_battle_over() {
+        let mut battle = session.battle.take().unwrap();
+        let Some(mut battle_log_entry) =
+            create_battle_log_entry(&session.account_id, logic_game_mode, &battle)
+        else {
+            if let Some(defender_id) = &battle.defender_id {
+                state.online_players.end_attack(defender_id, battle.attack_id);
+            }
+            return;
+        };
+
+        match &battle.defender_id {
+            Some(defender_id) => {
+                let end_sub_tick = logic_game_mode.get_level().get_time().sub_tick;
+                battle.replay.as_mut().unwrap().set_end_sub_tick(end_sub_tick);
+                let replay = battle.replay.as_ref().unwrap();
+
+                match verify_battle_result(replay, &battle_log_entry) {
+                    BattleVerification::Reproduced => (),
+                    // The server's result stands, for the attacker's loot and
+                    // trophies as much as for the defender.
+                    BattleVerification::Mismatch(simulated_game_mode) => {
+                        let Some(simulated_battle_log_entry) =
+                            create_battle_log_entry(&session.account_id, &simulated_game_mode, &battle)
+                        else {
+                            warn!(
+                                "suspected cheat: battle of {} against {defender_id} doesn't reproduce and its simulation has no result, rolled back, (stars, destruction, gold, elixir, dark elixir, trophies): client {:?}",
+                                session.account_id,
+                                get_battle_result(&battle_log_entry)
+                            );
+                            *logic_game_mode = replay.simulate_without_commands();
+                            state.online_players.end_attack(defender_id, battle.attack_id);
+                            return;
+                        };
+
+                        warn!(
+                            "suspected cheat: battle of {} against {defender_id} doesn't reproduce, applying the server's result, (stars, destruction, gold, elixir, dark elixir, trophies): client {:?}, server {:?}",
+                            session.account_id,
+                            get_battle_result(&battle_log_entry),
+                            get_battle_result(&simulated_battle_log_entry)
+                        );
+
+                        battle_log_entry = simulated_battle_log_entry;
+                        *logic_game_mode = simulated_game_mode;
+                    }
+                    // Neither side keeps anything from a battle that can't be checked.
+                    BattleVerification::Failed => {
+                        warn!(
+                            "suspected cheat: battle of {} against {defender_id} can't be verified, rolled back, (stars, destruction, gold, elixir, dark elixir, trophies): client {:?}",
+                            session.account_id,
+                            get_battle_result(&battle_log_entry)
+                        );
+                        *logic_game_mode = replay.simulate_without_commands();
+                        state.online_players.end_attack(defender_id, battle.attack_id);
+                        return;
+                    }
+                }
+
+                // The defender may have logged in after the attack expired.
+                if !state.online_players.is_attack_held(defender_id, battle.attack_id) {
+                    warn!("attack of {} on {defender_id} expired before it ended", session.account_id);
+                    return;
+                }
+
+                let saved = apply_defense_result(
+                    state,
+                    logic_game_mode,
+                    defender_id,
+                    &mut battle_log_entry,
+                    &replay.compress(),
+                );
+                state.online_players.end_attack(defender_id, battle.attack_id);
+
+                if saved {
+                    let mut avatar_stream_entry_message = message::AvatarStreamEntryMessage::new();
+                    avatar_stream_entry_message.set_avatar_stream_entry(
+                        create_battle_log_stream_entry(&session.account_id, &battle_log_entry),
+                    );
+                    session.messaging.send(avatar_stream_entry_message.0);
+                }
+            }
+            None => match &battle.war_attack {
+                Some(war_attack) => {
+                    match db.record_war_attack(
+                        war_attack.war_id,
+                        war_attack.side,
+                        war_attack.position,
+                        war_attack.defender_position,
+                        battle_log_entry.stars,
+                        battle_log_entry.destruction_percentage,
+                    ) {
+                        Ok(new_stars) => info!(
+                            "war attack of {} on base {} of war {}: {new_stars} new star(s)",
+                            session.account_id, war_attack.defender_position, war_attack.war_id
+                        ),
+                        Err(err) => error!("failed to record war attack of {}: {err}", session.account_id),
+                    }
+                }
+                None => {
+                    if let Err(err) = db.save_battle_log_entry(&mut battle_log_entry) {
+                        error!("failed to save NPC battle log of {}: {err}", session.account_id);
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Resources of the player whose commands the game mode executes.
+fn get_player_resources(logic_game_mode: &LogicGameMode) -> AvatarResources {
+    let logic_client_avatar = if logic_game_mode.get_state() == 1 {
+        logic_game_mode.get_level().get_home_owner_avatar::<LogicClientAvatar>()
+    } else {
+        logic_game_mode.get_level().get_visitor_avatar::<LogicClientAvatar>()
+    };
+
+    logic_client_avatar
+        .as_ref()
+        .map(AvatarResources::from_avatar)
+        .unwrap_or_default()
+}
+
+fn create_battle_log_entry(
+    account_id: &LogicLong,
+    logic_game_mode: &LogicGameMode,
+    battle: &Battle,
+) -> Option<BattleLogEntry> {
+    use logic::data::LogicResourceData;
+
+    let Some(battle_log) = logic_game_mode.get_level().get_battle_log() else {
+        error!("battle of {account_id} is over but its battle log is NULL!");
+        return None;
+    };
+
+    let Some(attacker_avatar) = logic_game_mode.get_level().get_visitor_avatar::<LogicClientAvatar>() else {
+        error!("battle of {account_id} is over but visitor_avatar is NULL!");
+        return None;
+    };
+
+    // NPC avatars have no name or level of their own.
+    let (defender_name, defender_exp_level) = match battle.defender_id {
+        Some(_) => {
+            let Some(defender_avatar) = logic_game_mode
+                .get_level()
+                .get_home_owner_avatar::<LogicClientAvatar>()
+            else {
+                error!("battle of {account_id} is over but home_owner_avatar is NULL!");
+                return None;
+            };
+
+            (defender_avatar.get_name().unwrap_or_default(), defender_avatar.get_exp_level())
+        }
+        None => (String::new(), 0),
+    };
+
+    let mut attacker_log_json = StringBuilder::new();
+    battle_log.generate_attacker_json().write_to_string(&mut attacker_log_json);
+
+    let mut defender_log_json = StringBuilder::new();
+    battle_log.generate_defender_json().write_to_string(&mut defender_log_json);
+
+    let battle_log_entry = BattleLogEntry {
+        id: 0,
+        attacker_id: account_id.clone(),
+        defender_id: battle.defender_id.clone(),
+        attacker_name: attacker_avatar.get_name().unwrap_or_default(),
+        attacker_exp_level: attacker_avatar.get_exp_level(),
+        defender_name,
+        defender_exp_level,
+        start_timestamp: battle.start_timestamp,
+        end_timestamp: time_util::get_current_timestamp(),
+        stars: battle_log.get_stars(),
+        destruction_percentage: battle_log.get_destruction_percentage(),
+        stolen_gold: battle_log.get_stolen_resources(&LogicResourceData::gold()),
+        stolen_elixir: battle_log.get_stolen_resources(&LogicResourceData::elixir()),
+        stolen_dark_elixir: battle_log.get_stolen_resources(&LogicResourceData::dark_elixir()),
+        attacker_score_delta: battle_log.get_attacker_score(),
+        defender_score_delta: battle_log.get_defender_score(),
+        attacker_log_json: attacker_log_json.to_string(),
+        defender_log_json: defender_log_json.to_string(),
+        revenge_used: false,
+    };
+
+    info!(
+        "battle of {account_id} is over: stars: {}, destruction: {}%, loot: {}/{}/{}, score: {}/{}",
+        battle_log_entry.stars,
+        battle_log_entry.destruction_percentage,
+        battle_log_entry.stolen_gold,
+        battle_log_entry.stolen_elixir,
+        battle_log_entry.stolen_dark_elixir,
+        battle_log_entry.attacker_score_delta,
+        battle_log_entry.defender_score_delta,
+    );
+
+    Some(battle_log_entry)
+}
+
+/// How a multiplayer battle compares to its replay simulated again.
+enum BattleVerification {
+    Reproduced,
+    /// The simulated battle, which ended differently.
+    Mismatch(LogicGameMode),
+    Failed,
+}
+
+/// Simulates the battle again from its recorded start state and checks it
+/// ends the same way as the one played along with the client.
+fn verify_battle_result(replay: &BattleReplay, battle_log_entry: &BattleLogEntry) -> BattleVerification {
+    use logic::data::LogicResourceData;
+
+    let attacker_id = &battle_log_entry.attacker_id;
+    let defender_id = battle_log_entry.defender_id.as_ref().unwrap();
+
+    let Some(logic_game_mode) = replay.simulate() else {
+        error!("battle of {attacker_id} against {defender_id}: failed to simulate the replay");
+        return BattleVerification::Failed;
+    };
+
+    let Some(battle_log) = logic_game_mode.get_level().get_battle_log() else {
+        error!("battle of {attacker_id} against {defender_id}: simulated battle log is NULL!");
+        return BattleVerification::Failed;
+    };
+
+    let simulated_result = (
+        battle_log.get_stars(),
+        battle_log.get_destruction_percentage(),
+        battle_log.get_stolen_resources(&LogicResourceData::gold()),
+        battle_log.get_stolen_resources(&LogicResourceData::elixir()),
+        battle_log.get_stolen_resources(&LogicResourceData::dark_elixir()),
+    );
+
+    let client_result = (
+        battle_log_entry.stars,
+        battle_log_entry.destruction_percentage,
+        battle_log_entry.stolen_gold,
+        battle_log_entry.stolen_elixir,
+        battle_log_entry.stolen_dark_elixir,
+    );
+
+    if simulated_result != client_result {
+        return BattleVerification::Mismatch(logic_game_mode);
+    }
+
+    BattleVerification::Reproduced
+}
+
+/// Stars, destruction, gold, elixir, dark elixir and trophies, for logging.
+fn get_battle_result(battle_log_entry: &BattleLogEntry) -> (i32, i32, i32, i32, i32, i32) {
+    (
+        battle_log_entry.stars,
+        battle_log_entry.destruction_percentage,
+        battle_log_entry.stolen_gold,
+        battle_log_entry.stolen_elixir,
+        battle_log_entry.stolen_dark_elixir,
+        battle_log_entry.attacker_score_delta,
+    )
+}
+
+fn apply_defense_result(
+    state: &ServerState,
+    logic_game_mode: &LogicGameMode,
+    defender_id: &LogicLong,
+    battle_log_entry: &mut BattleLogEntry,
+    replay: &[u8],
+) -> bool {
+    let mut string_builder = StringBuilder::new();
+    let mut home_json_object = LogicJSONNode::new_json_object();
+    logic_game_mode.save_to_json(&mut home_json_object);
+    home_json_object.write_to_string(&mut string_builder);
+
+    let shield_timers = match get_defense_shield(battle_log_entry.destruction_percentage) {
+        Some(shield) => {
+            const HOUR: i64 = 60 * 60;
+
+            let shield_end_timestamp =
+                time_util::get_current_timestamp() + shield.get_time_hours() as i64 * HOUR;
+            ShieldTimers {
+                shield_end_timestamp,
+                guard_end_timestamp: shield_end_timestamp
+                    + shield.get_guard_time_hours() as i64 * HOUR,
+            }
+        }
+        None => ShieldTimers {
+            shield_end_timestamp: 0,
+            guard_end_timestamp: 0,
+        },
+    };
+
+    match state.db.save_defense_result(
+        defender_id,
+        &string_builder.to_string(),
+        &shield_timers,
+        battle_log_entry,
+        replay,
+    ) {
+        Ok(true) => true,
+        Ok(false) => {
+            error!("failed to save defense result of {defender_id}: avatar doesn't decode");
+            false
+        }
+        Err(err) => {
+            error!("failed to save defense result of {defender_id}: {err}");
+            false
+        }
+    }
+}
+
+/// Defenses past 40%, 60% and 90% destruction grant the shortest, second
+/// and third shortest shield of `logic/shields.csv`, followed by its guard.
+fn get_defense_shield(destruction_percentage: i32) -> Option<logic::data::LogicShieldData> {
+    use logic::data::LogicShieldData;
+
+    let tier = match destruction_percentage {
+        90.. => 2,
+        60.. => 1,
+        40.. => 0,
+        _ => return None,
+    };
+
+    let mut shields = LogicShieldData::get_all();
+    shields.sort_by_key(LogicShieldData::get_time_hours);
+
+    let index = usize::min(tier, shields.len().checked_sub(1)?);
+    shields.into_iter().nth(index)
 }
 
 fn handle_attack_npc_message(session: &mut PlayerSession, message: PiranhaMessage) {
@@ -442,63 +1144,2147 @@ fn handle_attack_npc_message(session: &mut PlayerSession, message: PiranhaMessag
     npc_data_message.set_logic_npc_avatar(&logic_game_mode.get_cloned_home_owner().unwrap());
     npc_data_message.set_logic_client_avatar(&logic_game_mode.get_cloned_visitor().unwrap());
 
+    session.battle = Some(Battle {
+        defender_id: None,
+        attack_id: 0,
+        start_timestamp: time_util::get_current_timestamp(),
+        replay: None,
+        war_attack: None,
+    });
     session.logic_game_mode = Some(logic_game_mode);
     session.messaging.send(npc_data_message.0);
 }
 
-fn handle_ask_for_avatar_profile_message(session: &mut PlayerSession, db: &Mutex<DatabaseConnection>, message: PiranhaMessage) {
-    use message::{AskForAvatarProfileMessage, AvatarProfileMessage, AvatarProfileFullEntry};
+fn handle_visit_home_message(
+    session: &mut PlayerSession,
+    db: &DatabaseConnection,
+    message: PiranhaMessage,
+) {
+    use message::{VisitHomeMessage, VisitedHomeDataMessage};
 
-    let ask_for_avatar_profile_message = AskForAvatarProfileMessage(message);
+    let message = VisitHomeMessage(message);
 
-    info!(
-        "AskForAvatarProfileMessage received, account_id: {}",
-        ask_for_avatar_profile_message.get_account_id()
-    );
+    let Some(logic_game_mode) = session.logic_game_mode.as_ref() else {
+        error!("received VisitHomeMessage while LogicGameMode is NULL!");
+        return;
+    };
 
-    let Ok(Some(player_data)) = db
-        .lock()
-        .unwrap()
-        .fetch_player(ask_for_avatar_profile_message.get_account_id())
-    else {
-        warn!(
-            "Profile Message: player with id {} was not found in the database",
-            ask_for_avatar_profile_message.get_account_id()
-        );
+    if logic_game_mode.get_state() != 1 {
+        error!("received VisitHomeMessage outside of home state!");
+        return;
+    }
+
+    let Some(visitor_avatar) = logic_game_mode.get_cloned_home_owner::<LogicClientAvatar>() else {
+        error!("received VisitHomeMessage while home_owner_avatar is NULL!");
         return;
     };
 
-    let mut logic_client_avatar = LogicClientAvatar::new();
+    let home_id = message.get_home_id();
 
-    let data = rbase64::decode(&player_data.client_avatar_blob).unwrap();
-    let mut byte_stream = ByteStream::from(&data);
-    logic_client_avatar.decode(&mut byte_stream);
+    let Ok(Some(player_data)) = db.fetch_player(home_id) else {
+        warn!("VisitHome: player with id {home_id} was not found in the database");
+        return;
+    };
 
-    let mut avatar_profile_full_entry = AvatarProfileFullEntry::new();
-    avatar_profile_full_entry.set_logic_client_avatar(logic_client_avatar);
-    avatar_profile_full_entry.set_home_json(&player_data.home_json);
+    let Some(owner_avatar) = player_data.decode_client_avatar() else {
+        error!("VisitHome: failed to decode avatar of player {home_id}");
+        return;
+    };
 
-    let mut avatar_profile_message = AvatarProfileMessage::new();
+    let mut logic_client_home = LogicClientHome::new();
+    logic_client_home.set_home_json(&player_data.home_json);
 
-    avatar_profile_message.set_avatar_profile_full_entry(avatar_profile_full_entry);
+    let timestamp = time_util::get_current_timestamp();
+    let seconds_since_last_save = (timestamp - player_data.last_save_timestamp) as i32;
 
-    session.messaging.send(avatar_profile_message.0);
+    let mut logic_game_mode = LogicGameMode::new();
+    logic_game_mode.load_visit_state(
+        &logic_client_home,
+        &owner_avatar,
+        &visitor_avatar,
+        seconds_since_last_save,
+    );
+    logic_game_mode.set_current_timestamp(timestamp as i32);
+
+    let mut visited_home_data_message = VisitedHomeDataMessage::new();
+    visited_home_data_message.set_current_timestamp(timestamp as i32);
+    visited_home_data_message.set_logic_client_home({
+        let mut logic_client_home = LogicClientHome::new();
+        logic_client_home.set_home_json(&player_data.home_json);
+        logic_client_home
+    });
+    visited_home_data_message.set_owner_avatar(logic_game_mode.get_cloned_home_owner().unwrap());
+    visited_home_data_message.set_visitor_avatar(logic_game_mode.get_cloned_visitor().unwrap());
+
+    session.logic_game_mode = Some(logic_game_mode);
+    session.messaging.send(visited_home_data_message.0);
 }
 
-fn handle_change_avatar_name_message(session: &mut PlayerSession, message: PiranhaMessage) {
-    use logic::command::LogicChangeAvatarNameCommand;
-    use message::{AvailableServerCommandMessage, ChangeAvatarNameMessage};
+fn handle_home_battle_replay_message(
+    session: &mut PlayerSession,
+    db: &DatabaseConnection,
+    message: PiranhaMessage,
+) {
+    use message::{HomeBattleReplayDataMessage, HomeBattleReplayMessage};
 
-    let message = ChangeAvatarNameMessage(message);
+    let message = HomeBattleReplayMessage(message);
 
-    let mut logic_change_avatar_name_command = LogicChangeAvatarNameCommand::new();
-    logic_change_avatar_name_command.set_avatar_name(&message.get_avatar_name());
-    logic_change_avatar_name_command.set_name_change_state(1);
+    // Replays are stored under the id of their battle log entry, which is
+    // also the id of the stream entry the client asks from.
+    let replay_id = message.get_replay_id().to_long();
 
-    let mut available_server_command_message = AvailableServerCommandMessage::new();
-    available_server_command_message.set_server_command(&logic_change_avatar_name_command.0);
+    let replay = match db.fetch_battle_replay(replay_id) {
+        Ok(Some(replay)) => replay,
+        Ok(None) => {
+            warn!("HomeBattleReplay: replay {replay_id} was not found in the database");
+            return;
+        }
+        Err(err) => {
+            error!("HomeBattleReplay: failed to fetch replay {replay_id}: {err}");
+            return;
+        }
+    };
 
-    session.messaging.send(available_server_command_message.0);
+    let mut home_battle_replay_data_message = HomeBattleReplayDataMessage::new();
+    home_battle_replay_data_message.set_replay_data(&replay);
+    session.messaging.send(home_battle_replay_data_message.0);
+}
+
+fn handle_attack_matched_home_message(
+    session: &mut PlayerSession,
+    state: &ServerState,
+    _message: PiranhaMessage,
+) {
+    let Some(logic_game_mode) = session.logic_game_mode.as_ref() else {
+        error!("received AttackMatchedHomeMessage while LogicGameMode is NULL!");
+        return;
+    };
+
+    // Searching for the next opponent while scouting the current one.
+    let attacker_avatar = if let Some(battle) = session
+        .battle
+        .take_if(|battle| battle.defender_id.is_some())
+        && let Some(defender_id) = &battle.defender_id
+    {
+        state.online_players.end_attack(defender_id, battle.attack_id);
+        logic_game_mode.get_cloned_visitor::<LogicClientAvatar>()
+    } else if logic_game_mode.get_state() == 1 {
+        logic_game_mode.get_cloned_home_owner::<LogicClientAvatar>()
+    } else {
+        error!("received AttackMatchedHomeMessage outside of home state!");
+        return;
+    };
+
+    let Some(attacker_avatar) = attacker_avatar else {
+        error!("received AttackMatchedHomeMessage while attacker avatar is NULL!");
+        return;
+    };
+
+    const MAX_MATCH_ATTEMPTS: usize = 3;
+
+    let mut excluded_ids = state.online_players.get_unavailable_ids();
+    excluded_ids.push(session.account_id.to_long());
+
+    let mut matched_player_data = None;
+    for _ in 0..MAX_MATCH_ATTEMPTS {
+        match state.db.find_opponent(attacker_avatar.get_score(), &excluded_ids) {
+            // The opponent could have logged in or been matched by someone else meanwhile.
+            Ok(Some(player_data)) => match state.online_players.try_begin_attack(&player_data.id) {
+                Some(attack_id) => {
+                    matched_player_data = Some((player_data, attack_id));
+                    break;
+                }
+                None => excluded_ids.push(player_data.id.to_long()),
+            },
+            Ok(None) => break,
+            Err(err) => {
+                error!("AttackMatchedHome: matchmaking query failed: {err}");
+                return;
+            }
+        }
+    }
+
+    let Some((player_data, attack_id)) = matched_player_data else {
+        warn!("AttackMatchedHome: no opponent found for {}", session.account_id);
+        return;
+    };
+
+    info!(
+        "AttackMatchedHome: {} matched against {} (score {})",
+        session.account_id, player_data.id, player_data.score
+    );
+
+    start_multiplayer_attack(session, state, player_data, attack_id, &attacker_avatar);
+}
+
+fn handle_revenge_attack_message(
+    session: &mut PlayerSession,
+    state: &ServerState,
+    message: PiranhaMessage,
+) {
+    use message::RevengeAttackMessage;
+
+    let message = RevengeAttackMessage(message);
+
+    let Some(logic_game_mode) = session.logic_game_mode.as_ref() else {
+        error!("received RevengeAttackMessage while LogicGameMode is NULL!");
+        return;
+    };
+
+    if logic_game_mode.get_state() != 1 {
+        error!("received RevengeAttackMessage outside of home state!");
+        return;
+    }
+
+    let Some(attacker_avatar) = logic_game_mode.get_cloned_home_owner::<LogicClientAvatar>() else {
+        error!("received RevengeAttackMessage while home_owner_avatar is NULL!");
+        return;
+    };
+
+    // Stream entries carry the id of their battle log entry.
+    let battle_log_id = message.get_stream_entry_id().to_long();
+
+    let battle_log_entry = match state.db.fetch_battle_log_entry(battle_log_id) {
+        Ok(Some(entry)) if entry.defender_id.as_ref() == Some(&session.account_id) => entry,
+        Ok(_) => {
+            warn!("Revenge: {} has no defense {battle_log_id}", session.account_id);
+            return;
+        }
+        Err(err) => {
+            error!("Revenge: failed to fetch battle log entry {battle_log_id}: {err}");
+            return;
+        }
+    };
+
+    if battle_log_entry.revenge_used {
+        warn!("Revenge: revenge for {battle_log_id} was already used");
+        return;
+    }
+
+    let target_id = battle_log_entry.attacker_id;
+
+    let Ok(Some(player_data)) = state.db.fetch_player(&target_id) else {
+        warn!("Revenge: player with id {target_id} was not found in the database");
+        return;
+    };
+
+    let timestamp = time_util::get_current_timestamp();
+    if player_data.shield_end_timestamp > timestamp || player_data.guard_end_timestamp > timestamp {
+        info!("Revenge: {target_id} is shielded");
+        return;
+    }
+
+    let Some(attack_id) = state.online_players.try_begin_attack(&target_id) else {
+        info!("Revenge: {target_id} is online or already being attacked");
+        return;
+    };
+
+    // Taken before the attack starts, so two sessions can't both use it,
+    // and given back if the attack doesn't start after all.
+    match state.db.use_revenge(battle_log_id) {
+        Ok(true) => (),
+        Ok(false) => {
+            warn!("Revenge: revenge for {battle_log_id} was already used");
+            state.online_players.end_attack(&target_id, attack_id);
+            return;
+        }
+        Err(err) => {
+            error!("Revenge: failed to mark revenge for {battle_log_id} as used: {err}");
+            state.online_players.end_attack(&target_id, attack_id);
+            return;
+        }
+    }
+
+    info!("Revenge: {} attacks {target_id} back", session.account_id);
+
+    if !start_multiplayer_attack(session, state, player_data, attack_id, &attacker_avatar)
+        && let Err(err) = state.db.release_revenge(battle_log_id)
+    {
+        error!("Revenge: failed to give back revenge for {battle_log_id}: {err}");
+    }
+}
+
+/// Loads `player_data`'s home into an attack state and sends it to the
+/// attacker. The caller must have begun the attack in `online_players`.
+/// Returns `false`, with the attack ended, if the home couldn't be loaded.
+fn start_multiplayer_attack(
+    session: &mut PlayerSession,
+    state: &ServerState,
+    player_data: PlayerSaveData,
+    attack_id: u64,
+    attacker_avatar: &LogicClientAvatar,
+) -> bool {
+    let Some(defender_avatar) = player_data.decode_client_avatar() else {
+        error!("failed to decode avatar of player {}", player_data.id);
+        state.online_players.end_attack(&player_data.id, attack_id);
+        return false;
+    };
+
+    let timestamp = time_util::get_current_timestamp();
+    let seconds_since_last_save = (timestamp - player_data.last_save_timestamp) as i32;
+
+    let replay = BattleReplay::new(
+        &player_data.home_json,
+        &defender_avatar,
+        attacker_avatar,
+        timestamp as i32,
+        seconds_since_last_save,
+    );
+
+    session.battle = Some(Battle {
+        defender_id: Some(player_data.id),
+        attack_id,
+        start_timestamp: timestamp,
+        replay: Some(replay),
+        war_attack: None,
+    });
+
+    send_enemy_home(
+        session,
+        &player_data.home_json,
+        &defender_avatar,
+        attacker_avatar,
+        timestamp,
+        seconds_since_last_save,
+    );
+
+    true
+}
+
+/// Loads the home into an attack state and sends it to the attacker.
+fn send_enemy_home(
+    session: &mut PlayerSession,
+    home_json: &str,
+    defender_avatar: &LogicClientAvatar,
+    attacker_avatar: &LogicClientAvatar,
+    timestamp: i64,
+    seconds_since_last_save: i32,
+) {
+    use message::EnemyHomeDataMessage;
+
+    let mut logic_client_home = LogicClientHome::new();
+    logic_client_home.set_home_json(home_json);
+
+    let mut logic_game_mode = LogicGameMode::new();
+    logic_game_mode.load_matched_attack_state(
+        &logic_client_home,
+        defender_avatar,
+        attacker_avatar,
+        seconds_since_last_save,
+    );
+    logic_game_mode.set_current_timestamp(timestamp as i32);
+
+    let mut enemy_home_data_message = EnemyHomeDataMessage::new();
+    enemy_home_data_message.set_current_timestamp(timestamp as i32);
+    enemy_home_data_message.set_logic_client_home({
+        let mut logic_client_home = LogicClientHome::new();
+        logic_client_home.set_home_json(home_json);
+        logic_client_home
+    });
+    enemy_home_data_message.set_enemy_avatar(logic_game_mode.get_cloned_home_owner().unwrap());
+    enemy_home_data_message.set_attacker_avatar(logic_game_mode.get_cloned_visitor().unwrap());
+
+    session.logic_game_mode = Some(logic_game_mode);
+    session.messaging.send(enemy_home_data_message.0);
+}
+
+fn handle_ask_for_avatar_stream_message(
+    session: &mut PlayerSession,
+    db: &DatabaseConnection,
+    _message: PiranhaMessage,
+) {
+    send_avatar_stream(session, db);
+}
+
+fn send_avatar_stream(session: &mut PlayerSession, db: &DatabaseConnection) {
+    use message::AvatarStreamMessage;
+
+    let battle_log = match db.fetch_battle_log(&session.account_id) {
+        Ok(battle_log) => battle_log,
+        Err(err) => {
+            error!("failed to fetch battle log of {}: {err}", session.account_id);
+            return;
+        }
+    };
+
+    let mut avatar_stream_message = AvatarStreamMessage::new();
+    avatar_stream_message.set_avatar_stream_entries(
+        battle_log
+            .iter()
+            .map(|entry| create_battle_log_stream_entry(&session.account_id, entry))
+            .collect(),
+    );
+
+    session.messaging.send(avatar_stream_message.0);
+}
+
+/// The entry shows the opponent as its sender: the defender in the player's
+/// attack log, the attacker in their defense log.
+fn create_battle_log_stream_entry(
+    account_id: &LogicLong,
+    battle_log_entry: &BattleLogEntry,
+) -> message::BattleLogStreamEntry {
+    use message::BattleLogStreamEntry;
+
+    let is_attacker = battle_log_entry.attacker_id == *account_id;
+
+    let (mut entry, sender_id, sender_name, sender_exp_level, battle_log_json) = if is_attacker {
+        (
+            BattleLogStreamEntry::new_attacker(),
+            battle_log_entry.defender_id.clone().unwrap_or(LogicLong::new(0, 0)),
+            &battle_log_entry.defender_name,
+            battle_log_entry.defender_exp_level,
+            &battle_log_entry.attacker_log_json,
+        )
+    } else {
+        (
+            BattleLogStreamEntry::new_defender(),
+            battle_log_entry.attacker_id.clone(),
+            &battle_log_entry.attacker_name,
+            battle_log_entry.attacker_exp_level,
+            &battle_log_entry.defender_log_json,
+        )
+    };
+
+    entry.set_id(&LogicLong::from_long(battle_log_entry.id));
+    entry.set_sender_avatar_id(&sender_id);
+    entry.set_sender_name(sender_name);
+    entry.set_sender_exp_level(sender_exp_level);
+    entry.set_age_seconds(
+        (time_util::get_current_timestamp() - battle_log_entry.end_timestamp) as i32,
+    );
+    entry.set_battle_log_json(battle_log_json);
+    entry.set_home_id(&sender_id);
+    entry
+}
+
+fn handle_ask_for_avatar_profile_message(session: &mut PlayerSession, db: &DatabaseConnection, message: PiranhaMessage) {
+    use message::{AskForAvatarProfileMessage, AvatarProfileMessage, AvatarProfileFullEntry};
+
+    let ask_for_avatar_profile_message = AskForAvatarProfileMessage(message);
+
+    info!(
+        "AskForAvatarProfileMessage received, account_id: {}",
+        ask_for_avatar_profile_message.get_account_id()
+    );
+
+    let Ok(Some(player_data)) = db.fetch_player(ask_for_avatar_profile_message.get_account_id())
+    else {
+        warn!(
+            "Profile Message: player with id {} was not found in the database",
+            ask_for_avatar_profile_message.get_account_id()
+        );
+        return;
+    };
+
+    let Some(logic_client_avatar) = player_data.decode_client_avatar() else {
+        error!(
+            "Profile Message: failed to decode avatar of player {}",
+            player_data.id
+        );
+        return;
+    };
+
+    let mut avatar_profile_full_entry = AvatarProfileFullEntry::new();
+    avatar_profile_full_entry.set_logic_client_avatar(logic_client_avatar);
+    avatar_profile_full_entry.set_home_json(&player_data.home_json);
+
+    let mut avatar_profile_message = AvatarProfileMessage::new();
+
+    avatar_profile_message.set_avatar_profile_full_entry(avatar_profile_full_entry);
+
+    session.messaging.send(avatar_profile_message.0);
+}
+
+fn handle_ask_for_avatar_ranking_list_message(
+    session: &mut PlayerSession,
+    state: &ServerState,
+    _message: PiranhaMessage,
+) {
+    use message::{AvatarRankingEntry, AvatarRankingListMessage};
+
+    let Some(leaderboard) = state.leaderboard.get(&state.db) else {
+        error!("AskForAvatarRankingListMessage: leaderboard is not available");
+        return;
+    };
+
+    let create_entry = |id: &LogicLong, name: &str, exp_level: i32, score: i32, order: i32| {
+        let mut entry = AvatarRankingEntry::new();
+        entry.set_id(id);
+        entry.set_home_id(id);
+        entry.set_name(name);
+        entry.set_exp_level(exp_level);
+        entry.set_score(score);
+        entry.set_order(order);
+        entry.set_previous_order(leaderboard.get_previous_rank(id).unwrap_or(order));
+        entry
+    };
+
+    let mut entries = leaderboard
+        .top_players
+        .iter()
+        .enumerate()
+        .map(|(i, player)| {
+            create_entry(&player.id, &player.name, player.exp_level, player.score, i as i32 + 1)
+        })
+        .collect::<Vec<_>>();
+
+    // Players below the top of the board still get their own rank appended.
+    let own_avatar = session
+        .logic_game_mode
+        .as_ref()
+        .filter(|logic_game_mode| logic_game_mode.get_state() == 1)
+        .and_then(|logic_game_mode| {
+            logic_game_mode
+                .get_level()
+                .get_home_owner_avatar::<LogicClientAvatar>()
+        });
+
+    let own_rank = match leaderboard.get_rank(&session.account_id) {
+        Some(_) => None,
+        None => state.db.fetch_player_rank(&session.account_id).unwrap_or_else(|err| {
+            error!("AskForAvatarRankingListMessage: failed to fetch rank of {}: {err}", session.account_id);
+            None
+        }),
+    };
+
+    if let (Some(own_rank), Some(own_avatar)) = (own_rank, own_avatar) {
+        entries.push(create_entry(
+            &session.account_id,
+            &own_avatar.get_name().unwrap_or_default(),
+            own_avatar.get_exp_level(),
+            own_avatar.get_score(),
+            own_rank,
+        ));
+    }
+
+    let mut avatar_ranking_list_message = AvatarRankingListMessage::new();
+    avatar_ranking_list_message.set_avatar_ranking_list(entries);
+
+    session.messaging.send(avatar_ranking_list_message.0);
+}
+
+fn handle_change_avatar_name_message(session: &mut PlayerSession, message: PiranhaMessage) {
+    use logic::command::LogicChangeAvatarNameCommand;
+    use message::{AvailableServerCommandMessage, ChangeAvatarNameMessage};
+
+    let message = ChangeAvatarNameMessage(message);
+
+    let mut logic_change_avatar_name_command = LogicChangeAvatarNameCommand::new();
+    logic_change_avatar_name_command.set_avatar_name(&message.get_avatar_name());
+    logic_change_avatar_name_command.set_name_change_state(1);
+
+    let mut available_server_command_message = AvailableServerCommandMessage::new();
+    available_server_command_message.set_server_command(&logic_change_avatar_name_command.0);
+
+    session.messaging.send(available_server_command_message.0);
+}
+
+fn handle_create_alliance_message(
+    session: &mut PlayerSession,
+    db: &DatabaseConnection,
+    message: PiranhaMessage,
+) {
+    use logic::command::LogicJoinAllianceCommand;
+    use logic::data::LogicDataTables;
+    use message::{AvailableServerCommandMessage, CreateAllianceMessage};
+
+    const MAX_ALLIANCE_NAME_LENGTH: usize = 16;
+
+    let message = CreateAllianceMessage(message);
+
+    let name = message
+        .get_alliance_name()
+        .map(|name| name.to_string().trim().to_string())
+        .unwrap_or_default();
+
+    if name.is_empty() || name.chars().count() > MAX_ALLIANCE_NAME_LENGTH {
+        warn!("CreateAlliance: invalid alliance name {name:?}");
+        return;
+    }
+
+    let Some(settings) = read_alliance_settings(
+        message.get_alliance_description(),
+        message.get_alliance_badge_data(),
+        message.get_alliance_type(),
+        message.get_required_score(),
+    ) else {
+        warn!("CreateAlliance: invalid alliance settings from {}", session.account_id);
+        return;
+    };
+
+    let alliance = match db.create_alliance(&session.account_id, &name, &settings) {
+        Ok(Some(alliance)) => alliance,
+        Ok(None) => {
+            warn!("CreateAlliance: {} is already in an alliance", session.account_id);
+            return;
+        }
+        Err(err) => {
+            error!("CreateAlliance: failed to create alliance: {err}");
+            return;
+        }
+    };
+
+    info!("CreateAlliance: {} created alliance {} ({name})", session.account_id, alliance.id);
+
+    let mut logic_join_alliance_command = LogicJoinAllianceCommand::new();
+    logic_join_alliance_command.set_alliance_id(&alliance.id);
+    logic_join_alliance_command.set_alliance_name(&alliance.name);
+    logic_join_alliance_command
+        .set_alliance_badge_data(LogicDataTables::get_data_by_id(alliance.settings.badge_id));
+    logic_join_alliance_command.set_alliance_create(true);
+
+    let mut available_server_command_message = AvailableServerCommandMessage::new();
+    available_server_command_message.set_server_command(&logic_join_alliance_command.0);
+    session.messaging.send(available_server_command_message.0);
+
+    send_alliance_data(session, db, &alliance.id);
+}
+
+/// Validates settings sent by a client creating or editing an alliance.
+fn read_alliance_settings(
+    description: Option<sc_string::ScString>,
+    badge_data: Option<logic::data::LogicData>,
+    alliance_type: i32,
+    required_score: i32,
+) -> Option<AllianceSettings> {
+    use logic::data::LogicDataTables;
+
+    const MAX_ALLIANCE_DESCRIPTION_LENGTH: usize = 128;
+
+    let description = description.map(|description| description.to_string()).unwrap_or_default();
+    if description.chars().count() > MAX_ALLIANCE_DESCRIPTION_LENGTH {
+        return None;
+    }
+
+    let badge_data = badge_data?;
+    if badge_data.get_table_index() != LogicDataTables::ALLIANCE_BADGE {
+        return None;
+    }
+
+    if !(1..=3).contains(&alliance_type) || required_score < 0 {
+        return None;
+    }
+
+    Some(AllianceSettings {
+        description,
+        badge_id: badge_data.get_global_id(),
+        alliance_type,
+        required_score,
+    })
+}
+
+fn handle_ask_for_alliance_data_message(
+    session: &mut PlayerSession,
+    db: &DatabaseConnection,
+    message: PiranhaMessage,
+) {
+    let message = message::AskForAllianceDataMessage(message);
+    send_alliance_data(session, db, message.get_alliance_id());
+}
+
+fn send_alliance_data(session: &mut PlayerSession, db: &DatabaseConnection, alliance_id: &LogicLong) {
+    use message::{AllianceDataMessage, AllianceFullEntry, AllianceMemberEntry};
+
+    let (alliance_header, members) = match (
+        db.fetch_alliance_header(alliance_id),
+        db.fetch_alliance_members(alliance_id),
+    ) {
+        (Ok(Some(alliance_header)), Ok(members)) => (alliance_header, members),
+        (Ok(None), _) => {
+            warn!("AllianceData: alliance {alliance_id} was not found in the database");
+            return;
+        }
+        (Err(err), _) | (_, Err(err)) => {
+            error!("AllianceData: failed to fetch alliance {alliance_id}: {err}");
+            return;
+        }
+    };
+
+    let member_entries = members
+        .iter()
+        .enumerate()
+        .map(|(i, member)| {
+            let mut entry = AllianceMemberEntry::new();
+            entry.set_avatar_id(&member.id);
+            entry.set_home_id(&member.id);
+            entry.set_name(&member.name);
+            entry.set_role(member.role as i32);
+            entry.set_exp_level(member.exp_level);
+            entry.set_score(member.score);
+            entry.set_order(i as i32 + 1);
+            entry.set_previous_order(i as i32 + 1);
+            entry
+        })
+        .collect();
+
+    let mut full_entry = AllianceFullEntry::new();
+    full_entry.set_alliance_header_entry(create_alliance_header_entry(&alliance_header));
+    full_entry.set_alliance_description(&alliance_header.alliance.settings.description);
+    full_entry.set_alliance_members(member_entries);
+
+    let mut alliance_data_message = AllianceDataMessage::new();
+    alliance_data_message.set_alliance_full_entry(full_entry);
+    session.messaging.send(alliance_data_message.0);
+}
+
+fn create_alliance_header_entry(alliance_header: &AllianceHeaderData) -> message::AllianceHeaderEntry {
+    use logic::data::LogicDataTables;
+    use message::AllianceHeaderEntry;
+
+    let alliance = &alliance_header.alliance;
+
+    let mut entry = AllianceHeaderEntry::new();
+    entry.set_alliance_id(&alliance.id);
+    entry.set_alliance_name(&alliance.name);
+    entry.set_alliance_badge_data(LogicDataTables::get_data_by_id(alliance.settings.badge_id));
+    entry.set_alliance_type(alliance.settings.alliance_type);
+    entry.set_member_count(alliance_header.member_count);
+    entry.set_score(alliance_header.score);
+    entry.set_required_score(alliance.settings.required_score);
+    entry
+}
+
+/// The player's own score, as long as they are at home.
+fn get_home_owner_score(session: &PlayerSession) -> Option<i32> {
+    session
+        .logic_game_mode
+        .as_ref()
+        .filter(|logic_game_mode| logic_game_mode.get_state() == 1)
+        .and_then(|logic_game_mode| {
+            logic_game_mode
+                .get_level()
+                .get_home_owner_avatar::<LogicClientAvatar>()
+        })
+        .map(|logic_client_avatar| logic_client_avatar.get_score())
+}
+
+fn handle_ask_for_joinable_alliances_list_message(
+    session: &mut PlayerSession,
+    db: &DatabaseConnection,
+    _message: PiranhaMessage,
+) {
+    use database::AllianceSearchFilter;
+    use message::JoinableAllianceListMessage;
+
+    const JOINABLE_ALLIANCE_COUNT: usize = 40;
+
+    let Some(score) = get_home_owner_score(session) else {
+        error!("received AskForJoinableAlliancesListMessage outside of home state!");
+        return;
+    };
+
+    let filter = AllianceSearchFilter {
+        joinable_with_score: Some(score),
+        ..Default::default()
+    };
+
+    let alliances = match db.search_alliances(&filter, JOINABLE_ALLIANCE_COUNT) {
+        Ok(alliances) => alliances,
+        Err(err) => {
+            error!("AskForJoinableAlliancesList: failed to fetch alliances: {err}");
+            return;
+        }
+    };
+
+    let mut joinable_alliance_list_message = JoinableAllianceListMessage::new();
+    joinable_alliance_list_message
+        .set_alliances(alliances.iter().map(create_alliance_header_entry).collect());
+    session.messaging.send(joinable_alliance_list_message.0);
+}
+
+fn handle_search_alliances_message(
+    session: &mut PlayerSession,
+    db: &DatabaseConnection,
+    message: PiranhaMessage,
+) {
+    use database::AllianceSearchFilter;
+    use message::{AllianceListMessage, SearchAlliancesMessage};
+
+    const SEARCH_RESULT_COUNT: usize = 64;
+
+    let message = SearchAlliancesMessage(message);
+
+    let search_string = message
+        .get_search_string()
+        .map(|search_string| search_string.to_string().trim().to_string())
+        .unwrap_or_default();
+
+    let joinable_with_score = if message.is_only_joinable() {
+        let Some(score) = get_home_owner_score(session) else {
+            error!("received SearchAlliancesMessage for joinable alliances outside of home state!");
+            return;
+        };
+        Some(score)
+    } else {
+        None
+    };
+
+    // The client sends 0 for filters that aren't set.
+    let filter = AllianceSearchFilter {
+        name: (!search_string.is_empty()).then(|| search_string.clone()),
+        min_score: message.get_minimum_score().max(0),
+        min_member_count: message.get_minimum_member_count().max(0),
+        max_member_count: Some(message.get_maximum_member_count()).filter(|count| *count > 0),
+        joinable_with_score,
+    };
+
+    let alliances = match db.search_alliances(&filter, SEARCH_RESULT_COUNT) {
+        Ok(alliances) => alliances,
+        Err(err) => {
+            error!("SearchAlliances: failed to search alliances: {err}");
+            return;
+        }
+    };
+
+    let mut alliance_list_message = AllianceListMessage::new();
+    alliance_list_message.set_search_string(&search_string);
+    alliance_list_message.set_alliances(alliances.iter().map(create_alliance_header_entry).collect());
+    session.messaging.send(alliance_list_message.0);
+}
+
+fn handle_ask_for_alliance_ranking_list_message(
+    session: &mut PlayerSession,
+    db: &DatabaseConnection,
+    _message: PiranhaMessage,
+) {
+    use database::AllianceSearchFilter;
+    use logic::data::LogicDataTables;
+    use message::{AllianceRankingEntry, AllianceRankingListMessage};
+
+    const RANKING_SIZE: usize = 200;
+
+    let alliances = match db.search_alliances(&AllianceSearchFilter::default(), RANKING_SIZE) {
+        Ok(alliances) => alliances,
+        Err(err) => {
+            error!("AskForAllianceRankingList: failed to fetch alliances: {err}");
+            return;
+        }
+    };
+
+    let entries = alliances
+        .iter()
+        .enumerate()
+        .map(|(i, alliance_header)| {
+            let alliance = &alliance_header.alliance;
+
+            let mut entry = AllianceRankingEntry::new();
+            entry.set_id(&alliance.id);
+            entry.set_name(&alliance.name);
+            entry.set_order(i as i32 + 1);
+            entry.set_previous_order(i as i32 + 1);
+            entry.set_score(alliance_header.score);
+            entry.set_alliance_badge_data(LogicDataTables::get_data_by_id(alliance.settings.badge_id));
+            entry.set_member_count(alliance_header.member_count);
+            entry
+        })
+        .collect();
+
+    let mut alliance_ranking_list_message = AllianceRankingListMessage::new();
+    alliance_ranking_list_message.set_alliance_ranking_list(entries);
+    session.messaging.send(alliance_ranking_list_message.0);
+}
+
+fn handle_join_alliance_message(
+    session: &mut PlayerSession,
+    state: &ServerState,
+    message: PiranhaMessage,
+) {
+    use logic::command::LogicJoinAllianceCommand;
+    use logic::data::LogicDataTables;
+    use message::{AvailableServerCommandMessage, JoinAllianceMessage};
+
+    const OPEN_ALLIANCE_TYPE: i32 = 1;
+
+    let db = &state.db;
+    let message = JoinAllianceMessage(message);
+    let alliance_id = message.get_alliance_id();
+
+    let Some(logic_game_mode) = session.logic_game_mode.as_ref() else {
+        error!("received JoinAllianceMessage while LogicGameMode is NULL!");
+        return;
+    };
+
+    if logic_game_mode.get_state() != 1 {
+        error!("received JoinAllianceMessage outside of home state!");
+        return;
+    }
+
+    let Some(logic_client_avatar) = logic_game_mode
+        .get_level()
+        .get_home_owner_avatar::<LogicClientAvatar>()
+    else {
+        error!("received JoinAllianceMessage while home_owner_avatar is NULL!");
+        return;
+    };
+
+    let Ok(Some(alliance)) = db.fetch_alliance(alliance_id) else {
+        warn!("JoinAlliance: alliance {alliance_id} was not found in the database");
+        return;
+    };
+
+    if alliance.settings.alliance_type != OPEN_ALLIANCE_TYPE {
+        warn!("JoinAlliance: alliance {alliance_id} isn't open");
+        return;
+    }
+
+    if logic_client_avatar.get_score() < alliance.settings.required_score {
+        warn!("JoinAlliance: {} doesn't have the required score", session.account_id);
+        return;
+    }
+
+    match db.join_alliance(&session.account_id, alliance_id) {
+        Ok(true) => (),
+        Ok(false) => {
+            warn!("JoinAlliance: {} is already in an alliance or {alliance_id} is full", session.account_id);
+            return;
+        }
+        Err(err) => {
+            error!("JoinAlliance: failed to join alliance {alliance_id}: {err}");
+            return;
+        }
+    }
+
+    info!("JoinAlliance: {} joined alliance {alliance_id}", session.account_id);
+
+    let mut logic_join_alliance_command = LogicJoinAllianceCommand::new();
+    logic_join_alliance_command.set_alliance_id(&alliance.id);
+    logic_join_alliance_command.set_alliance_name(&alliance.name);
+    logic_join_alliance_command
+        .set_alliance_badge_data(LogicDataTables::get_data_by_id(alliance.settings.badge_id));
+    logic_join_alliance_command.set_alliance_create(false);
+
+    let mut available_server_command_message = AvailableServerCommandMessage::new();
+    available_server_command_message.set_server_command(&logic_join_alliance_command.0);
+    session.messaging.send(available_server_command_message.0);
+
+    send_alliance_data(session, db, &alliance.id);
+    // The history goes out first, the joiner gets their own event live.
+    send_alliance_stream(session, db, &alliance.id);
+
+    if let Some(member) = find_alliance_member(db, &alliance.id, &session.account_id) {
+        post_alliance_stream_entry(
+            state,
+            AllianceStreamEntryData::new(
+                &alliance.id,
+                &member,
+                AllianceStreamEntryKind::Event {
+                    event_type: AllianceEventType::Joined,
+                    event_avatar_id: member.id.clone(),
+                    event_avatar_name: member.name.clone(),
+                },
+            ),
+        );
+    }
+}
+
+fn handle_change_alliance_member_role_message(
+    session: &mut PlayerSession,
+    state: &ServerState,
+    message: PiranhaMessage,
+) {
+    use message::ChangeAllianceMemberRoleMessage;
+
+    let db = &state.db;
+    let message = ChangeAllianceMemberRoleMessage(message);
+    let member_id = message.get_member_id();
+
+    let Some(new_role) = AllianceRole::from_i32(message.get_member_role()) else {
+        warn!("ChangeAllianceMemberRole: invalid role {}", message.get_member_role());
+        return;
+    };
+
+    let Ok(Some((alliance, role))) = db.fetch_player_alliance(&session.account_id) else {
+        warn!("ChangeAllianceMemberRole: {} isn't in an alliance", session.account_id);
+        return;
+    };
+
+    let Ok(members) = db.fetch_alliance_members(&alliance.id) else {
+        error!("ChangeAllianceMemberRole: failed to fetch members of {}", alliance.id);
+        return;
+    };
+
+    let Some(member) = members.iter().find(|member| member.id == *member_id) else {
+        warn!("ChangeAllianceMemberRole: {member_id} isn't in alliance {}", alliance.id);
+        return;
+    };
+
+    // Only the leader can hand over leadership; everyone else can only
+    // promote up to the rank below their own.
+    let can_change = member.id != session.account_id
+        && role.rank() > member.role.rank()
+        && (new_role.rank() < role.rank() || role == AllianceRole::Leader);
+
+    if !can_change {
+        warn!(
+            "ChangeAllianceMemberRole: {} ({role:?}) can't make {member_id} ({:?}) {new_role:?}",
+            session.account_id, member.role
+        );
+        return;
+    }
+
+    if let Err(err) = db.change_alliance_member_role(&alliance.id, member_id, new_role) {
+        error!("ChangeAllianceMemberRole: failed to change role of {member_id}: {err}");
+        return;
+    }
+
+    info!("ChangeAllianceMemberRole: {member_id} is now {new_role:?} in alliance {}", alliance.id);
+
+    send_alliance_data(session, db, &alliance.id);
+
+    let Some(actor) = members.iter().find(|member| member.id == session.account_id) else {
+        return;
+    };
+
+    announce_alliance_role_change(state, &alliance.id, member, new_role, actor);
+
+    // Handing over leadership demotes the leader, who is the actor.
+    if new_role == AllianceRole::Leader {
+        announce_alliance_role_change(state, &alliance.id, actor, AllianceRole::CoLeader, actor);
+    }
+}
+
+/// Tells the member about their new role and posts the promotion or
+/// demotion by `actor` to the alliance stream. `member` still has the old
+/// role.
+fn announce_alliance_role_change(
+    state: &ServerState,
+    alliance_id: &LogicLong,
+    member: &AllianceMemberData,
+    new_role: AllianceRole,
+    actor: &AllianceMemberData,
+) {
+    state.online_players.send(&member.id, || SessionEvent::AllianceRoleChanged {
+        alliance_id: alliance_id.clone(),
+        role: new_role as i32,
+    });
+
+    let event_type = if new_role.rank() > member.role.rank() {
+        AllianceEventType::Promoted
+    } else {
+        AllianceEventType::Demoted
+    };
+
+    post_alliance_stream_entry(
+        state,
+        AllianceStreamEntryData::new(
+            alliance_id,
+            &AllianceMemberData {
+                role: new_role,
+                ..member.clone()
+            },
+            AllianceStreamEntryKind::Event {
+                event_type,
+                event_avatar_id: actor.id.clone(),
+                event_avatar_name: actor.name.clone(),
+            },
+        ),
+    );
+}
+
+fn handle_leave_alliance_message(
+    session: &mut PlayerSession,
+    state: &ServerState,
+    _message: PiranhaMessage,
+) {
+    use logic::command::LogicLeaveAllianceCommand;
+    use message::AvailableServerCommandMessage;
+
+    let db = &state.db;
+
+    // Looked up before leaving, events show the members as they were.
+    let members = match db.fetch_player_alliance(&session.account_id) {
+        Ok(Some((alliance, _))) => db.fetch_alliance_members(&alliance.id).unwrap_or_else(|err| {
+            error!("LeaveAlliance: failed to fetch members of {}: {err}", alliance.id);
+            Vec::new()
+        }),
+        _ => Vec::new(),
+    };
+    let member = members.iter().find(|member| member.id == session.account_id);
+
+    let (alliance_id, new_leader_id) = match db.leave_alliance(&session.account_id) {
+        Ok(Some(left)) => left,
+        Ok(None) => {
+            warn!("LeaveAlliance: {} isn't in an alliance", session.account_id);
+            return;
+        }
+        Err(err) => {
+            error!("LeaveAlliance: failed to leave alliance: {err}");
+            return;
+        }
+    };
+
+    info!("LeaveAlliance: {} left alliance {alliance_id}", session.account_id);
+
+    let mut logic_leave_alliance_command = LogicLeaveAllianceCommand::new();
+    logic_leave_alliance_command.set_alliance_id(&alliance_id);
+
+    let mut available_server_command_message = AvailableServerCommandMessage::new();
+    available_server_command_message.set_server_command(&logic_leave_alliance_command.0);
+    session.messaging.send(available_server_command_message.0);
+
+    if let Some(member) = member {
+        post_alliance_stream_entry(
+            state,
+            AllianceStreamEntryData::new(
+                &alliance_id,
+                member,
+                AllianceStreamEntryKind::Event {
+                    event_type: AllianceEventType::Left,
+                    event_avatar_id: member.id.clone(),
+                    event_avatar_name: member.name.clone(),
+                },
+            ),
+        );
+
+        let new_leader = new_leader_id.and_then(|id| members.iter().find(|member| member.id == id));
+        if let Some(new_leader) = new_leader {
+            announce_alliance_role_change(state, &alliance_id, new_leader, AllianceRole::Leader, member);
+        }
+    }
+}
+
+fn handle_chat_to_alliance_stream_message(
+    session: &mut PlayerSession,
+    state: &ServerState,
+    message: PiranhaMessage,
+) {
+    use message::ChatToAllianceStreamMessage;
+
+    let db = &state.db;
+    let message = ChatToAllianceStreamMessage(message);
+
+    let Some(text) = read_stream_message(message.get_message()).filter(|text| !text.is_empty())
+    else {
+        warn!("ChatToAllianceStream: invalid message from {}", session.account_id);
+        return;
+    };
+
+    if session.is_admin
+        && let Some(command) = admin::AdminCommand::parse(&text)
+    {
+        match command {
+            Ok(command) => execute_admin_command(session, state, command),
+            Err(reason) => warn!("admin command of {} rejected: {reason}", session.account_id),
+        }
+        return;
+    }
+
+    let Ok(Some((alliance, _))) = db.fetch_player_alliance(&session.account_id) else {
+        warn!("ChatToAllianceStream: {} isn't in an alliance", session.account_id);
+        return;
+    };
+
+    let Some(member) = find_alliance_member(db, &alliance.id, &session.account_id) else {
+        return;
+    };
+
+    post_alliance_stream_entry(
+        state,
+        AllianceStreamEntryData::new(
+            &alliance.id,
+            &member,
+            AllianceStreamEntryKind::Chat { message: text },
+        ),
+    );
+}
+
+/// Trims a message a client wants posted to a stream or the global chat.
+/// `None` if it's too long or can't be turned back into a client string.
+fn read_stream_message(text: Option<sc_string::ScString>) -> Option<String> {
+    const MAX_STREAM_MESSAGE_LENGTH: usize = 128;
+
+    let text = text
+        .map(|text| text.to_string().trim().to_string())
+        .unwrap_or_default();
+
+    (text.chars().count() <= MAX_STREAM_MESSAGE_LENGTH && !text.contains('\0')).then_some(text)
+}
+
+fn handle_request_alliance_units_message(
+    session: &mut PlayerSession,
+    state: &ServerState,
+    message: PiranhaMessage,
+) {
+    use message::RequestAllianceUnitsMessage;
+
+    let db = &state.db;
+    let message = RequestAllianceUnitsMessage(message);
+
+    let Some(logic_game_mode) = session.logic_game_mode.as_ref() else {
+        error!("received RequestAllianceUnitsMessage while LogicGameMode is NULL!");
+        return;
+    };
+
+    if logic_game_mode.get_state() != 1 {
+        error!("received RequestAllianceUnitsMessage outside of home state!");
+        return;
+    }
+
+    let Some(logic_client_avatar) = logic_game_mode
+        .get_level()
+        .get_home_owner_avatar::<LogicClientAvatar>()
+    else {
+        error!("received RequestAllianceUnitsMessage while home_owner_avatar is NULL!");
+        return;
+    };
+
+    let Some(text) = read_stream_message(message.get_message()) else {
+        warn!("RequestAllianceUnits: invalid message from {}", session.account_id);
+        return;
+    };
+
+    let total_capacity = logic_client_avatar.get_alliance_castle_total_capacity();
+    let used_capacity = logic_client_avatar.get_alliance_castle_used_capacity();
+    if used_capacity >= total_capacity {
+        warn!("RequestAllianceUnits: clan castle of {} is full or not built", session.account_id);
+        return;
+    }
+
+    let Ok(Some((alliance, _))) = db.fetch_player_alliance(&session.account_id) else {
+        warn!("RequestAllianceUnits: {} isn't in an alliance", session.account_id);
+        return;
+    };
+
+    let Some(member) = find_alliance_member(db, &alliance.id, &session.account_id) else {
+        return;
+    };
+
+    match db.remove_donate_requests(&session.account_id) {
+        Ok(removed_ids) if !removed_ids.is_empty() => {
+            if let Ok(members) = db.fetch_alliance_members(&alliance.id) {
+                for id in removed_ids {
+                    notify_alliance_members(state, &members, || {
+                        SessionEvent::AllianceStreamEntryRemoved(id)
+                    });
+                }
+            }
+        }
+        Ok(_) => (),
+        Err(err) => {
+            error!("RequestAllianceUnits: failed to remove old requests: {err}");
+            return;
+        }
+    }
+
+    post_alliance_stream_entry(
+        state,
+        AllianceStreamEntryData::new(
+            &alliance.id,
+            &member,
+            AllianceStreamEntryKind::DonateRequest {
+                message: text,
+                total_capacity,
+                used_capacity,
+            },
+        ),
+    );
+}
+
+fn handle_donate_alliance_unit_message(
+    session: &mut PlayerSession,
+    state: &ServerState,
+    message: PiranhaMessage,
+) {
+    use logic::command::LogicDonateAllianceUnitCommand;
+    use logic::data::LogicCharacterData;
+    use message::{AvailableServerCommandMessage, DonateAllianceUnitMessage};
+
+    let db = &state.db;
+    let message = DonateAllianceUnitMessage(message);
+    let stream_entry_id = message.get_stream_entry_id();
+
+    let Some(logic_game_mode) = session.logic_game_mode.as_ref() else {
+        error!("received DonateAllianceUnitMessage while LogicGameMode is NULL!");
+        return;
+    };
+
+    if logic_game_mode.get_state() != 1 {
+        error!("received DonateAllianceUnitMessage outside of home state!");
+        return;
+    }
+
+    let Some(logic_client_avatar) = logic_game_mode
+        .get_level()
+        .get_home_owner_avatar::<LogicClientAvatar>()
+    else {
+        error!("received DonateAllianceUnitMessage while home_owner_avatar is NULL!");
+        return;
+    };
+
+    let Some(unit_data) = message.get_unit_data() else {
+        warn!("DonateAllianceUnit: no unit from {}", session.account_id);
+        return;
+    };
+
+    let Some(character_data) = LogicCharacterData::from_data(unit_data) else {
+        warn!("DonateAllianceUnit: {} isn't a character", unit_data.get_global_id());
+        return;
+    };
+
+    if logic_client_avatar.get_unit_count(&unit_data) < 1 {
+        warn!("DonateAllianceUnit: {} has no {} to donate", session.account_id, unit_data.get_global_id());
+        return;
+    }
+
+    let Ok(Some(stream_entry)) = db.fetch_alliance_stream_entry(stream_entry_id.to_long()) else {
+        warn!("DonateAllianceUnit: stream entry {stream_entry_id} was not found in the database");
+        return;
+    };
+
+    if !matches!(stream_entry.kind, AllianceStreamEntryKind::DonateRequest { .. }) {
+        warn!("DonateAllianceUnit: stream entry {stream_entry_id} isn't a request");
+        return;
+    }
+
+    if stream_entry.sender_id == session.account_id {
+        warn!("DonateAllianceUnit: {} donating to themselves", session.account_id);
+        return;
+    }
+
+    let Ok(Some((alliance, _))) = db.fetch_player_alliance(&session.account_id) else {
+        warn!("DonateAllianceUnit: {} isn't in an alliance", session.account_id);
+        return;
+    };
+
+    if alliance.id != stream_entry.alliance_id {
+        warn!("DonateAllianceUnit: stream entry {stream_entry_id} is from another alliance");
+        return;
+    }
+
+    // A defense save would overwrite a unit added to an offline home while
+    // it's attacked, so those get no donations, and an offline recipient is
+    // held like a defender until the unit is saved.
+    let recipient_id = &stream_entry.sender_id;
+    let recipient_hold = state.online_players.try_begin_attack(recipient_id);
+    if recipient_hold.is_none() && !state.online_players.is_online(recipient_id) {
+        warn!("DonateAllianceUnit: home of {recipient_id} is being attacked");
+        return;
+    }
+
+    let housing_space = character_data.get_housing_space();
+    let donation_added = match db.add_donation(stream_entry.id, housing_space) {
+        Ok(true) => true,
+        Ok(false) => {
+            warn!("DonateAllianceUnit: {} doesn't fit into request {stream_entry_id}", unit_data.get_global_id());
+            false
+        }
+        Err(err) => {
+            error!("DonateAllianceUnit: failed to add donation to {stream_entry_id}: {err}");
+            false
+        }
+    };
+
+    if !donation_added {
+        if let Some(attack_id) = recipient_hold {
+            state.online_players.end_attack(recipient_id, attack_id);
+        }
+        return;
+    }
+
+    let sender_name = logic_client_avatar.get_name().unwrap_or_default();
+    let unit_data_id = unit_data.get_global_id();
+    let upgrade_level = logic_client_avatar.get_unit_upgrade_level(&unit_data);
+
+    let mut delivered = recipient_hold.is_none()
+        && state.online_players.send(recipient_id, || SessionEvent::DonatedUnitReceived {
+            sender_name: sender_name.clone(),
+            unit_data_id,
+            upgrade_level,
+        });
+
+    // The recipient may also have logged out since being found online.
+    if !delivered
+        && let Some(attack_id) = recipient_hold.or_else(|| state.online_players.try_begin_attack(recipient_id))
+    {
+        delivered = add_alliance_unit_offline(db, recipient_id, &unit_data, housing_space, upgrade_level);
+        state.online_players.end_attack(recipient_id, attack_id);
+    }
+
+    // The donor only gives up the unit once it has arrived.
+    if !delivered {
+        warn!("DonateAllianceUnit: unit for {recipient_id} can't be delivered, refusing the donation");
+        if let Err(err) = db.remove_donation(stream_entry.id, housing_space) {
+            error!("DonateAllianceUnit: failed to take back donation to {stream_entry_id}: {err}");
+        }
+        return;
+    }
+
+    info!("DonateAllianceUnit: {} donated {unit_data_id} to {recipient_id}", session.account_id);
+
+    let mut logic_donate_alliance_unit_command = LogicDonateAllianceUnitCommand::new();
+    logic_donate_alliance_unit_command.set_unit_data(&unit_data);
+    logic_donate_alliance_unit_command.set_stream_entry_id(stream_entry_id);
+
+    let mut available_server_command_message = AvailableServerCommandMessage::new();
+    available_server_command_message.set_server_command(&logic_donate_alliance_unit_command.0);
+    session.messaging.send(available_server_command_message.0);
+
+    // Everyone sees the request fill up.
+    match (
+        db.fetch_alliance_stream_entry(stream_entry.id),
+        db.fetch_alliance_members(&alliance.id),
+    ) {
+        (Ok(Some(stream_entry)), Ok(members)) => notify_alliance_members(state, &members, || {
+            SessionEvent::AllianceStreamEntry(stream_entry.clone())
+        }),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("DonateAllianceUnit: failed to fetch updated request {stream_entry_id}: {err}")
+        }
+        _ => (),
+    }
+}
+
+/// What [`logic::command::LogicAllianceUnitReceivedCommand`] would do, for a
+/// recipient that isn't online to execute it. Returns `false` if the unit
+/// couldn't be saved.
+fn add_alliance_unit_offline(
+    db: &DatabaseConnection,
+    id: &LogicLong,
+    unit_data: &logic::data::LogicData,
+    housing_space: i32,
+    upgrade_level: i32,
+) -> bool {
+    let Ok(Some(player_data)) = db.fetch_player(id) else {
+        error!("failed to fetch player {id} to add an alliance unit");
+        return false;
+    };
+
+    let Some(mut logic_client_avatar) = player_data.decode_client_avatar() else {
+        error!("failed to decode avatar of player {id}");
+        return false;
+    };
+
+    logic_client_avatar.add_alliance_unit(unit_data, upgrade_level);
+    logic_client_avatar.set_alliance_castle_used_capacity(
+        logic_client_avatar.get_alliance_castle_used_capacity() + housing_space,
+    );
+
+    if let Err(err) = db.save_player_avatar(id, &logic_client_avatar) {
+        error!("failed to save alliance unit of player {id}: {err}");
+        return false;
+    }
+
+    true
+}
+
+fn find_alliance_member(
+    db: &DatabaseConnection,
+    alliance_id: &LogicLong,
+    player_id: &LogicLong,
+) -> Option<AllianceMemberData> {
+    match db.fetch_alliance_members(alliance_id) {
+        Ok(members) => members.into_iter().find(|member| member.id == *player_id),
+        Err(err) => {
+            error!("failed to fetch members of alliance {alliance_id}: {err}");
+            None
+        }
+    }
+}
+
+/// Saves the entry to the alliance's stream and pushes it to every member
+/// currently online, the sender included.
+fn post_alliance_stream_entry(state: &ServerState, mut entry: AllianceStreamEntryData) {
+    let members = match state.db.fetch_alliance_members(&entry.alliance_id) {
+        Ok(members) => members,
+        Err(err) => {
+            error!("failed to fetch members of alliance {}: {err}", entry.alliance_id);
+            return;
+        }
+    };
+
+    // The alliance was deleted along with its last member.
+    if members.is_empty() {
+        return;
+    }
+
+    if let Err(err) = state.db.add_alliance_stream_entry(&mut entry) {
+        error!("failed to save stream entry of alliance {}: {err}", entry.alliance_id);
+        return;
+    }
+
+    notify_alliance_members(state, &members, || SessionEvent::AllianceStreamEntry(entry.clone()));
+}
+
+fn notify_alliance_members(
+    state: &ServerState,
+    members: &[AllianceMemberData],
+    event: impl Fn() -> SessionEvent,
+) {
+    for member in members.iter() {
+        state.online_players.send(&member.id, &event);
+    }
+}
+
+fn send_alliance_stream(session: &mut PlayerSession, db: &DatabaseConnection, alliance_id: &LogicLong) {
+    use message::AllianceStreamMessage;
+
+    let alliance_stream = match db.fetch_alliance_stream(alliance_id) {
+        Ok(alliance_stream) => alliance_stream,
+        Err(err) => {
+            error!("failed to fetch stream of alliance {alliance_id}: {err}");
+            return;
+        }
+    };
+
+    let mut alliance_stream_message = AllianceStreamMessage::new();
+    alliance_stream_message.set_alliance_stream_entries(
+        alliance_stream.iter().map(create_alliance_stream_entry).collect(),
+    );
+
+    session.messaging.send(alliance_stream_message.0);
+}
+
+fn create_alliance_stream_entry(stream_entry: &AllianceStreamEntryData) -> message::AllianceStreamEntry {
+    use message::AllianceStreamEntry;
+
+    let mut entry = match &stream_entry.kind {
+        AllianceStreamEntryKind::Chat { message } => {
+            let mut entry = AllianceStreamEntry::new_chat();
+            entry.set_message(message);
+            entry
+        }
+        AllianceStreamEntryKind::DonateRequest {
+            message,
+            total_capacity,
+            used_capacity,
+        } => {
+            let mut entry = AllianceStreamEntry::new_donate();
+            entry.set_message(message);
+            entry.set_castle_total_capacity(*total_capacity);
+            entry.set_castle_used_capacity(*used_capacity);
+            entry
+        }
+        AllianceStreamEntryKind::Event {
+            event_type,
+            event_avatar_id,
+            event_avatar_name,
+        } => {
+            let mut entry = AllianceStreamEntry::new_event();
+            entry.set_event_type(*event_type as i32);
+            entry.set_event_avatar_id(event_avatar_id);
+            entry.set_event_avatar_name(event_avatar_name);
+            entry
+        }
+    };
+
+    entry.set_id(&LogicLong::from_long(stream_entry.id));
+    entry.set_sender_avatar_id(&stream_entry.sender_id);
+    entry.set_sender_home_id(&stream_entry.sender_id);
+    entry.set_sender_name(&stream_entry.sender_name);
+    entry.set_sender_exp_level(stream_entry.sender_exp_level);
+    entry.set_sender_role(stream_entry.sender_role as i32);
+    entry.set_age_seconds((time_util::get_current_timestamp() - stream_entry.created_at) as i32);
+    entry
+}
+
+fn handle_change_alliance_settings_message(
+    session: &mut PlayerSession,
+    state: &ServerState,
+    message: PiranhaMessage,
+) {
+    use message::ChangeAllianceSettingsMessage;
+
+    let db = &state.db;
+    let message = ChangeAllianceSettingsMessage(message);
+
+    let Ok(Some((alliance, role))) = db.fetch_player_alliance(&session.account_id) else {
+        warn!("ChangeAllianceSettings: {} isn't in an alliance", session.account_id);
+        return;
+    };
+
+    if role.rank() < AllianceRole::CoLeader.rank() {
+        warn!("ChangeAllianceSettings: {} ({role:?}) can't change settings", session.account_id);
+        return;
+    }
+
+    let Some(settings) = read_alliance_settings(
+        message.get_alliance_description(),
+        message.get_alliance_badge_data(),
+        message.get_alliance_type(),
+        message.get_required_score(),
+    ) else {
+        warn!("ChangeAllianceSettings: invalid alliance settings from {}", session.account_id);
+        return;
+    };
+
+    if let Err(err) = db.update_alliance_settings(&alliance.id, &settings) {
+        error!("ChangeAllianceSettings: failed to update alliance {}: {err}", alliance.id);
+        return;
+    }
+
+    send_alliance_data(session, db, &alliance.id);
+
+    // The badge is also shown on each member's avatar.
+    if let Ok(members) = db.fetch_alliance_members(&alliance.id) {
+        notify_alliance_members(state, &members, || SessionEvent::AllianceSettingsChanged {
+            alliance_id: alliance.id.clone(),
+            badge_id: settings.badge_id,
+        });
+    }
+}
+
+fn handle_start_alliance_war_search_message(
+    session: &mut PlayerSession,
+    db: &DatabaseConnection,
+    _message: PiranhaMessage,
+) {
+    let Ok(Some((alliance, role))) = db.fetch_player_alliance(&session.account_id) else {
+        warn!("StartAllianceWarSearch: {} isn't in an alliance", session.account_id);
+        return;
+    };
+
+    if role.rank() < AllianceRole::CoLeader.rank() {
+        warn!("StartAllianceWarSearch: {} can't start wars", session.account_id);
+        return;
+    }
+
+    let member_count = match db.fetch_alliance_members(&alliance.id) {
+        Ok(members) => members.len() as i32,
+        Err(err) => {
+            error!("StartAllianceWarSearch: failed to fetch members of {}: {err}", alliance.id);
+            return;
+        }
+    };
+
+    let Some(war_data) = war::get_war_data(member_count) else {
+        warn!("StartAllianceWarSearch: {} has too few members for a war", alliance.id);
+        return;
+    };
+
+    match db.start_war_search(&alliance.id, war_data.get_global_id(), war_data.get_team_size()) {
+        Ok(true) => info!(
+            "StartAllianceWarSearch: {} searches a {}v{} war",
+            alliance.id,
+            war_data.get_team_size(),
+            war_data.get_team_size()
+        ),
+        Ok(false) => warn!("StartAllianceWarSearch: {} is already searching or at war", alliance.id),
+        Err(err) => error!("StartAllianceWarSearch: failed to start search of {}: {err}", alliance.id),
+    }
+}
+
+fn handle_ask_for_alliance_war_data_message(
+    session: &mut PlayerSession,
+    db: &DatabaseConnection,
+    _message: PiranhaMessage,
+) {
+    use message::AllianceWarDataMessage;
+
+    let Some((war, side)) = find_player_war(db, &session.account_id) else {
+        return;
+    };
+
+    let timestamp = time_util::get_current_timestamp();
+    let seconds_left = match war.state {
+        WarState::Preparation => war.battle_start_timestamp - timestamp,
+        WarState::Battle => war.end_timestamp - timestamp,
+        WarState::Ended => 0,
+    };
+
+    let (Some(own_side), Some(enemy_side)) = (
+        create_alliance_war_side_entry(db, &war, side),
+        create_alliance_war_side_entry(db, &war, 1 - side),
+    ) else {
+        return;
+    };
+
+    let mut alliance_war_data_message = AllianceWarDataMessage::new();
+    alliance_war_data_message.set_war_state(war.state as i32);
+    alliance_war_data_message.set_seconds_left(seconds_left.max(0) as i32);
+    alliance_war_data_message.set_own_side(own_side);
+    alliance_war_data_message.set_enemy_side(enemy_side);
+    session.messaging.send(alliance_war_data_message.0);
+}
+
+fn handle_attack_alliance_war_member_message(
+    session: &mut PlayerSession,
+    db: &DatabaseConnection,
+    message: PiranhaMessage,
+) {
+    use message::AttackAllianceWarMemberMessage;
+
+    let message = AttackAllianceWarMemberMessage(message);
+
+    let Some(logic_game_mode) = session.logic_game_mode.as_ref() else {
+        error!("received AttackAllianceWarMemberMessage while LogicGameMode is NULL!");
+        return;
+    };
+
+    if logic_game_mode.get_state() != 1 {
+        error!("received AttackAllianceWarMemberMessage outside of home state!");
+        return;
+    }
+
+    let Some(attacker_avatar) = logic_game_mode.get_cloned_home_owner::<LogicClientAvatar>() else {
+        error!("received AttackAllianceWarMemberMessage while home_owner_avatar is NULL!");
+        return;
+    };
+
+    let Some((war, side)) = find_player_war(db, &session.account_id) else {
+        return;
+    };
+
+    if war.state != WarState::Battle {
+        warn!("AttackAllianceWarMember: war {} isn't in its battle day", war.id);
+        return;
+    }
+
+    let (Ok(own_members), Ok(enemy_members)) = (
+        db.fetch_war_members(war.id, side),
+        db.fetch_war_members(war.id, 1 - side),
+    ) else {
+        error!("AttackAllianceWarMember: failed to fetch members of war {}", war.id);
+        return;
+    };
+
+    // Only members who were in the alliance when the war started take part.
+    let Some(attacker) = own_members
+        .iter()
+        .find(|member| member.player_id == session.account_id)
+    else {
+        warn!("AttackAllianceWarMember: {} isn't in war {}", session.account_id, war.id);
+        return;
+    };
+
+    let defender_position = message.get_defender_position();
+    let Some(defender) = enemy_members
+        .iter()
+        .find(|member| member.position == defender_position)
+    else {
+        warn!("AttackAllianceWarMember: war {} has no base {defender_position}", war.id);
+        return;
+    };
+
+    let Some(defender_avatar) = defender.decode_client_avatar() else {
+        error!("AttackAllianceWarMember: failed to decode war base {defender_position} of war {}", war.id);
+        return;
+    };
+
+    match db.use_war_attack(war.id, side, attacker.position, war::ATTACKS_PER_MEMBER) {
+        Ok(true) => (),
+        Ok(false) => {
+            warn!("AttackAllianceWarMember: {} has no attacks left", session.account_id);
+            return;
+        }
+        Err(err) => {
+            error!("AttackAllianceWarMember: failed to use attack of {}: {err}", session.account_id);
+            return;
+        }
+    }
+
+    let timestamp = time_util::get_current_timestamp();
+
+    session.battle = Some(Battle {
+        defender_id: None,
+        attack_id: 0,
+        start_timestamp: timestamp,
+        replay: None,
+        war_attack: Some(war::WarAttack {
+            war_id: war.id,
+            side,
+            position: attacker.position,
+            defender_position,
+        }),
+    });
+
+    // War bases stay as they were when the war started.
+    send_enemy_home(session, &defender.home_json, &defender_avatar, &attacker_avatar, timestamp, 0);
+}
+
+/// The war of the player's alliance, and the side the alliance is on.
+fn find_player_war(db: &DatabaseConnection, player_id: &LogicLong) -> Option<(WarData, i32)> {
+    let Ok(Some((alliance, _))) = db.fetch_player_alliance(player_id) else {
+        warn!("{player_id} isn't in an alliance");
+        return None;
+    };
+
+    let war = match db.fetch_alliance_war(&alliance.id) {
+        Ok(Some(war)) => war,
+        Ok(None) => {
+            info!("alliance {} isn't at war", alliance.id);
+            return None;
+        }
+        Err(err) => {
+            error!("failed to fetch war of alliance {}: {err}", alliance.id);
+            return None;
+        }
+    };
+
+    let side = war
+        .sides
+        .iter()
+        .find(|side| side.alliance_id.as_ref() == Some(&alliance.id))?
+        .side;
+
+    Some((war, side))
+}
+
+fn create_alliance_war_side_entry(
+    db: &DatabaseConnection,
+    war: &WarData,
+    side: i32,
+) -> Option<message::AllianceWarSideEntry> {
+    use logic::data::LogicDataTables;
+    use message::{AllianceWarMemberEntry, AllianceWarSideEntry};
+
+    let war_side = war.sides.iter().find(|war_side| war_side.side == side)?;
+
+    let members = match db.fetch_war_members(war.id, side) {
+        Ok(members) => members,
+        Err(err) => {
+            error!("failed to fetch members of war {}: {err}", war.id);
+            return None;
+        }
+    };
+
+    let mut entry = AllianceWarSideEntry::new();
+    if let Some(alliance_id) = &war_side.alliance_id {
+        entry.set_alliance_id(alliance_id);
+    }
+    entry.set_alliance_name(&war_side.name);
+    entry.set_alliance_badge_data(LogicDataTables::get_data_by_id(war_side.badge_id));
+    entry.set_stars(war_side.stars);
+    entry.set_destruction_percentage(war_side.destruction_percentage);
+    entry.set_members(
+        members
+            .iter()
+            .map(|member| {
+                let mut member_entry = AllianceWarMemberEntry::new();
+                member_entry.set_avatar_id(&member.player_id);
+                member_entry.set_name(&member.name);
+                member_entry.set_position(member.position);
+                member_entry.set_exp_level(member.exp_level);
+                member_entry.set_attacks_used(member.attacks_used);
+                member_entry.set_stars(member.best_stars);
+                member_entry.set_destruction_percentage(member.best_destruction_percentage);
+                member_entry.set_score(member.score);
+                member_entry
+            })
+            .collect(),
+    );
+
+    Some(entry)
+}
+
+fn handle_send_global_chat_line_message(
+    session: &mut PlayerSession,
+    state: &ServerState,
+    message: PiranhaMessage,
+) {
+    use message::SendGlobalChatLineMessage;
+
+    let message = SendGlobalChatLineMessage(message);
+
+    let Some(logic_game_mode) = session.logic_game_mode.as_ref() else {
+        error!("received SendGlobalChatLineMessage while LogicGameMode is NULL!");
+        return;
+    };
+
+    if logic_game_mode.get_state() != 1 {
+        error!("received SendGlobalChatLineMessage outside of home state!");
+        return;
+    }
+
+    let Some(logic_client_avatar) = logic_game_mode
+        .get_level()
+        .get_home_owner_avatar::<LogicClientAvatar>()
+    else {
+        error!("received SendGlobalChatLineMessage while home_owner_avatar is NULL!");
+        return;
+    };
+
+    let Some(text) = read_stream_message(message.get_message()).filter(|text| !text.is_empty())
+    else {
+        warn!("SendGlobalChatLine: invalid message from {}", session.account_id);
+        return;
+    };
+
+    if session.is_admin
+        && let Some(command) = admin::AdminCommand::parse(&text)
+    {
+        match command {
+            Ok(command) => execute_admin_command(session, state, command),
+            Err(reason) => warn!("admin command of {} rejected: {reason}", session.account_id),
+        }
+        return;
+    }
+
+    let sender_alliance = match state.db.fetch_player_alliance(&session.account_id) {
+        Ok(alliance) => alliance.map(|(alliance, _)| GlobalChatAlliance {
+            id: alliance.id,
+            name: alliance.name,
+            badge_id: alliance.settings.badge_id,
+        }),
+        Err(err) => {
+            error!("SendGlobalChatLine: failed to fetch alliance of {}: {err}", session.account_id);
+            None
+        }
+    };
+
+    let line = GlobalChatLine {
+        message: text,
+        sender_id: session.account_id.clone(),
+        sender_name: logic_client_avatar.get_name().unwrap_or_default(),
+        sender_exp_level: logic_client_avatar.get_exp_level(),
+        sender_alliance,
+    };
+
+    if !state.global_chat.try_post(&line) {
+        warn!("SendGlobalChatLine: {} is sending too fast", session.account_id);
+        return;
+    }
+
+    state
+        .online_players
+        .send_to_all(|| SessionEvent::GlobalChatLine(line.clone()));
+}
+
+fn create_global_chat_line_message(line: &GlobalChatLine) -> message::GlobalChatLineMessage {
+    use logic::data::LogicDataTables;
+
+    let mut global_chat_line_message = message::GlobalChatLineMessage::new();
+    global_chat_line_message.set_message(&line.message);
+    global_chat_line_message.set_avatar_name(&line.sender_name);
+    global_chat_line_message.set_avatar_exp_level(line.sender_exp_level);
+    global_chat_line_message.set_avatar_id(&line.sender_id);
+    global_chat_line_message.set_home_id(&line.sender_id);
+
+    if let Some(alliance) = &line.sender_alliance {
+        global_chat_line_message.set_alliance_id(&alliance.id);
+        global_chat_line_message.set_alliance_name(&alliance.name);
+        global_chat_line_message
+            .set_alliance_badge_data(LogicDataTables::get_data_by_id(alliance.badge_id));
+    }
+
+    global_chat_line_message
+}
+
+/// Most commands change the player's home and send it again, as the
+/// client can't be told about those changes any other way.
+fn execute_admin_command(session: &mut PlayerSession, state: &ServerState, command: admin::AdminCommand) {
+    use admin::AdminCommand;
+    use logic::command::{LogicDiamondsAddedCommand, LogicResourcesChangedCommand};
+    use logic::data::LogicResourceData;
+
+    let db = &state.db;
+
+    let Some(logic_game_mode) = session.logic_game_mode.as_ref() else {
+        error!("received admin command while LogicGameMode is NULL!");
+        return;
+    };
+
+    if logic_game_mode.get_state() != 1 {
+        warn!("admin command of {} outside of home state", session.account_id);
+        return;
+    }
+
+    let Some(mut logic_client_avatar) = logic_game_mode.get_cloned_home_owner::<LogicClientAvatar>() else {
+        error!("received admin command while home_owner_avatar is NULL!");
+        return;
+    };
+
+    let mut string_builder = StringBuilder::new();
+    let mut home_json_object = LogicJSONNode::new_json_object();
+    logic_game_mode.save_to_json(&mut home_json_object);
+    home_json_object.write_to_string(&mut string_builder);
+
+    let mut home_json = string_builder.to_string();
+    let mut seconds_since_last_save = 0;
+
+    info!("{} executes admin command {command:?}", session.account_id);
+
+    match command {
+        // The client applies these itself, no reload needed.
+        AdminCommand::Gold(amount) => {
+            let mut logic_resources_changed_command = LogicResourcesChangedCommand::new();
+            logic_resources_changed_command.set_resource_data(&LogicResourceData::gold());
+            logic_resources_changed_command.set_resource_count(amount);
+
+            let mut available_server_command_message = message::AvailableServerCommandMessage::new();
+            available_server_command_message.set_server_command(&logic_resources_changed_command.0);
+            session.messaging.send(available_server_command_message.0);
+            return;
+        }
+        AdminCommand::Gems(amount) => {
+            let mut logic_diamonds_added_command = LogicDiamondsAddedCommand::new();
+            logic_diamonds_added_command.set_free_diamonds(true);
+            logic_diamonds_added_command.set_diamonds_count(amount);
+
+            let mut available_server_command_message = message::AvailableServerCommandMessage::new();
+            available_server_command_message.set_server_command(&logic_diamonds_added_command.0);
+            session.messaging.send(available_server_command_message.0);
+            return;
+        }
+        AdminCommand::TownHall(level) => {
+            let Some(changed_home_json) = admin::set_town_hall_level(&home_json, level) else {
+                warn!("home of {} has no town hall", session.account_id);
+                return;
+            };
+            home_json = changed_home_json;
+        }
+        AdminCommand::Skip(seconds) => seconds_since_last_save = seconds,
+        AdminCommand::Log {
+            account_low_id,
+            count,
+        } => {
+            let account_id = LogicLong::new(session.account_id.higher_int, account_low_id);
+            send_command_log(session, db, &account_id, count);
+            return;
+        }
+        AdminCommand::Rotate { account_low_id } => {
+            let account_id = LogicLong::new(session.account_id.higher_int, account_low_id);
+            send_rotated_pass_token(session, state, &account_id);
+            return;
+        }
+        AdminCommand::Reset => {
+            let mut string_builder = StringBuilder::new();
+            ResourceManager::get_json("level/starting_home.json").write_to_string(&mut string_builder);
+            home_json = string_builder.to_string();
+
+            logic_client_avatar = LogicClientAvatar::get_default_avatar();
+            logic_client_avatar.set_id(&session.account_id);
+
+            // Alliance membership is kept.
+            match db.fetch_player_alliance(&session.account_id) {
+                Ok(alliance) => set_avatar_alliance(&mut logic_client_avatar, alliance.as_ref()),
+                Err(err) => error!("failed to fetch alliance of {}: {err}", session.account_id),
+            }
+        }
+    }
+
+    if let Err(err) = db.save_player_data(&session.account_id, &home_json, &logic_client_avatar) {
+        error!("failed to save player data after admin command: {err}");
+        return;
+    }
+
+    send_own_home(session, home_json, &logic_client_avatar, seconds_since_last_save);
+}
+
+/// Shows an admin the account's latest commands as global chat lines only
+/// they receive, oldest first.
+fn send_command_log(session: &mut PlayerSession, db: &DatabaseConnection, account_id: &LogicLong, count: i32) {
+    let command_log = match db.fetch_command_log(account_id, count) {
+        Ok(command_log) => command_log,
+        Err(err) => {
+            error!("failed to fetch command log of {account_id}: {err}");
+            return;
+        }
+    };
+
+    if command_log.is_empty() {
+        info!("no commands logged for {account_id}");
+    }
+
+    let timestamp = time_util::get_current_timestamp();
+
+    for entry in command_log.iter().rev() {
+        let (before, after) = (&entry.resources_before, &entry.resources_after);
+
+        let line = GlobalChatLine {
+            message: format!(
+                "{}s ago, tick {}: {} {} | gold {}>{} elixir {}>{} dark {}>{} gems {}>{} (tick of {} commands)",
+                timestamp - entry.timestamp,
+                entry.execute_sub_tick,
+                entry.command_type,
+                entry.parameters_json,
+                before.gold,
+                after.gold,
+                before.elixir,
+                after.elixir,
+                before.dark_elixir,
+                after.dark_elixir,
+                before.diamonds,
+                after.diamonds,
+                entry.sub_tick_command_count,
+            ),
+            sender_id: entry.player_id.clone(),
+            sender_name: "Command log".to_string(),
+            sender_exp_level: 1,
+            sender_alliance: None,
+        };
+
+        session.messaging.send(create_global_chat_line_message(&line).0);
+    }
+}
+
+/// Gives the new pass token to the admin only, as a global chat line.
+fn send_rotated_pass_token(session: &mut PlayerSession, state: &ServerState, account_id: &LogicLong) {
+    let message = match state.db.rotate_pass_token(account_id) {
+        Ok(Some(pass_token)) => {
+            info!("pass token of {account_id} rotated");
+            // Whoever is logged in with the old token goes too.
+            state.online_players.send(account_id, || SessionEvent::Disconnect);
+            format!("new pass token of {account_id}: {pass_token}")
+        }
+        Ok(None) => format!("no account {account_id}"),
+        Err(err) => {
+            error!("failed to rotate pass token of {account_id}: {err}");
+            return;
+        }
+    };
+
+    let line = GlobalChatLine {
+        message,
+        sender_id: account_id.clone(),
+        sender_name: "Pass token".to_string(),
+        sender_exp_level: 1,
+        sender_alliance: None,
+    };
+
+    session.messaging.send(create_global_chat_line_message(&line).0);
+}
+
+/// Servers can run for a long time, so old commands are also pruned while
+/// they do, forever.
+fn run_command_log_timer(state: &ServerState) {
+    const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+    loop {
+        thread::sleep(PRUNE_INTERVAL);
+        prune_command_log(&state.db, state.command_log_retention_seconds);
+    }
+}
+
+fn prune_command_log(db: &DatabaseConnection, retention_seconds: i64) {
+    match db.prune_command_log(retention_seconds) {
+        Ok(0) => (),
+        Ok(count) => info!("pruned {count} old command log entries"),
+        Err(err) => error!("failed to prune command log: {err}"),
+    }
+}
+
+/// Replaces the client's home with a fresh one loaded from `home_json`.
+fn send_own_home(
+    session: &mut PlayerSession,
+    home_json: String,
+    logic_client_avatar: &LogicClientAvatar,
+    seconds_since_last_save: i32,
+) {
+    use message::OwnHomeDataMessage;
+
+    let mut logic_client_home = LogicClientHome::new();
+    logic_client_home.set_home_json(&home_json);
+
+    let timestamp = time_util::get_current_timestamp();
+
+    let mut logic_game_mode = LogicGameMode::new();
+    logic_game_mode.load_home_state(&logic_client_home, logic_client_avatar, seconds_since_last_save);
+    logic_game_mode.set_current_timestamp(timestamp as i32);
+
+    let mut own_home_data_message = OwnHomeDataMessage::new();
+    own_home_data_message.set_seconds_since_last_save(seconds_since_last_save);
+    own_home_data_message.set_current_timestamp(timestamp as i32);
+    own_home_data_message.set_logic_client_home({
+        let mut logic_client_home = LogicClientHome::new();
+        logic_client_home.set_home_json(&home_json);
+        logic_client_home
+    });
+    own_home_data_message.set_logic_client_avatar(logic_game_mode.get_cloned_home_owner().unwrap());
+
+    session.logic_game_mode = Some(logic_game_mode);
+    session.saved_home_json = Some(home_json);
+    session.messaging.send(own_home_data_message.0);
 }
 
 fn init_tracing() {
@@ -1,6 +1,9 @@
-use crate::{byte_stream::ByteStream, import, malloc, math::LogicLong};
+use crate::{byte_stream::ByteStream, import, malloc, math::LogicLong, sc_string::ScString};
 
-use super::data::LogicNpcData;
+use super::{
+    data::{LogicData, LogicNpcData, LogicResourceData},
+    json::LogicJSONNode,
+};
 
 pub trait LogicAvatar: Sized {
     fn new_from_ptr(ptr: *const u8) -> Self;
@@ -41,6 +44,98 @@ impl LogicClientAvatar {
         unsafe { *(self.0.wrapping_add(108) as *mut *const LogicLong) = id.to_heap() }
     }
 
+    pub fn get_name(&self) -> Option<String> {
+        unsafe {
+            let ptr = *(self.0.wrapping_add(120) as *const *const u8);
+            (!ptr.is_null()).then(|| ScString(ptr).to_string())
+        }
+    }
+
+    pub fn set_alliance_id(&mut self, id: Option<&LogicLong>) {
+        unsafe {
+            *(self.0.wrapping_add(128) as *mut *const LogicLong) =
+                id.map(LogicLong::to_heap).unwrap_or(std::ptr::null())
+        }
+    }
+
+    pub fn set_alliance_name(&mut self, name: &str) {
+        unsafe { *(self.0.wrapping_add(132) as *mut usize) = ScString::from(name).0 as usize }
+    }
+
+    pub fn set_alliance_badge_data(&mut self, data: Option<LogicData>) {
+        unsafe {
+            *(self.0.wrapping_add(136) as *mut *const u8) =
+                data.map(|data| data.0).unwrap_or(std::ptr::null())
+        }
+    }
+
+    pub fn set_alliance_role(&mut self, role: i32) {
+        unsafe { *(self.0.wrapping_add(140) as *mut i32) = role }
+    }
+
+    pub fn get_alliance_castle_total_capacity(&self) -> i32 {
+        unsafe { *(self.0.wrapping_add(152) as *const i32) }
+    }
+
+    pub fn get_alliance_castle_used_capacity(&self) -> i32 {
+        unsafe { *(self.0.wrapping_add(156) as *const i32) }
+    }
+
+    pub fn set_alliance_castle_used_capacity(&mut self, capacity: i32) {
+        unsafe { *(self.0.wrapping_add(156) as *mut i32) = capacity }
+    }
+
+    /// Only adds the unit to the castle; the used capacity is up to the caller.
+    pub fn add_alliance_unit(&mut self, data: &LogicData, upgrade_level: i32) {
+        import!(logic_client_avatar_add_alliance_unit(ptr: *const u8, data: *const u8, count: i32, upgrade_level: i32) -> () = 0x1875A4);
+        logic_client_avatar_add_alliance_unit(self.0, data.0, 1, upgrade_level);
+    }
+
+    pub fn get_unit_count(&self, data: &LogicData) -> i32 {
+        import!(logic_avatar_get_unit_count(ptr: *const u8, data: *const u8) -> i32 = 0x184B3C);
+        logic_avatar_get_unit_count(self.0, data.0)
+    }
+
+    pub fn get_unit_upgrade_level(&self, data: &LogicData) -> i32 {
+        import!(logic_avatar_get_unit_upgrade_level(ptr: *const u8, data: *const u8) -> i32 = 0x184C10);
+        logic_avatar_get_unit_upgrade_level(self.0, data.0)
+    }
+
+    pub fn get_resource_count(&self, data: &LogicResourceData) -> i32 {
+        import!(logic_avatar_get_resource_count(ptr: *const u8, data: *const u8) -> i32 = 0x1844E2);
+        logic_avatar_get_resource_count(self.0, data.0)
+    }
+
+    pub fn set_resource_count(&mut self, data: &LogicResourceData, count: i32) {
+        import!(logic_avatar_set_resource_count(ptr: *const u8, data: *const u8, count: i32) -> () = 0x184536);
+        logic_avatar_set_resource_count(self.0, data.0, count);
+    }
+
+    pub fn get_town_hall_level(&self) -> i32 {
+        unsafe { *(self.0.wrapping_add(64) as *const i32) }
+    }
+
+    pub fn get_exp_level(&self) -> i32 {
+        unsafe { *(self.0.wrapping_add(96) as *const i32) }
+    }
+
+    pub fn get_score(&self) -> i32 {
+        unsafe { *(self.0.wrapping_add(200) as *const i32) }
+    }
+
+    pub fn set_score(&mut self, score: i32) {
+        unsafe { *(self.0.wrapping_add(200) as *mut i32) = score }
+    }
+
+    pub fn get_diamonds(&self) -> i32 {
+        unsafe { *(self.0.wrapping_add(208) as *const i32) }
+    }
+
+    pub fn save_to_replay(&self, json: &mut LogicJSONNode) {
+        import!(logic_client_avatar_save_to_replay(ptr: *const u8, json: *const u8) -> () = 0x1884F0);
+        logic_client_avatar_save_to_replay(self.0, json.0);
+    }
+
     pub fn decode(&mut self, stream: &mut ByteStream) {
         import!(logic_client_avatar_decode(ptr: *const u8, s: *const u8) -> () = 0x188826);
         logic_client_avatar_decode(self.0, stream.0);
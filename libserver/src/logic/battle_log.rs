@@ -0,0 +1,41 @@
+use crate::import;
+
+use super::{data::LogicResourceData, json::LogicJSONNode};
+
+#[repr(transparent)]
+pub struct LogicBattleLog(pub *const u8);
+
+impl LogicBattleLog {
+    pub fn get_stars(&self) -> i32 {
+        import!(logic_battle_log_get_stars(ptr: *const u8) -> i32 = 0x18F2C4);
+        logic_battle_log_get_stars(self.0)
+    }
+
+    pub fn get_destruction_percentage(&self) -> i32 {
+        import!(logic_battle_log_get_destruction_percentage(ptr: *const u8) -> i32 = 0x18F2F0);
+        logic_battle_log_get_destruction_percentage(self.0)
+    }
+
+    pub fn get_stolen_resources(&self, data: &LogicResourceData) -> i32 {
+        import!(logic_battle_log_get_stolen_resources(ptr: *const u8, data: *const u8) -> i32 = 0x18F33A);
+        logic_battle_log_get_stolen_resources(self.0, data.0)
+    }
+
+    pub fn get_attacker_score(&self) -> i32 {
+        unsafe { *(self.0.wrapping_add(60) as *const i32) }
+    }
+
+    pub fn get_defender_score(&self) -> i32 {
+        unsafe { *(self.0.wrapping_add(64) as *const i32) }
+    }
+
+    pub fn generate_attacker_json(&self) -> LogicJSONNode {
+        import!(logic_battle_log_generate_attacker_json(ptr: *const u8) -> *const u8 = 0x18F5A8);
+        LogicJSONNode(logic_battle_log_generate_attacker_json(self.0))
+    }
+
+    pub fn generate_defender_json(&self) -> LogicJSONNode {
+        import!(logic_battle_log_generate_defender_json(ptr: *const u8) -> *const u8 = 0x18F7E4);
+        LogicJSONNode(logic_battle_log_generate_defender_json(self.0))
+    }
+}
@@ -1,4 +1,11 @@
-use crate::{import, malloc, sc_string::ScString};
+use serde::Serialize;
+
+use crate::{import, malloc, math::LogicLong, sc_string::ScString};
+
+use super::{
+    data::{LogicData, LogicResourceData},
+    json::LogicJSONNode,
+};
 
 #[repr(transparent)]
 pub struct LogicCommand(pub *const u8);
@@ -17,6 +24,152 @@ impl LogicCommand {
     pub fn get_execute_sub_tick(&self) -> i32 {
         unsafe { *(self.0.wrapping_add(4) as *const i32) }
     }
+
+    /// Reads the fields of the common client commands.
+    pub fn decode(&self) -> ClientCommand {
+        match self.get_command_type() {
+            500 => ClientCommand::BuyBuilding {
+                x: self.read_i32(12),
+                y: self.read_i32(16),
+                building_data_id: self.read_data_id(20),
+            },
+            501 => ClientCommand::MoveBuilding {
+                x: self.read_i32(12),
+                y: self.read_i32(16),
+                game_object_id: self.read_i32(20),
+            },
+            502 => ClientCommand::UpgradeBuilding {
+                game_object_id: self.read_i32(12),
+            },
+            504 => ClientCommand::SpeedUpConstruction {
+                game_object_id: self.read_i32(12),
+            },
+            506 => ClientCommand::CollectResources {
+                game_object_id: self.read_i32(12),
+            },
+            508 => ClientCommand::TrainUnit {
+                game_object_id: self.read_i32(12),
+                unit_type: self.read_i32(16),
+                unit_data_id: self.read_data_id(20),
+                count: self.read_i32(24),
+            },
+            513 => ClientCommand::SpeedUpTraining {
+                game_object_id: self.read_i32(12),
+            },
+            518 => ClientCommand::BuyResources {
+                resource_data_id: self.read_data_id(12),
+                count: self.read_i32(16),
+            },
+            _ => ClientCommand::Other,
+        }
+    }
+
+    fn read_i32(&self, offset: usize) -> i32 {
+        unsafe { *(self.0.wrapping_add(offset) as *const i32) }
+    }
+
+    fn read_data_id(&self, offset: usize) -> Option<i32> {
+        let data = unsafe { *(self.0.wrapping_add(offset) as *const *const u8) };
+        (!data.is_null()).then(|| LogicData(data).get_global_id())
+    }
+}
+
+/// A client command with its fields. Rows of the CSV tables are given by
+/// global id.
+#[derive(Debug, Serialize)]
+pub enum ClientCommand {
+    BuyBuilding {
+        x: i32,
+        y: i32,
+        building_data_id: Option<i32>,
+    },
+    MoveBuilding {
+        x: i32,
+        y: i32,
+        game_object_id: i32,
+    },
+    UpgradeBuilding {
+        game_object_id: i32,
+    },
+    SpeedUpConstruction {
+        game_object_id: i32,
+    },
+    CollectResources {
+        game_object_id: i32,
+    },
+    TrainUnit {
+        game_object_id: i32,
+        /// 0 for troops, 1 for spells.
+        unit_type: i32,
+        unit_data_id: Option<i32>,
+        count: i32,
+    },
+    SpeedUpTraining {
+        game_object_id: i32,
+    },
+    BuyResources {
+        resource_data_id: Option<i32>,
+        count: i32,
+    },
+    /// Any other command; only its type is known.
+    Other,
+}
+
+impl ClientCommand {
+    /// Village coordinates go up to this, exclusive.
+    const MAP_SIZE: i32 = 50;
+
+    /// Catches values the client would never send. The command is still
+    /// executed either way, since the client already has.
+    pub fn validate(&self) -> Result<(), String> {
+        let in_map = |x: i32, y: i32| (0..Self::MAP_SIZE).contains(&x) && (0..Self::MAP_SIZE).contains(&y);
+
+        match *self {
+            Self::BuyBuilding { x, y, building_data_id } => {
+                if building_data_id.is_none() {
+                    return Err("building data is NULL".to_string());
+                }
+                if !in_map(x, y) {
+                    return Err(format!("building placed outside of the map at ({x}, {y})"));
+                }
+            }
+            Self::MoveBuilding { x, y, game_object_id } => {
+                if game_object_id < 0 {
+                    return Err(format!("invalid game object id {game_object_id}"));
+                }
+                if !in_map(x, y) {
+                    return Err(format!("building moved outside of the map to ({x}, {y})"));
+                }
+            }
+            Self::UpgradeBuilding { game_object_id }
+            | Self::SpeedUpConstruction { game_object_id }
+            | Self::CollectResources { game_object_id }
+            | Self::SpeedUpTraining { game_object_id } => {
+                if game_object_id < 0 {
+                    return Err(format!("invalid game object id {game_object_id}"));
+                }
+            }
+            Self::TrainUnit { game_object_id, unit_type, unit_data_id, count } => {
+                if game_object_id < 0 || unit_data_id.is_none() || !(0..=1).contains(&unit_type) {
+                    return Err("invalid unit or barracks".to_string());
+                }
+                if count <= 0 {
+                    return Err(format!("training {count} units"));
+                }
+            }
+            Self::BuyResources { resource_data_id, count } => {
+                if resource_data_id.is_none() {
+                    return Err("resource data is NULL".to_string());
+                }
+                if count <= 0 {
+                    return Err(format!("buying {count} resources"));
+                }
+            }
+            Self::Other => (),
+        }
+
+        Ok(())
+    }
 }
 
 #[repr(transparent)]
@@ -27,6 +180,21 @@ impl LogicCommandManager {
         import!(logic_command_manager_add_command(ptr: *const u8, command: *const u8) -> () = 0x191888);
         logic_command_manager_add_command(self.0, command.0);
     }
+
+    pub fn save_command_to_json(command: &LogicCommand) -> LogicJSONNode {
+        import!(logic_command_manager_save_command_to_json(json: *const u8, command: *const u8) -> () = 0x191C3A);
+
+        let json = LogicJSONNode::new_json_object();
+        logic_command_manager_save_command_to_json(json.0, command.0);
+        json
+    }
+
+    pub fn load_command_from_json(json: &LogicJSONNode) -> Option<LogicCommand> {
+        import!(logic_command_manager_load_command_from_json(json: *const u8) -> *const u8 = 0x191B10);
+
+        let command = logic_command_manager_load_command_from_json(json.0);
+        (!command.is_null()).then_some(LogicCommand(command))
+    }
 }
 
 pub struct LogicChangeAvatarNameCommand(pub LogicCommand);
@@ -51,3 +219,179 @@ impl LogicChangeAvatarNameCommand {
         }
     }
 }
+
+pub struct LogicJoinAllianceCommand(pub LogicCommand);
+
+impl LogicJoinAllianceCommand {
+    pub fn new() -> Self {
+        import!(logic_join_alliance_command_ctor(ptr: *const u8) -> () = 0x1E6A30);
+        let instance = malloc(32);
+        logic_join_alliance_command_ctor(instance);
+        Self(LogicCommand(instance))
+    }
+
+    pub fn set_alliance_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0 .0.wrapping_add(12) as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_alliance_name(&mut self, name: &str) {
+        unsafe {
+            *(self.0 .0.wrapping_add(16) as *mut usize) = ScString::from(name).0 as usize;
+        }
+    }
+
+    pub fn set_alliance_badge_data(&mut self, data: Option<LogicData>) {
+        unsafe {
+            *(self.0 .0.wrapping_add(20) as *mut *const u8) =
+                data.map(|data| data.0).unwrap_or(std::ptr::null());
+        }
+    }
+
+    pub fn set_alliance_create(&mut self, value: bool) {
+        unsafe {
+            *(self.0 .0.wrapping_add(24) as *mut bool) = value;
+        }
+    }
+}
+
+pub struct LogicLeaveAllianceCommand(pub LogicCommand);
+
+impl LogicLeaveAllianceCommand {
+    pub fn new() -> Self {
+        import!(logic_leave_alliance_command_ctor(ptr: *const u8) -> () = 0x1E6C94);
+        let instance = malloc(24);
+        logic_leave_alliance_command_ctor(instance);
+        Self(LogicCommand(instance))
+    }
+
+    pub fn set_alliance_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0 .0.wrapping_add(12) as *mut *const LogicLong) = id.to_heap() }
+    }
+}
+
+/// Takes a donated unit out of the donor's army.
+pub struct LogicDonateAllianceUnitCommand(pub LogicCommand);
+
+impl LogicDonateAllianceUnitCommand {
+    pub fn new() -> Self {
+        import!(logic_donate_alliance_unit_command_ctor(ptr: *const u8) -> () = 0x1E6118);
+        let instance = malloc(24);
+        logic_donate_alliance_unit_command_ctor(instance);
+        Self(LogicCommand(instance))
+    }
+
+    pub fn set_unit_data(&mut self, data: &LogicData) {
+        unsafe { *(self.0 .0.wrapping_add(12) as *mut *const u8) = data.0 }
+    }
+
+    pub fn set_stream_entry_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0 .0.wrapping_add(16) as *mut *const LogicLong) = id.to_heap() }
+    }
+}
+
+/// Puts a donated unit into the recipient's clan castle.
+pub struct LogicAllianceUnitReceivedCommand(pub LogicCommand);
+
+impl LogicAllianceUnitReceivedCommand {
+    pub fn new() -> Self {
+        import!(logic_alliance_unit_received_command_ctor(ptr: *const u8) -> () = 0x1E5A1C);
+        let instance = malloc(24);
+        logic_alliance_unit_received_command_ctor(instance);
+        Self(LogicCommand(instance))
+    }
+
+    pub fn set_sender_name(&mut self, name: &str) {
+        unsafe {
+            *(self.0 .0.wrapping_add(12) as *mut usize) = ScString::from(name).0 as usize;
+        }
+    }
+
+    pub fn set_unit_data(&mut self, data: &LogicData) {
+        unsafe { *(self.0 .0.wrapping_add(16) as *mut *const u8) = data.0 }
+    }
+
+    pub fn set_upgrade_level(&mut self, upgrade_level: i32) {
+        unsafe { *(self.0 .0.wrapping_add(20) as *mut i32) = upgrade_level }
+    }
+}
+
+/// Credits diamonds, bought or free.
+pub struct LogicDiamondsAddedCommand(pub LogicCommand);
+
+impl LogicDiamondsAddedCommand {
+    pub fn new() -> Self {
+        import!(logic_diamonds_added_command_ctor(ptr: *const u8) -> () = 0x1E5E44);
+        let instance = malloc(32);
+        logic_diamonds_added_command_ctor(instance);
+        Self(LogicCommand(instance))
+    }
+
+    pub fn set_free_diamonds(&mut self, value: bool) {
+        unsafe { *(self.0 .0.wrapping_add(12) as *mut bool) = value }
+    }
+
+    pub fn set_diamonds_count(&mut self, count: i32) {
+        unsafe { *(self.0 .0.wrapping_add(16) as *mut i32) = count }
+    }
+}
+
+/// Adds (or with a negative count, takes) an amount of one resource.
+pub struct LogicResourcesChangedCommand(pub LogicCommand);
+
+impl LogicResourcesChangedCommand {
+    pub fn new() -> Self {
+        import!(logic_resources_changed_command_ctor(ptr: *const u8) -> () = 0x1E5F5C);
+        let instance = malloc(24);
+        logic_resources_changed_command_ctor(instance);
+        Self(LogicCommand(instance))
+    }
+
+    pub fn set_resource_data(&mut self, data: &LogicResourceData) {
+        unsafe { *(self.0 .0.wrapping_add(12) as *mut *const u8) = data.0 }
+    }
+
+    pub fn set_resource_count(&mut self, count: i32) {
+        unsafe { *(self.0 .0.wrapping_add(16) as *mut i32) = count }
+    }
+}
+
+pub struct LogicChangeAllianceRoleCommand(pub LogicCommand);
+
+impl LogicChangeAllianceRoleCommand {
+    pub fn new() -> Self {
+        import!(logic_change_alliance_role_command_ctor(ptr: *const u8) -> () = 0x1E5C0C);
+        let instance = malloc(24);
+        logic_change_alliance_role_command_ctor(instance);
+        Self(LogicCommand(instance))
+    }
+
+    pub fn set_alliance_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0 .0.wrapping_add(12) as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_alliance_role(&mut self, role: i32) {
+        unsafe { *(self.0 .0.wrapping_add(16) as *mut i32) = role }
+    }
+}
+
+pub struct LogicAllianceSettingsChangedCommand(pub LogicCommand);
+
+impl LogicAllianceSettingsChangedCommand {
+    pub fn new() -> Self {
+        import!(logic_alliance_settings_changed_command_ctor(ptr: *const u8) -> () = 0x1E5B02);
+        let instance = malloc(24);
+        logic_alliance_settings_changed_command_ctor(instance);
+        Self(LogicCommand(instance))
+    }
+
+    pub fn set_alliance_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0 .0.wrapping_add(12) as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_alliance_badge_data(&mut self, data: Option<LogicData>) {
+        unsafe {
+            *(self.0 .0.wrapping_add(16) as *mut *const u8) =
+                data.map(|data| data.0).unwrap_or(std::ptr::null());
+        }
+    }
+}
@@ -0,0 +1,15 @@
+use super::table::{LogicData, LogicDataTables};
+
+/// A row of `logic/characters.csv`.
+pub struct LogicCharacterData(pub *const u8);
+
+impl LogicCharacterData {
+    /// `None` if `data` belongs to another table.
+    pub fn from_data(data: LogicData) -> Option<Self> {
+        (data.get_table_index() == LogicDataTables::CHARACTER).then_some(Self(data.0))
+    }
+
+    pub fn get_housing_space(&self) -> i32 {
+        unsafe { *(self.0.wrapping_add(112) as *const i32) }
+    }
+}
@@ -1,3 +1,13 @@
+mod character;
 mod npc;
+mod resource;
+mod shield;
+mod table;
+mod war;
 
+pub use character::LogicCharacterData;
 pub use npc::LogicNpcData;
+pub use resource::LogicResourceData;
+pub use shield::LogicShieldData;
+pub use table::{LogicData, LogicDataTables};
+pub use war::LogicWarData;
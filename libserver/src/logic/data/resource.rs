@@ -0,0 +1,20 @@
+use crate::import;
+
+pub struct LogicResourceData(pub *const u8);
+
+impl LogicResourceData {
+    pub fn gold() -> Self {
+        import!(logic_data_tables_get_gold_data() -> *const u8 = 0x1AD200);
+        Self(logic_data_tables_get_gold_data())
+    }
+
+    pub fn elixir() -> Self {
+        import!(logic_data_tables_get_elixir_data() -> *const u8 = 0x1AD224);
+        Self(logic_data_tables_get_elixir_data())
+    }
+
+    pub fn dark_elixir() -> Self {
+        import!(logic_data_tables_get_dark_elixir_data() -> *const u8 = 0x1AD248);
+        Self(logic_data_tables_get_dark_elixir_data())
+    }
+}
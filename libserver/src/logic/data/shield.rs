@@ -0,0 +1,21 @@
+use super::table::LogicDataTables;
+
+pub struct LogicShieldData(pub *const u8);
+
+impl LogicShieldData {
+    /// Every row of `logic/shields.csv`, in file order.
+    pub fn get_all() -> Vec<Self> {
+        let table = LogicDataTables::get_table(LogicDataTables::SHIELD);
+        (0..table.get_item_count())
+            .map(|index| Self(table.get_item_at(index)))
+            .collect()
+    }
+
+    pub fn get_time_hours(&self) -> i32 {
+        unsafe { *(self.0.wrapping_add(48) as *const i32) }
+    }
+
+    pub fn get_guard_time_hours(&self) -> i32 {
+        unsafe { *(self.0.wrapping_add(52) as *const i32) }
+    }
+}
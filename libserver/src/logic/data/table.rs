@@ -0,0 +1,51 @@
+use crate::import;
+
+pub struct LogicDataTables;
+
+impl LogicDataTables {
+    pub const CHARACTER: i32 = 3;
+    pub const ALLIANCE_BADGE: i32 = 12;
+    pub const SHIELD: i32 = 19;
+    pub const WAR: i32 = 30;
+
+    pub fn get_table(index: i32) -> LogicDataTable {
+        import!(logic_data_tables_get_table(index: i32) -> *const u8 = 0x1AD0F4);
+        LogicDataTable(logic_data_tables_get_table(index))
+    }
+
+    pub fn get_data_by_id(global_id: i32) -> Option<LogicData> {
+        import!(logic_data_tables_get_data_by_id(global_id: i32) -> *const u8 = 0x1AD0B8);
+
+        let data = logic_data_tables_get_data_by_id(global_id);
+        (!data.is_null()).then_some(LogicData(data))
+    }
+}
+
+pub struct LogicDataTable(pub *const u8);
+
+impl LogicDataTable {
+    pub fn get_item_count(&self) -> i32 {
+        import!(logic_data_table_get_item_count(ptr: *const u8) -> i32 = 0x1AB9C2);
+        logic_data_table_get_item_count(self.0)
+    }
+
+    pub fn get_item_at(&self, index: i32) -> *const u8 {
+        import!(logic_data_table_get_item_at(ptr: *const u8, index: i32) -> *const u8 = 0x1AB9D6);
+        logic_data_table_get_item_at(self.0, index)
+    }
+}
+
+/// Any row of any CSV table.
+#[derive(Clone, Copy)]
+pub struct LogicData(pub *const u8);
+
+impl LogicData {
+    pub fn get_global_id(&self) -> i32 {
+        unsafe { *(self.0.wrapping_add(8) as *const i32) }
+    }
+
+    /// Global ids are `(table index + 1) * 1_000_000 + row index`.
+    pub fn get_table_index(&self) -> i32 {
+        self.get_global_id() / 1_000_000 - 1
+    }
+}
@@ -0,0 +1,30 @@
+use super::table::{LogicData, LogicDataTables};
+
+/// A row of `logic/war.csv`: one war size with its timings.
+pub struct LogicWarData(pub *const u8);
+
+impl LogicWarData {
+    /// Every row of `logic/war.csv`, in file order.
+    pub fn get_all() -> Vec<Self> {
+        let table = LogicDataTables::get_table(LogicDataTables::WAR);
+        (0..table.get_item_count())
+            .map(|index| Self(table.get_item_at(index)))
+            .collect()
+    }
+
+    pub fn get_global_id(&self) -> i32 {
+        LogicData(self.0).get_global_id()
+    }
+
+    pub fn get_team_size(&self) -> i32 {
+        unsafe { *(self.0.wrapping_add(48) as *const i32) }
+    }
+
+    pub fn get_preparation_minutes(&self) -> i32 {
+        unsafe { *(self.0.wrapping_add(52) as *const i32) }
+    }
+
+    pub fn get_war_minutes(&self) -> i32 {
+        unsafe { *(self.0.wrapping_add(56) as *const i32) }
+    }
+}
@@ -17,4 +17,12 @@ impl LogicClientHome {
             *(self.0.wrapping_add(36) as *mut usize) = ScString::from(home_json).0 as usize;
         }
     }
+
+    pub fn set_shield_duration_seconds(&mut self, value: i32) {
+        unsafe { *(self.0.wrapping_add(40) as *mut i32) = value }
+    }
+
+    pub fn set_guard_duration_seconds(&mut self, value: i32) {
+        unsafe { *(self.0.wrapping_add(44) as *mut i32) = value }
+    }
 }
@@ -1,4 +1,7 @@
-use crate::{import, malloc, sc_string::StringBuilder};
+use crate::{
+    import, malloc,
+    sc_string::{ScString, StringBuilder},
+};
 
 #[repr(transparent)]
 pub struct LogicJSONNode(pub *const u8);
@@ -20,3 +23,14 @@ impl LogicJSONNode {
         write_to_string(self.0, string_builder.0);
     }
 }
+
+pub struct LogicJSONParser;
+
+impl LogicJSONParser {
+    pub fn parse(json: &str) -> Option<LogicJSONNode> {
+        import!(logic_json_parser_parse(json: *const u8) -> *const u8 = 0x26B1A0);
+
+        let node = logic_json_parser_parse(ScString::from(json).0);
+        (!node.is_null()).then_some(LogicJSONNode(node))
+    }
+}
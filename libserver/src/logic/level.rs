@@ -1,6 +1,6 @@
 use crate::import;
 
-use super::{avatar::LogicAvatar, json::LogicJSONNode, time::LogicTime};
+use super::{avatar::LogicAvatar, battle_log::LogicBattleLog, json::LogicJSONNode, time::LogicTime};
 
 #[repr(transparent)]
 pub struct LogicLevel(pub *const u8);
@@ -24,6 +24,13 @@ impl LogicLevel {
         }
     }
 
+    pub fn get_battle_log(&self) -> Option<LogicBattleLog> {
+        unsafe {
+            let ptr = *(self.0.wrapping_add(96) as *const *const u8);
+            (!ptr.is_null()).then_some(LogicBattleLog(ptr))
+        }
+    }
+
     pub fn save_to_json(&self, json: &mut LogicJSONNode) {
         import!(logic_level_save_to_json(ptr: *const u8, json: *const u8) -> () = 0x1D8C56);
         logic_level_save_to_json(self.0, json.0);
@@ -1,4 +1,5 @@
 pub mod avatar;
+pub mod battle_log;
 pub mod command;
 pub mod data;
 pub mod home;
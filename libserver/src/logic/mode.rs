@@ -57,6 +57,42 @@ impl LogicGameMode {
         );
     }
 
+    pub fn load_matched_attack_state(
+        &mut self,
+        logic_client_home: &LogicClientHome,
+        defender_avatar: &LogicClientAvatar,
+        attacker_avatar: &LogicClientAvatar,
+        seconds_since_last_save: i32,
+    ) {
+        import!(logic_game_mode_load_matched_attack_state(lgm: *const u8, lch: *const u8, defender: *const u8, attacker: *const u8, ssls: i32) -> () = 0x1DCF64);
+
+        logic_game_mode_load_matched_attack_state(
+            self.0,
+            logic_client_home.0,
+            defender_avatar.0,
+            attacker_avatar.0,
+            seconds_since_last_save,
+        );
+    }
+
+    pub fn load_visit_state(
+        &mut self,
+        logic_client_home: &LogicClientHome,
+        owner_avatar: &LogicClientAvatar,
+        visitor_avatar: &LogicClientAvatar,
+        seconds_since_last_save: i32,
+    ) {
+        import!(logic_game_mode_load_visit_state(lgm: *const u8, lch: *const u8, owner: *const u8, visitor: *const u8, ssls: i32) -> () = 0x1DD5B2);
+
+        logic_game_mode_load_visit_state(
+            self.0,
+            logic_client_home.0,
+            owner_avatar.0,
+            visitor_avatar.0,
+            seconds_since_last_save,
+        );
+    }
+
     pub fn get_cloned_home_owner<T: LogicAvatar>(&self) -> Option<T> {
         let avatar = self.get_level().get_home_owner_avatar::<T>()?;
         let mut stream = ByteStream::new(10);
@@ -110,6 +146,11 @@ impl LogicGameMode {
         unsafe { LogicLevel(*(self.0.wrapping_add(16) as *const *const u8)) }
     }
 
+    pub fn is_battle_over(&self) -> bool {
+        import!(logic_game_mode_is_battle_over(ptr: *const u8) -> bool = 0x1DD9C8);
+        logic_game_mode_is_battle_over(self.0)
+    }
+
     pub fn get_state(&self) -> i32 {
         unsafe { *(self.0 as *const i32) }
     }
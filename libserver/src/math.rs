@@ -17,6 +17,14 @@ impl LogicLong {
         }
     }
 
+    pub fn from_long(value: i64) -> Self {
+        Self::new((value >> 32) as i32, value as i32)
+    }
+
+    pub fn to_long(&self) -> i64 {
+        ((self.higher_int as i64) << 32) | (self.lower_int as u32 as i64)
+    }
+
     pub fn to_heap(&self) -> *const LogicLong {
         let ll = malloc(8);
         unsafe {
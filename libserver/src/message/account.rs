@@ -110,3 +110,28 @@ impl KeepAliveServerMessage {
         Self(PiranhaMessage(instance))
     }
 }
+
+pub struct LoginFailedMessage(pub PiranhaMessage);
+
+impl LoginFailedMessage {
+    /// The client shows the reason and a countdown, then reconnects.
+    pub const ERROR_CODE_SERVER_MAINTENANCE: i32 = 10;
+    /// The client shows the reason and doesn't retry on its own.
+    pub const ERROR_CODE_ACCOUNT_LOCKED: i32 = 13;
+
+    pub fn new() -> Self {
+        Self(PiranhaMessage::new(20103))
+    }
+
+    pub fn set_error_code(&mut self, error_code: i32) {
+        unsafe { *(self.0 .0.wrapping_add(48) as *mut i32) = error_code }
+    }
+
+    pub fn set_reason(&mut self, reason: &str) {
+        unsafe { *(self.0 .0.wrapping_add(68) as *mut usize) = ScString::from(reason).0 as usize }
+    }
+
+    pub fn set_seconds_until_maintenance_end(&mut self, seconds: i32) {
+        unsafe { *(self.0 .0.wrapping_add(72) as *mut i32) = seconds }
+    }
+}
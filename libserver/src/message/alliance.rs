@@ -0,0 +1,282 @@
+use crate::{
+    array_list::LogicArrayList, import, logic::data::LogicData, malloc, math::LogicLong,
+    network::PiranhaMessage, sc_string::ScString,
+};
+
+pub struct CreateAllianceMessage(pub PiranhaMessage);
+
+impl CreateAllianceMessage {
+    pub fn get_alliance_name(&self) -> Option<ScString> {
+        unsafe {
+            let strptr = *(self.0 .0.wrapping_add(48) as *const ScString);
+            (!strptr.0.is_null()).then_some(strptr)
+        }
+    }
+
+    pub fn get_alliance_description(&self) -> Option<ScString> {
+        unsafe {
+            let strptr = *(self.0 .0.wrapping_add(52) as *const ScString);
+            (!strptr.0.is_null()).then_some(strptr)
+        }
+    }
+
+    pub fn get_alliance_badge_data(&self) -> Option<LogicData> {
+        unsafe {
+            let ptr = *(self.0 .0.wrapping_add(56) as *const *const u8);
+            (!ptr.is_null()).then_some(LogicData(ptr))
+        }
+    }
+
+    pub fn get_alliance_type(&self) -> i32 {
+        unsafe { *(self.0 .0.wrapping_add(60) as *const i32) }
+    }
+
+    pub fn get_required_score(&self) -> i32 {
+        unsafe { *(self.0 .0.wrapping_add(64) as *const i32) }
+    }
+}
+
+pub struct ChangeAllianceSettingsMessage(pub PiranhaMessage);
+
+impl ChangeAllianceSettingsMessage {
+    pub fn get_alliance_description(&self) -> Option<ScString> {
+        unsafe {
+            let strptr = *(self.0 .0.wrapping_add(48) as *const ScString);
+            (!strptr.0.is_null()).then_some(strptr)
+        }
+    }
+
+    pub fn get_alliance_badge_data(&self) -> Option<LogicData> {
+        unsafe {
+            let ptr = *(self.0 .0.wrapping_add(52) as *const *const u8);
+            (!ptr.is_null()).then_some(LogicData(ptr))
+        }
+    }
+
+    pub fn get_alliance_type(&self) -> i32 {
+        unsafe { *(self.0 .0.wrapping_add(56) as *const i32) }
+    }
+
+    pub fn get_required_score(&self) -> i32 {
+        unsafe { *(self.0 .0.wrapping_add(60) as *const i32) }
+    }
+}
+
+pub struct AskForAllianceDataMessage(pub PiranhaMessage);
+
+impl AskForAllianceDataMessage {
+    pub fn get_alliance_id(&self) -> &LogicLong {
+        unsafe { &**(self.0 .0.wrapping_add(48) as *const *const LogicLong) }
+    }
+}
+
+pub struct JoinAllianceMessage(pub PiranhaMessage);
+
+impl JoinAllianceMessage {
+    pub fn get_alliance_id(&self) -> &LogicLong {
+        unsafe { &**(self.0 .0.wrapping_add(48) as *const *const LogicLong) }
+    }
+}
+
+pub struct ChangeAllianceMemberRoleMessage(pub PiranhaMessage);
+
+impl ChangeAllianceMemberRoleMessage {
+    pub fn get_member_id(&self) -> &LogicLong {
+        unsafe { &**(self.0 .0.wrapping_add(48) as *const *const LogicLong) }
+    }
+
+    pub fn get_member_role(&self) -> i32 {
+        unsafe { *(self.0 .0.wrapping_add(52) as *const i32) }
+    }
+}
+
+pub struct SearchAlliancesMessage(pub PiranhaMessage);
+
+impl SearchAlliancesMessage {
+    pub fn get_search_string(&self) -> Option<ScString> {
+        unsafe {
+            let strptr = *(self.0 .0.wrapping_add(48) as *const ScString);
+            (!strptr.0.is_null()).then_some(strptr)
+        }
+    }
+
+    pub fn get_minimum_member_count(&self) -> i32 {
+        unsafe { *(self.0 .0.wrapping_add(52) as *const i32) }
+    }
+
+    pub fn get_maximum_member_count(&self) -> i32 {
+        unsafe { *(self.0 .0.wrapping_add(56) as *const i32) }
+    }
+
+    pub fn get_minimum_score(&self) -> i32 {
+        unsafe { *(self.0 .0.wrapping_add(60) as *const i32) }
+    }
+
+    pub fn is_only_joinable(&self) -> bool {
+        unsafe { *(self.0 .0.wrapping_add(64) as *const bool) }
+    }
+}
+
+pub struct AllianceListMessage(pub PiranhaMessage);
+
+impl AllianceListMessage {
+    pub fn new() -> Self {
+        Self(PiranhaMessage::new(24310))
+    }
+
+    pub fn set_search_string(&mut self, search_string: &str) {
+        unsafe {
+            *(self.0 .0.wrapping_add(48) as *mut usize) = ScString::from(search_string).0 as usize
+        }
+    }
+
+    pub fn set_alliances(&mut self, alliances: Vec<AllianceHeaderEntry>) {
+        unsafe {
+            *(self.0 .0.wrapping_add(52) as *mut usize) =
+                LogicArrayList::new_on_heap(alliances) as usize;
+        }
+    }
+}
+
+pub struct JoinableAllianceListMessage(pub PiranhaMessage);
+
+impl JoinableAllianceListMessage {
+    pub fn new() -> Self {
+        Self(PiranhaMessage::new(24304))
+    }
+
+    pub fn set_alliances(&mut self, alliances: Vec<AllianceHeaderEntry>) {
+        unsafe {
+            *(self.0 .0.wrapping_add(48) as *mut usize) =
+                LogicArrayList::new_on_heap(alliances) as usize;
+        }
+    }
+}
+
+pub struct AllianceDataMessage(pub PiranhaMessage);
+
+impl AllianceDataMessage {
+    pub fn new() -> Self {
+        Self(PiranhaMessage::new(24301))
+    }
+
+    pub fn set_alliance_full_entry(&mut self, entry: AllianceFullEntry) {
+        unsafe { *(self.0 .0.wrapping_add(48) as *mut *const u8) = entry.0 }
+    }
+}
+
+#[repr(transparent)]
+pub struct AllianceFullEntry(pub *const u8);
+
+impl AllianceFullEntry {
+    pub fn new() -> Self {
+        import!(alliance_full_entry_ctor(ptr: *const u8) -> () = 0x20E1C4);
+
+        let instance = malloc(16);
+        alliance_full_entry_ctor(instance);
+        Self(instance)
+    }
+
+    pub fn set_alliance_header_entry(&mut self, entry: AllianceHeaderEntry) {
+        unsafe { *(self.0 as *mut *const u8) = entry.0 }
+    }
+
+    pub fn set_alliance_description(&mut self, description: &str) {
+        unsafe { *(self.0.wrapping_add(4) as *mut usize) = ScString::from(description).0 as usize }
+    }
+
+    pub fn set_alliance_members(&mut self, members: Vec<AllianceMemberEntry>) {
+        unsafe {
+            *(self.0.wrapping_add(8) as *mut usize) = LogicArrayList::new_on_heap(members) as usize;
+        }
+    }
+}
+
+#[repr(transparent)]
+pub struct AllianceHeaderEntry(pub *const u8);
+
+impl AllianceHeaderEntry {
+    pub fn new() -> Self {
+        import!(alliance_header_entry_ctor(ptr: *const u8) -> () = 0x20E3F0);
+
+        let instance = malloc(40);
+        alliance_header_entry_ctor(instance);
+        Self(instance)
+    }
+
+    pub fn set_alliance_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0 as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_alliance_name(&mut self, name: &str) {
+        unsafe { *(self.0.wrapping_add(4) as *mut usize) = ScString::from(name).0 as usize }
+    }
+
+    pub fn set_alliance_badge_data(&mut self, data: Option<LogicData>) {
+        unsafe {
+            *(self.0.wrapping_add(8) as *mut *const u8) =
+                data.map(|data| data.0).unwrap_or(std::ptr::null())
+        }
+    }
+
+    pub fn set_alliance_type(&mut self, alliance_type: i32) {
+        unsafe { *(self.0.wrapping_add(12) as *mut i32) = alliance_type }
+    }
+
+    pub fn set_member_count(&mut self, member_count: i32) {
+        unsafe { *(self.0.wrapping_add(16) as *mut i32) = member_count }
+    }
+
+    pub fn set_score(&mut self, score: i32) {
+        unsafe { *(self.0.wrapping_add(20) as *mut i32) = score }
+    }
+
+    pub fn set_required_score(&mut self, required_score: i32) {
+        unsafe { *(self.0.wrapping_add(24) as *mut i32) = required_score }
+    }
+}
+
+#[repr(transparent)]
+pub struct AllianceMemberEntry(pub *const u8);
+
+impl AllianceMemberEntry {
+    pub fn new() -> Self {
+        import!(alliance_member_entry_ctor(ptr: *const u8) -> () = 0x20E61A);
+
+        let instance = malloc(48);
+        alliance_member_entry_ctor(instance);
+        Self(instance)
+    }
+
+    pub fn set_avatar_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0 as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_home_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0.wrapping_add(4) as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        unsafe { *(self.0.wrapping_add(8) as *mut usize) = ScString::from(name).0 as usize }
+    }
+
+    pub fn set_role(&mut self, role: i32) {
+        unsafe { *(self.0.wrapping_add(12) as *mut i32) = role }
+    }
+
+    pub fn set_exp_level(&mut self, exp_level: i32) {
+        unsafe { *(self.0.wrapping_add(16) as *mut i32) = exp_level }
+    }
+
+    pub fn set_score(&mut self, score: i32) {
+        unsafe { *(self.0.wrapping_add(20) as *mut i32) = score }
+    }
+
+    pub fn set_order(&mut self, order: i32) {
+        unsafe { *(self.0.wrapping_add(24) as *mut i32) = order }
+    }
+
+    pub fn set_previous_order(&mut self, order: i32) {
+        unsafe { *(self.0.wrapping_add(28) as *mut i32) = order }
+    }
+}
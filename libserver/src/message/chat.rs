@@ -0,0 +1,57 @@
+use crate::{
+    logic::data::LogicData, math::LogicLong, network::PiranhaMessage, sc_string::ScString,
+};
+
+pub struct SendGlobalChatLineMessage(pub PiranhaMessage);
+
+impl SendGlobalChatLineMessage {
+    pub fn get_message(&self) -> Option<ScString> {
+        unsafe {
+            let strptr = *(self.0 .0.wrapping_add(48) as *const ScString);
+            (!strptr.0.is_null()).then_some(strptr)
+        }
+    }
+}
+
+pub struct GlobalChatLineMessage(pub PiranhaMessage);
+
+impl GlobalChatLineMessage {
+    pub fn new() -> Self {
+        Self(PiranhaMessage::new(24715))
+    }
+
+    pub fn set_message(&mut self, message: &str) {
+        unsafe { *(self.0 .0.wrapping_add(48) as *mut usize) = ScString::from(message).0 as usize }
+    }
+
+    pub fn set_avatar_name(&mut self, name: &str) {
+        unsafe { *(self.0 .0.wrapping_add(52) as *mut usize) = ScString::from(name).0 as usize }
+    }
+
+    pub fn set_avatar_exp_level(&mut self, exp_level: i32) {
+        unsafe { *(self.0 .0.wrapping_add(56) as *mut i32) = exp_level }
+    }
+
+    pub fn set_avatar_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0 .0.wrapping_add(64) as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_home_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0 .0.wrapping_add(68) as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_alliance_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0 .0.wrapping_add(72) as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_alliance_name(&mut self, name: &str) {
+        unsafe { *(self.0 .0.wrapping_add(76) as *mut usize) = ScString::from(name).0 as usize }
+    }
+
+    pub fn set_alliance_badge_data(&mut self, data: Option<LogicData>) {
+        unsafe {
+            *(self.0 .0.wrapping_add(80) as *mut *const u8) =
+                data.map(|data| data.0).unwrap_or(std::ptr::null())
+        }
+    }
+}
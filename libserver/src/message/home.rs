@@ -8,6 +8,7 @@ use crate::{
         home::LogicClientHome,
     },
     malloc,
+    math::LogicLong,
     network::PiranhaMessage,
     sc_string::ScString,
 };
@@ -138,3 +139,71 @@ impl NpcDataMessage {
         }
     }
 }
+
+pub struct VisitHomeMessage(pub PiranhaMessage);
+
+impl VisitHomeMessage {
+    pub fn get_home_id(&self) -> &LogicLong {
+        unsafe { &**(self.0 .0.wrapping_add(48) as *const *const LogicLong) }
+    }
+}
+
+pub struct VisitedHomeDataMessage(pub PiranhaMessage);
+
+impl VisitedHomeDataMessage {
+    pub fn new() -> Self {
+        Self(PiranhaMessage::new(24113))
+    }
+
+    pub fn set_current_timestamp(&mut self, value: i32) {
+        unsafe { *(self.0 .0.wrapping_add(52) as *mut i32) = value }
+    }
+
+    pub fn set_logic_client_home(&mut self, logic_client_home: LogicClientHome) {
+        unsafe {
+            *(self.0 .0.wrapping_add(56) as *mut usize) = logic_client_home.0 as usize;
+        }
+    }
+
+    pub fn set_owner_avatar(&mut self, logic_client_avatar: LogicClientAvatar) {
+        unsafe {
+            *(self.0 .0.wrapping_add(60) as *mut usize) = logic_client_avatar.0 as usize;
+        }
+    }
+
+    pub fn set_visitor_avatar(&mut self, logic_client_avatar: LogicClientAvatar) {
+        unsafe {
+            *(self.0 .0.wrapping_add(64) as *mut usize) = logic_client_avatar.0 as usize;
+        }
+    }
+}
+
+pub struct EnemyHomeDataMessage(pub PiranhaMessage);
+
+impl EnemyHomeDataMessage {
+    pub fn new() -> Self {
+        Self(PiranhaMessage::new(24107))
+    }
+
+    pub fn set_current_timestamp(&mut self, value: i32) {
+        unsafe { *(self.0 .0.wrapping_add(52) as *mut i32) = value }
+    }
+
+    pub fn set_logic_client_home(&mut self, logic_client_home: LogicClientHome) {
+        unsafe {
+            *(self.0 .0.wrapping_add(56) as *mut usize) = logic_client_home.0 as usize;
+        }
+    }
+
+    pub fn set_enemy_avatar(&mut self, logic_client_avatar: LogicClientAvatar) {
+        unsafe {
+            *(self.0 .0.wrapping_add(60) as *mut usize) = logic_client_avatar.0 as usize;
+        }
+    }
+
+    pub fn set_attacker_avatar(&mut self, logic_client_avatar: LogicClientAvatar) {
+        unsafe {
+            *(self.0 .0.wrapping_add(64) as *mut usize) = logic_client_avatar.0 as usize;
+        }
+    }
+}
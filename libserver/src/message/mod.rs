@@ -1,7 +1,19 @@
 mod account;
+mod alliance;
+mod chat;
 mod avatar;
 mod home;
+mod ranking;
+mod replay;
+mod stream;
+mod war;
 
 pub use account::*;
+pub use alliance::*;
+pub use chat::*;
 pub use avatar::*;
 pub use home::*;
+pub use ranking::*;
+pub use replay::*;
+pub use stream::*;
+pub use war::*;
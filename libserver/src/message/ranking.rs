@@ -0,0 +1,120 @@
+use crate::{
+    array_list::LogicArrayList, import, logic::data::LogicData, malloc, math::LogicLong,
+    network::PiranhaMessage, sc_string::ScString,
+};
+
+pub struct AvatarRankingListMessage(pub PiranhaMessage);
+
+impl AvatarRankingListMessage {
+    pub fn new() -> Self {
+        Self(PiranhaMessage::new(24403))
+    }
+
+    pub fn set_avatar_ranking_list(&mut self, entries: Vec<AvatarRankingEntry>) {
+        unsafe {
+            *(self.0 .0.wrapping_add(48) as *mut usize) =
+                LogicArrayList::new_on_heap(entries) as usize;
+        }
+    }
+}
+
+#[repr(transparent)]
+pub struct AvatarRankingEntry(pub *const u8);
+
+impl AvatarRankingEntry {
+    pub fn new() -> Self {
+        import!(avatar_ranking_entry_ctor(ptr: *const u8) -> () = 0x20F3D8);
+
+        let instance = malloc(64);
+        avatar_ranking_entry_ctor(instance);
+        Self(instance)
+    }
+
+    pub fn set_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0.wrapping_add(4) as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        unsafe { *(self.0.wrapping_add(8) as *mut usize) = ScString::from(name).0 as usize }
+    }
+
+    pub fn set_order(&mut self, order: i32) {
+        unsafe { *(self.0.wrapping_add(12) as *mut i32) = order }
+    }
+
+    pub fn set_score(&mut self, score: i32) {
+        unsafe { *(self.0.wrapping_add(16) as *mut i32) = score }
+    }
+
+    pub fn set_previous_order(&mut self, order: i32) {
+        unsafe { *(self.0.wrapping_add(20) as *mut i32) = order }
+    }
+
+    pub fn set_exp_level(&mut self, exp_level: i32) {
+        unsafe { *(self.0.wrapping_add(24) as *mut i32) = exp_level }
+    }
+
+    pub fn set_home_id(&mut self, home_id: &LogicLong) {
+        unsafe { *(self.0.wrapping_add(48) as *mut *const LogicLong) = home_id.to_heap() }
+    }
+}
+
+pub struct AllianceRankingListMessage(pub PiranhaMessage);
+
+impl AllianceRankingListMessage {
+    pub fn new() -> Self {
+        Self(PiranhaMessage::new(24401))
+    }
+
+    pub fn set_alliance_ranking_list(&mut self, entries: Vec<AllianceRankingEntry>) {
+        unsafe {
+            *(self.0 .0.wrapping_add(48) as *mut usize) =
+                LogicArrayList::new_on_heap(entries) as usize;
+        }
+    }
+}
+
+/// Shares the ranking entry base with [`AvatarRankingEntry`].
+#[repr(transparent)]
+pub struct AllianceRankingEntry(pub *const u8);
+
+impl AllianceRankingEntry {
+    pub fn new() -> Self {
+        import!(alliance_ranking_entry_ctor(ptr: *const u8) -> () = 0x20F1A0);
+
+        let instance = malloc(64);
+        alliance_ranking_entry_ctor(instance);
+        Self(instance)
+    }
+
+    pub fn set_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0.wrapping_add(4) as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        unsafe { *(self.0.wrapping_add(8) as *mut usize) = ScString::from(name).0 as usize }
+    }
+
+    pub fn set_order(&mut self, order: i32) {
+        unsafe { *(self.0.wrapping_add(12) as *mut i32) = order }
+    }
+
+    pub fn set_score(&mut self, score: i32) {
+        unsafe { *(self.0.wrapping_add(16) as *mut i32) = score }
+    }
+
+    pub fn set_previous_order(&mut self, order: i32) {
+        unsafe { *(self.0.wrapping_add(20) as *mut i32) = order }
+    }
+
+    pub fn set_alliance_badge_data(&mut self, data: Option<LogicData>) {
+        unsafe {
+            *(self.0.wrapping_add(24) as *mut *const u8) =
+                data.map(|data| data.0).unwrap_or(std::ptr::null())
+        }
+    }
+
+    pub fn set_member_count(&mut self, member_count: i32) {
+        unsafe { *(self.0.wrapping_add(28) as *mut i32) = member_count }
+    }
+}
@@ -0,0 +1,28 @@
+use crate::{malloc, math::LogicLong, network::PiranhaMessage};
+
+pub struct HomeBattleReplayMessage(pub PiranhaMessage);
+
+impl HomeBattleReplayMessage {
+    pub fn get_replay_id(&self) -> &LogicLong {
+        unsafe { &**(self.0 .0.wrapping_add(48) as *const *const LogicLong) }
+    }
+}
+
+pub struct HomeBattleReplayDataMessage(pub PiranhaMessage);
+
+impl HomeBattleReplayDataMessage {
+    pub fn new() -> Self {
+        Self(PiranhaMessage::new(24114))
+    }
+
+    /// `replay_data` is already zlib compressed.
+    pub fn set_replay_data(&mut self, replay_data: &[u8]) {
+        unsafe {
+            let replay_data_ptr = malloc(replay_data.len());
+            std::slice::from_raw_parts_mut(replay_data_ptr as *mut u8, replay_data.len())
+                .copy_from_slice(replay_data);
+            *(self.0 .0.wrapping_add(48) as *mut *const u8) = replay_data_ptr;
+            *(self.0 .0.wrapping_add(52) as *mut i32) = replay_data.len() as i32;
+        }
+    }
+}
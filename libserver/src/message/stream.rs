@@ -0,0 +1,249 @@
+use crate::{
+    array_list::LogicArrayList, import, logic::data::LogicData, malloc, math::LogicLong,
+    network::PiranhaMessage, sc_string::ScString,
+};
+
+pub struct AvatarStreamMessage(pub PiranhaMessage);
+
+impl AvatarStreamMessage {
+    pub fn new() -> Self {
+        Self(PiranhaMessage::new(24411))
+    }
+
+    pub fn set_avatar_stream_entries(&mut self, entries: Vec<BattleLogStreamEntry>) {
+        unsafe {
+            *(self.0 .0.wrapping_add(48) as *mut usize) =
+                LogicArrayList::new_on_heap(entries) as usize;
+        }
+    }
+}
+
+pub struct AvatarStreamEntryMessage(pub PiranhaMessage);
+
+impl AvatarStreamEntryMessage {
+    pub fn new() -> Self {
+        Self(PiranhaMessage::new(24412))
+    }
+
+    pub fn set_avatar_stream_entry(&mut self, entry: BattleLogStreamEntry) {
+        unsafe { *(self.0 .0.wrapping_add(48) as *mut *const u8) = entry.0 }
+    }
+}
+
+pub struct RevengeAttackMessage(pub PiranhaMessage);
+
+impl RevengeAttackMessage {
+    pub fn get_stream_entry_id(&self) -> &LogicLong {
+        unsafe { &**(self.0 .0.wrapping_add(48) as *const *const LogicLong) }
+    }
+}
+
+/// Attacker and defender battle log entries only differ in their vtable,
+/// so both are driven through the same setters.
+#[repr(transparent)]
+pub struct BattleLogStreamEntry(pub *const u8);
+
+impl BattleLogStreamEntry {
+    pub fn new_attacker() -> Self {
+        import!(attacker_battle_log_stream_entry_ctor(ptr: *const u8) -> () = 0x2100F2);
+
+        let instance = malloc(64);
+        attacker_battle_log_stream_entry_ctor(instance);
+        Self(instance)
+    }
+
+    pub fn new_defender() -> Self {
+        import!(defender_battle_log_stream_entry_ctor(ptr: *const u8) -> () = 0x21049A);
+
+        let instance = malloc(64);
+        defender_battle_log_stream_entry_ctor(instance);
+        Self(instance)
+    }
+
+    pub fn set_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0.wrapping_add(4) as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_sender_avatar_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0.wrapping_add(8) as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_sender_name(&mut self, name: &str) {
+        unsafe { *(self.0.wrapping_add(16) as *mut usize) = ScString::from(name).0 as usize }
+    }
+
+    pub fn set_sender_exp_level(&mut self, exp_level: i32) {
+        unsafe { *(self.0.wrapping_add(20) as *mut i32) = exp_level }
+    }
+
+    pub fn set_age_seconds(&mut self, age_seconds: i32) {
+        unsafe { *(self.0.wrapping_add(28) as *mut i32) = age_seconds }
+    }
+
+    pub fn set_battle_log_json(&mut self, json: &str) {
+        unsafe { *(self.0.wrapping_add(40) as *mut usize) = ScString::from(json).0 as usize }
+    }
+
+    pub fn set_home_id(&mut self, home_id: &LogicLong) {
+        unsafe { *(self.0.wrapping_add(44) as *mut *const LogicLong) = home_id.to_heap() }
+    }
+}
+
+pub struct ChatToAllianceStreamMessage(pub PiranhaMessage);
+
+impl ChatToAllianceStreamMessage {
+    pub fn get_message(&self) -> Option<ScString> {
+        unsafe {
+            let strptr = *(self.0 .0.wrapping_add(48) as *const ScString);
+            (!strptr.0.is_null()).then_some(strptr)
+        }
+    }
+}
+
+pub struct RequestAllianceUnitsMessage(pub PiranhaMessage);
+
+impl RequestAllianceUnitsMessage {
+    pub fn get_message(&self) -> Option<ScString> {
+        unsafe {
+            let strptr = *(self.0 .0.wrapping_add(48) as *const ScString);
+            (!strptr.0.is_null()).then_some(strptr)
+        }
+    }
+}
+
+pub struct DonateAllianceUnitMessage(pub PiranhaMessage);
+
+impl DonateAllianceUnitMessage {
+    pub fn get_unit_data(&self) -> Option<LogicData> {
+        unsafe {
+            let ptr = *(self.0 .0.wrapping_add(48) as *const *const u8);
+            (!ptr.is_null()).then_some(LogicData(ptr))
+        }
+    }
+
+    pub fn get_stream_entry_id(&self) -> &LogicLong {
+        unsafe { &**(self.0 .0.wrapping_add(52) as *const *const LogicLong) }
+    }
+}
+
+pub struct AllianceStreamMessage(pub PiranhaMessage);
+
+impl AllianceStreamMessage {
+    pub fn new() -> Self {
+        Self(PiranhaMessage::new(24311))
+    }
+
+    pub fn set_alliance_stream_entries(&mut self, entries: Vec<AllianceStreamEntry>) {
+        unsafe {
+            *(self.0 .0.wrapping_add(48) as *mut usize) =
+                LogicArrayList::new_on_heap(entries) as usize;
+        }
+    }
+}
+
+pub struct AllianceStreamEntryMessage(pub PiranhaMessage);
+
+impl AllianceStreamEntryMessage {
+    pub fn new() -> Self {
+        Self(PiranhaMessage::new(24312))
+    }
+
+    pub fn set_alliance_stream_entry(&mut self, entry: AllianceStreamEntry) {
+        unsafe { *(self.0 .0.wrapping_add(48) as *mut *const u8) = entry.0 }
+    }
+}
+
+pub struct AllianceStreamEntryRemovedMessage(pub PiranhaMessage);
+
+impl AllianceStreamEntryRemovedMessage {
+    pub fn new() -> Self {
+        Self(PiranhaMessage::new(24318))
+    }
+
+    pub fn set_stream_entry_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0 .0.wrapping_add(48) as *mut *const LogicLong) = id.to_heap() }
+    }
+}
+
+/// Chat, donate and event entries share the stream entry header; each kind only
+/// has the setters for its own fields used on it.
+#[repr(transparent)]
+pub struct AllianceStreamEntry(pub *const u8);
+
+impl AllianceStreamEntry {
+    pub fn new_chat() -> Self {
+        import!(chat_stream_entry_ctor(ptr: *const u8) -> () = 0x20F8A4);
+
+        let instance = malloc(48);
+        chat_stream_entry_ctor(instance);
+        Self(instance)
+    }
+
+    pub fn new_donate() -> Self {
+        import!(donate_stream_entry_ctor(ptr: *const u8) -> () = 0x20F5E0);
+
+        let instance = malloc(56);
+        donate_stream_entry_ctor(instance);
+        Self(instance)
+    }
+
+    pub fn new_event() -> Self {
+        import!(alliance_event_stream_entry_ctor(ptr: *const u8) -> () = 0x20FB16);
+
+        let instance = malloc(56);
+        alliance_event_stream_entry_ctor(instance);
+        Self(instance)
+    }
+
+    pub fn set_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0.wrapping_add(4) as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_sender_avatar_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0.wrapping_add(8) as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_sender_home_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0.wrapping_add(12) as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_sender_name(&mut self, name: &str) {
+        unsafe { *(self.0.wrapping_add(16) as *mut usize) = ScString::from(name).0 as usize }
+    }
+
+    pub fn set_sender_exp_level(&mut self, exp_level: i32) {
+        unsafe { *(self.0.wrapping_add(20) as *mut i32) = exp_level }
+    }
+
+    pub fn set_sender_role(&mut self, role: i32) {
+        unsafe { *(self.0.wrapping_add(24) as *mut i32) = role }
+    }
+
+    pub fn set_age_seconds(&mut self, age_seconds: i32) {
+        unsafe { *(self.0.wrapping_add(28) as *mut i32) = age_seconds }
+    }
+
+    pub fn set_message(&mut self, message: &str) {
+        unsafe { *(self.0.wrapping_add(40) as *mut usize) = ScString::from(message).0 as usize }
+    }
+
+    pub fn set_castle_total_capacity(&mut self, capacity: i32) {
+        unsafe { *(self.0.wrapping_add(44) as *mut i32) = capacity }
+    }
+
+    pub fn set_castle_used_capacity(&mut self, capacity: i32) {
+        unsafe { *(self.0.wrapping_add(48) as *mut i32) = capacity }
+    }
+
+    pub fn set_event_type(&mut self, event_type: i32) {
+        unsafe { *(self.0.wrapping_add(40) as *mut i32) = event_type }
+    }
+
+    pub fn set_event_avatar_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0.wrapping_add(44) as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_event_avatar_name(&mut self, name: &str) {
+        unsafe { *(self.0.wrapping_add(48) as *mut usize) = ScString::from(name).0 as usize }
+    }
+}
@@ -0,0 +1,125 @@
+use crate::{
+    array_list::LogicArrayList, import, logic::data::LogicData, malloc, math::LogicLong,
+    network::PiranhaMessage, sc_string::ScString,
+};
+
+pub struct AttackAllianceWarMemberMessage(pub PiranhaMessage);
+
+impl AttackAllianceWarMemberMessage {
+    /// Position of the enemy base in the war map, starting at 1.
+    pub fn get_defender_position(&self) -> i32 {
+        unsafe { *(self.0 .0.wrapping_add(48) as *const i32) }
+    }
+}
+
+pub struct AllianceWarDataMessage(pub PiranhaMessage);
+
+impl AllianceWarDataMessage {
+    pub fn new() -> Self {
+        Self(PiranhaMessage::new(24330))
+    }
+
+    pub fn set_war_state(&mut self, state: i32) {
+        unsafe { *(self.0 .0.wrapping_add(48) as *mut i32) = state }
+    }
+
+    pub fn set_seconds_left(&mut self, seconds: i32) {
+        unsafe { *(self.0 .0.wrapping_add(52) as *mut i32) = seconds }
+    }
+
+    pub fn set_own_side(&mut self, entry: AllianceWarSideEntry) {
+        unsafe { *(self.0 .0.wrapping_add(56) as *mut *const u8) = entry.0 }
+    }
+
+    pub fn set_enemy_side(&mut self, entry: AllianceWarSideEntry) {
+        unsafe { *(self.0 .0.wrapping_add(60) as *mut *const u8) = entry.0 }
+    }
+}
+
+#[repr(transparent)]
+pub struct AllianceWarSideEntry(pub *const u8);
+
+impl AllianceWarSideEntry {
+    pub fn new() -> Self {
+        import!(alliance_war_side_entry_ctor(ptr: *const u8) -> () = 0x20E6A0);
+
+        let instance = malloc(32);
+        alliance_war_side_entry_ctor(instance);
+        Self(instance)
+    }
+
+    pub fn set_alliance_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0 as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_alliance_name(&mut self, name: &str) {
+        unsafe { *(self.0.wrapping_add(4) as *mut usize) = ScString::from(name).0 as usize }
+    }
+
+    pub fn set_alliance_badge_data(&mut self, data: Option<LogicData>) {
+        unsafe {
+            *(self.0.wrapping_add(8) as *mut *const u8) =
+                data.map(|data| data.0).unwrap_or(std::ptr::null())
+        }
+    }
+
+    pub fn set_stars(&mut self, stars: i32) {
+        unsafe { *(self.0.wrapping_add(12) as *mut i32) = stars }
+    }
+
+    pub fn set_destruction_percentage(&mut self, percentage: i32) {
+        unsafe { *(self.0.wrapping_add(16) as *mut i32) = percentage }
+    }
+
+    pub fn set_members(&mut self, members: Vec<AllianceWarMemberEntry>) {
+        unsafe {
+            *(self.0.wrapping_add(20) as *mut usize) = LogicArrayList::new_on_heap(members) as usize;
+        }
+    }
+}
+
+#[repr(transparent)]
+pub struct AllianceWarMemberEntry(pub *const u8);
+
+impl AllianceWarMemberEntry {
+    pub fn new() -> Self {
+        import!(alliance_war_member_entry_ctor(ptr: *const u8) -> () = 0x20E8B4);
+
+        let instance = malloc(40);
+        alliance_war_member_entry_ctor(instance);
+        Self(instance)
+    }
+
+    pub fn set_avatar_id(&mut self, id: &LogicLong) {
+        unsafe { *(self.0 as *mut *const LogicLong) = id.to_heap() }
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        unsafe { *(self.0.wrapping_add(4) as *mut usize) = ScString::from(name).0 as usize }
+    }
+
+    pub fn set_position(&mut self, position: i32) {
+        unsafe { *(self.0.wrapping_add(8) as *mut i32) = position }
+    }
+
+    pub fn set_exp_level(&mut self, exp_level: i32) {
+        unsafe { *(self.0.wrapping_add(12) as *mut i32) = exp_level }
+    }
+
+    pub fn set_attacks_used(&mut self, attacks: i32) {
+        unsafe { *(self.0.wrapping_add(16) as *mut i32) = attacks }
+    }
+
+    /// Best stars scored against this base.
+    pub fn set_stars(&mut self, stars: i32) {
+        unsafe { *(self.0.wrapping_add(20) as *mut i32) = stars }
+    }
+
+    pub fn set_destruction_percentage(&mut self, percentage: i32) {
+        unsafe { *(self.0.wrapping_add(24) as *mut i32) = percentage }
+    }
+
+    pub fn set_score(&mut self, score: i32) {
+        unsafe { *(self.0.wrapping_add(28) as *mut i32) = score }
+    }
+}
@@ -1,7 +1,12 @@
+use std::{sync::LazyLock, time::Duration};
+
 use tracing::info;
 
 use crate::{import, malloc, sc_string::ScString};
 
+pub static MESSAGE_FACTORY: LazyLock<LogicMagicMessageFactory> =
+    LazyLock::new(LogicMagicMessageFactory::new);
+
 pub struct Messaging(*const u8);
 
 #[repr(C)]
@@ -36,6 +41,18 @@ impl Messaging {
         unsafe { *(self.0.wrapping_add(4) as *mut usize) = factory as usize }
     }
 
+    /// Blocks until the connection has something to read (or was closed) or
+    /// `timeout` elapses, so the session can do other work in between.
+    pub fn wait_for_data(&mut self, timeout: Duration) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd: self.get_connection().fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as i32) > 0 }
+    }
+
     pub fn on_receive(&mut self) {
         import!(messaging_on_receive(ptr: *const u8, connection: *mut Connection) -> () = 0x225CE6);
         unsafe { messaging_on_receive(self.0, std::mem::transmute(self.0.wrapping_add(64))) }
@@ -63,6 +80,17 @@ impl Messaging {
         unsafe { messaging_on_wakeup(self.0, std::mem::transmute(self.0.wrapping_add(64))) }
     }
 
+    /// Drops the client from the server's side. The session ends once its
+    /// loop sees the connection is gone.
+    pub fn disconnect(&mut self) {
+        let connection = self.get_connection();
+        unsafe {
+            libc::shutdown(connection.fd, libc::SHUT_RDWR);
+            libc::close(connection.fd);
+        }
+        connection.is_connected = false;
+    }
+
     pub fn get_connection(&mut self) -> &mut Connection {
         unsafe { std::mem::transmute(self.0.wrapping_add(64)) }
     }
@@ -77,6 +105,12 @@ impl Messaging {
 pub struct PiranhaMessage(pub *const u8);
 
 impl PiranhaMessage {
+    pub fn new(message_type: u16) -> Self {
+        MESSAGE_FACTORY
+            .create_message_by_type(message_type)
+            .unwrap_or_else(|| panic!("message factory can't create message {message_type}"))
+    }
+
     pub fn get_message_type(&self) -> u16 {
         unsafe {
             let fn_ptr = ((*(self.0 as *const usize)) + 20) as *const usize;
@@ -103,6 +137,18 @@ impl LogicMagicMessageFactory {
 
         instance
     }
+
+    pub fn create_message_by_type(&self, message_type: u16) -> Option<PiranhaMessage> {
+        // vtable: two destructor slots, then createMessageByType
+        let create_message_by_type = unsafe {
+            std::mem::transmute::<usize, extern "C" fn(*const Self, i32) -> *const u8>(
+                *((self.vtable + 8) as *const usize),
+            )
+        };
+
+        let message = create_message_by_type(self, message_type as i32);
+        (!message.is_null()).then_some(PiranhaMessage(message))
+    }
 }
 
 #[repr(transparent)]
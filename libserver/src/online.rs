@@ -0,0 +1,215 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        mpsc::{self, Receiver, Sender},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{database::AllianceStreamEntryData, global_chat::GlobalChatLine, math::LogicLong};
+
+/// Something another session wants delivered to a player. Sessions own
+/// their `Messaging`, so they only exchange plain data and each session
+/// builds the libg messages itself.
+pub enum SessionEvent {
+    /// A new entry, or an updated one with an id the client already has.
+    AllianceStreamEntry(AllianceStreamEntryData),
+    AllianceStreamEntryRemoved(i64),
+    DonatedUnitReceived {
+        sender_name: String,
+        unit_data_id: i32,
+        upgrade_level: i32,
+    },
+    GlobalChatLine(GlobalChatLine),
+    AllianceRoleChanged {
+        alliance_id: LogicLong,
+        role: i32,
+    },
+    AllianceSettingsChanged {
+        alliance_id: LogicLong,
+        badge_id: i32,
+    },
+    /// The session's credentials were revoked.
+    Disconnect,
+}
+
+/// The receiving end of a logged in session's events.
+pub struct Mailbox {
+    pub session_id: u64,
+    pub receiver: Receiver<SessionEvent>,
+}
+
+/// Who holds a home and since when.
+struct Attack {
+    attack_id: u64,
+    started_at: Instant,
+}
+
+impl Attack {
+    fn is_expired(&self) -> bool {
+        self.started_at.elapsed() >= OnlinePlayers::MAX_ATTACK_DURATION
+    }
+}
+
+#[derive(Default)]
+struct Presence {
+    sessions: HashMap<i64, Vec<(u64, Sender<SessionEvent>)>>,
+    under_attack: HashMap<i64, Attack>,
+    next_session_id: u64,
+}
+
+impl Presence {
+    /// The unexpired attack on the home, dropping an expired one.
+    fn get_attack(&mut self, id: i64) -> Option<&Attack> {
+        if self.under_attack.get(&id).is_some_and(Attack::is_expired) {
+            self.under_attack.remove(&id);
+        }
+
+        self.under_attack.get(&id)
+    }
+
+    fn next_id(&mut self) -> u64 {
+        let id = self.next_session_id;
+        self.next_session_id += 1;
+        id
+    }
+}
+
+/// Logged in sessions, with a mailbox each, and accounts whose home is
+/// being attacked or otherwise changed while they're offline. Both live
+/// under one lock so a defender can't log in between being matched and the
+/// attack starting.
+pub struct OnlinePlayers(Mutex<Presence>);
+
+impl OnlinePlayers {
+    /// Scouting, the battle itself and some slack for the client's last turn.
+    /// An attacker that goes quiet loses their hold on the home after this,
+    /// so the defender isn't locked out indefinitely.
+    pub const MAX_ATTACK_DURATION: Duration = Duration::from_secs(4 * 60);
+
+    pub fn new() -> Self {
+        Self(Mutex::new(Presence::default()))
+    }
+
+    /// Fails while the account's home is being attacked, with the time
+    /// until the attack expires at the latest.
+    pub fn try_add(&self, id: &LogicLong) -> Result<Mailbox, Duration> {
+        let mut presence = self.0.lock().unwrap();
+        if let Some(attack) = presence.get_attack(id.to_long()) {
+            return Err(Self::MAX_ATTACK_DURATION.saturating_sub(attack.started_at.elapsed()));
+        }
+
+        let session_id = presence.next_id();
+
+        let (sender, receiver) = mpsc::channel();
+        presence
+            .sessions
+            .entry(id.to_long())
+            .or_default()
+            .push((session_id, sender));
+
+        Ok(Mailbox {
+            session_id,
+            receiver,
+        })
+    }
+
+    pub fn remove(&self, id: &LogicLong, session_id: u64) {
+        let mut presence = self.0.lock().unwrap();
+        if let Some(sessions) = presence.sessions.get_mut(&id.to_long()) {
+            sessions.retain(|(id, _)| *id != session_id);
+            if sessions.is_empty() {
+                presence.sessions.remove(&id.to_long());
+            }
+        }
+    }
+
+    /// Delivers `event` to every session of the account. Returns `false` if
+    /// the account isn't online.
+    pub fn send(&self, id: &LogicLong, event: impl Fn() -> SessionEvent) -> bool {
+        let presence = self.0.lock().unwrap();
+        let Some(sessions) = presence.sessions.get(&id.to_long()) else {
+            return false;
+        };
+
+        for (_, sender) in sessions {
+            // The session is shutting down if its receiver is gone.
+            let _ = sender.send(event());
+        }
+
+        true
+    }
+
+    /// Delivers `event` to every logged in session.
+    pub fn send_to_all(&self, event: impl Fn() -> SessionEvent) {
+        let presence = self.0.lock().unwrap();
+        for (_, sender) in presence.sessions.values().flatten() {
+            let _ = sender.send(event());
+        }
+    }
+
+    pub fn is_online(&self, id: &LogicLong) -> bool {
+        self.0.lock().unwrap().sessions.contains_key(&id.to_long())
+    }
+
+    /// Fails if the defender is online or already being attacked. The
+    /// returned attack id ends the attack and checks it's still held.
+    pub fn try_begin_attack(&self, defender_id: &LogicLong) -> Option<u64> {
+        let mut presence = self.0.lock().unwrap();
+        let defender_id = defender_id.to_long();
+
+        if presence.sessions.contains_key(&defender_id) || presence.get_attack(defender_id).is_some() {
+            return None;
+        }
+
+        let attack_id = presence.next_id();
+        presence.under_attack.insert(
+            defender_id,
+            Attack {
+                attack_id,
+                started_at: Instant::now(),
+            },
+        );
+
+        Some(attack_id)
+    }
+
+    /// Whether the attack still holds the home, i.e. it hasn't expired and
+    /// the defender couldn't have logged in since it started.
+    pub fn is_attack_held(&self, defender_id: &LogicLong, attack_id: u64) -> bool {
+        let mut presence = self.0.lock().unwrap();
+        presence
+            .get_attack(defender_id.to_long())
+            .is_some_and(|attack| attack.attack_id == attack_id)
+    }
+
+    /// Does nothing if the attack expired and another one holds the home now.
+    pub fn end_attack(&self, defender_id: &LogicLong, attack_id: u64) {
+        let mut presence = self.0.lock().unwrap();
+        if presence
+            .under_attack
+            .get(&defender_id.to_long())
+            .is_some_and(|attack| attack.attack_id == attack_id)
+        {
+            presence.under_attack.remove(&defender_id.to_long());
+        }
+    }
+
+    /// Ids that matchmaking must not pick.
+    pub fn get_unavailable_ids(&self) -> Vec<i64> {
+        let presence = self.0.lock().unwrap();
+        presence
+            .sessions
+            .keys()
+            .copied()
+            .chain(
+                presence
+                    .under_attack
+                    .iter()
+                    .filter(|(_, attack)| !attack.is_expired())
+                    .map(|(id, _)| *id),
+            )
+            .collect()
+    }
+}
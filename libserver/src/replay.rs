@@ -0,0 +1,158 @@
+use serde_json::{Value, json};
+
+use crate::{
+    byte_stream::ByteStream,
+    helper::compress_in_zlib_format,
+    logic::{
+        avatar::LogicClientAvatar,
+        command::{LogicCommand, LogicCommandManager},
+        home::LogicClientHome,
+        json::{LogicJSONNode, LogicJSONParser},
+        mode::LogicGameMode,
+    },
+    sc_string::StringBuilder,
+};
+
+/// A multiplayer battle's start state and every command the attacker sent,
+/// enough for the client to play it back and for the server to simulate it
+/// again.
+pub struct BattleReplay {
+    home_json: String,
+    defender_avatar: Vec<u8>,
+    attacker_avatar: Vec<u8>,
+    timestamp: i32,
+    seconds_since_last_save: i32,
+    commands: Vec<ReplayCommand>,
+    end_sub_tick: i32,
+}
+
+struct ReplayCommand {
+    execute_sub_tick: i32,
+    json: Value,
+}
+
+impl BattleReplay {
+    pub fn new(
+        home_json: &str,
+        defender_avatar: &LogicClientAvatar,
+        attacker_avatar: &LogicClientAvatar,
+        timestamp: i32,
+        seconds_since_last_save: i32,
+    ) -> Self {
+        Self {
+            home_json: home_json.to_string(),
+            defender_avatar: Self::encode_avatar(defender_avatar),
+            attacker_avatar: Self::encode_avatar(attacker_avatar),
+            timestamp,
+            seconds_since_last_save,
+            commands: Vec::new(),
+            end_sub_tick: 0,
+        }
+    }
+
+    /// Must be called before the command is handed to the command manager,
+    /// which frees it once executed.
+    pub fn record_command(&mut self, command: &LogicCommand) {
+        self.commands.push(ReplayCommand {
+            execute_sub_tick: command.get_execute_sub_tick(),
+            json: Self::to_value(&LogicCommandManager::save_command_to_json(command)),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    pub fn set_end_sub_tick(&mut self, sub_tick: i32) {
+        self.end_sub_tick = sub_tick;
+    }
+
+    /// Replays the recorded commands on a fresh game mode loaded from the
+    /// start state, up to the sub tick the battle ended at.
+    pub fn simulate(&self) -> Option<LogicGameMode> {
+        let logic_game_mode = self.load_start_state();
+
+        for command in self.commands.iter() {
+            while logic_game_mode.get_level().get_time().sub_tick < command.execute_sub_tick {
+                logic_game_mode.update_one_sub_tick();
+            }
+
+            let json = LogicJSONParser::parse(&command.json.to_string())?;
+            logic_game_mode
+                .get_command_manager()
+                .add_command(&LogicCommandManager::load_command_from_json(&json)?);
+        }
+
+        while logic_game_mode.get_level().get_time().sub_tick < self.end_sub_tick {
+            logic_game_mode.update_one_sub_tick();
+        }
+
+        Some(logic_game_mode)
+    }
+
+    /// The battle as if the attacker had sent no commands, i.e. with nothing
+    /// lost or won on either side.
+    pub fn simulate_without_commands(&self) -> LogicGameMode {
+        let logic_game_mode = self.load_start_state();
+
+        while logic_game_mode.get_level().get_time().sub_tick < self.end_sub_tick {
+            logic_game_mode.update_one_sub_tick();
+        }
+
+        logic_game_mode
+    }
+
+    fn load_start_state(&self) -> LogicGameMode {
+        let mut logic_client_home = LogicClientHome::new();
+        logic_client_home.set_home_json(&self.home_json);
+
+        let mut logic_game_mode = LogicGameMode::new();
+        logic_game_mode.load_matched_attack_state(
+            &logic_client_home,
+            &Self::decode_avatar(&self.defender_avatar),
+            &Self::decode_avatar(&self.attacker_avatar),
+            self.seconds_since_last_save,
+        );
+        logic_game_mode.set_current_timestamp(self.timestamp);
+
+        logic_game_mode
+    }
+
+    /// Replay JSON in the zlib format HomeBattleReplayDataMessage carries.
+    pub fn compress(&self) -> Vec<u8> {
+        let replay = json!({
+            "level": serde_json::from_str::<Value>(&self.home_json).unwrap_or(Value::Null),
+            "defender": Self::save_avatar(&Self::decode_avatar(&self.defender_avatar)),
+            "attacker": Self::save_avatar(&Self::decode_avatar(&self.attacker_avatar)),
+            "timestamp": self.timestamp,
+            "end_tick": self.end_sub_tick,
+            "cmd": self.commands.iter().map(|command| &command.json).collect::<Vec<_>>(),
+        });
+
+        compress_in_zlib_format(replay.to_string().as_bytes())
+    }
+
+    fn encode_avatar(avatar: &LogicClientAvatar) -> Vec<u8> {
+        let mut byte_stream = ByteStream::new(10);
+        avatar.encode(&mut byte_stream);
+        byte_stream.get_byte_array().to_vec()
+    }
+
+    fn decode_avatar(data: &[u8]) -> LogicClientAvatar {
+        let mut avatar = LogicClientAvatar::new();
+        avatar.decode(&mut ByteStream::from(data));
+        avatar
+    }
+
+    fn save_avatar(avatar: &LogicClientAvatar) -> Value {
+        let mut json = LogicJSONNode::new_json_object();
+        avatar.save_to_replay(&mut json);
+        Self::to_value(&json)
+    }
+
+    fn to_value(json: &LogicJSONNode) -> Value {
+        let mut string_builder = StringBuilder::new();
+        json.write_to_string(&mut string_builder);
+        serde_json::from_str(&string_builder.to_string()).unwrap_or(Value::Null)
+    }
+}
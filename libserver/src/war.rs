@@ -0,0 +1,288 @@
+use std::{thread, time::Duration};
+
+use tracing::{error, info, warn};
+
+use crate::{
+    ServerState,
+    database::{
+        DatabaseConnection, PlayerSaveData, WarResult, WarSearchData, WarSetup, WarSideSetup,
+    },
+    logic::data::{LogicData, LogicDataTables, LogicWarData},
+    math::LogicLong,
+    time_util,
+};
+
+/// Attacks each war member gets during the battle day.
+pub const ATTACKS_PER_MEMBER: i32 = 2;
+
+/// Gold and elixir paid per star earned, to the winners and to everyone else.
+const WIN_LOOT_BONUS_PER_STAR: i32 = 20_000;
+const LOOT_BONUS_PER_STAR: i32 = 10_000;
+
+const UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long an alliance waits for a real opponent before it gets a bot one.
+const BOT_MATCH_DELAY: i64 = 60;
+
+const BOT_ALLIANCE_NAMES: &[&str] = &[
+    "Goblin Raiders",
+    "Barbarian Horde",
+    "Wizard Tower",
+    "Pekka Knights",
+    "Dragon Riders",
+    "Hog Riders",
+];
+
+/// A war attack in progress: the attacker and the base they attack.
+pub struct WarAttack {
+    pub war_id: i64,
+    pub side: i32,
+    pub position: i32,
+    pub defender_position: i32,
+}
+
+/// Advances wars through their days and pairs searching alliances, forever.
+pub fn run_timer(state: &ServerState) {
+    info!("war timer started");
+
+    loop {
+        thread::sleep(UPDATE_INTERVAL);
+        update(&state.db);
+    }
+}
+
+/// The largest war size of `logic/war.csv` the alliance has enough members for.
+pub fn get_war_data(member_count: i32) -> Option<LogicWarData> {
+    LogicWarData::get_all()
+        .into_iter()
+        .filter(|war_data| war_data.get_team_size() <= member_count)
+        .max_by_key(LogicWarData::get_team_size)
+}
+
+fn update(db: &DatabaseConnection) {
+    let timestamp = time_util::get_current_timestamp();
+
+    match db.start_war_battle_days(timestamp) {
+        Ok(0) => (),
+        Ok(count) => info!("{count} war(s) entered their battle day"),
+        Err(err) => error!("failed to start war battle days: {err}"),
+    }
+
+    match db.fetch_finished_war_ids(timestamp) {
+        Ok(war_ids) => war_ids.into_iter().for_each(|war_id| finish_war(db, war_id)),
+        Err(err) => error!("failed to fetch finished wars: {err}"),
+    }
+
+    match db.fetch_war_searches() {
+        Ok(searches) => match_searches(db, searches, timestamp),
+        Err(err) => error!("failed to fetch war searches: {err}"),
+    }
+}
+
+/// More stars win, then higher destruction.
+fn finish_war(db: &DatabaseConnection, war_id: i64) {
+    use std::cmp::Ordering;
+
+    let war = match db.fetch_war(war_id) {
+        Ok(Some(war)) if war.sides.len() == 2 => war,
+        Ok(_) => {
+            error!("war {war_id} is missing a side");
+            return;
+        }
+        Err(err) => {
+            error!("failed to fetch war {war_id}: {err}");
+            return;
+        }
+    };
+
+    let (first, second) = (&war.sides[0], &war.sides[1]);
+    let results = match (first.stars, first.destruction_percentage)
+        .cmp(&(second.stars, second.destruction_percentage))
+    {
+        Ordering::Greater => [WarResult::Won, WarResult::Lost],
+        Ordering::Less => [WarResult::Lost, WarResult::Won],
+        Ordering::Equal => [WarResult::Draw, WarResult::Draw],
+    };
+
+    let loot_bonus_per_star = results.map(|result| match result {
+        WarResult::Won => WIN_LOOT_BONUS_PER_STAR,
+        _ => LOOT_BONUS_PER_STAR,
+    });
+
+    match db.finish_war(war_id, results, loot_bonus_per_star) {
+        Ok(()) => info!(
+            "war {war_id} is over: {} {} ({}%) - {} {} ({}%)",
+            first.name,
+            first.stars,
+            first.destruction_percentage,
+            second.name,
+            second.stars,
+            second.destruction_percentage
+        ),
+        Err(err) => error!("failed to finish war {war_id}: {err}"),
+    }
+}
+
+/// Pairs searches of the same war size, oldest first. Searches left
+/// unpaired for too long fight a bot alliance instead.
+fn match_searches(db: &DatabaseConnection, mut searches: Vec<WarSearchData>, timestamp: i64) {
+    while !searches.is_empty() {
+        let search = searches.remove(0);
+
+        let opponent_index = searches.iter().position(|opponent| {
+            opponent.war_data_id == search.war_data_id && opponent.team_size == search.team_size
+        });
+
+        match opponent_index {
+            Some(index) => {
+                let opponent = searches.remove(index);
+                start_war(db, &search, Some(&opponent));
+            }
+            None if timestamp - search.started_at >= BOT_MATCH_DELAY => {
+                start_war(db, &search, None)
+            }
+            None => (),
+        }
+    }
+}
+
+fn start_war(db: &DatabaseConnection, search: &WarSearchData, opponent: Option<&WarSearchData>) {
+    let Some(war_data) = LogicDataTables::get_data_by_id(search.war_data_id) else {
+        error!("war search of {} has unknown war data {}", search.alliance_id, search.war_data_id);
+        return;
+    };
+    let war_data = LogicWarData(war_data.0);
+
+    // A search that can't become a side would otherwise be retried forever.
+    let Some(mut first_side) = create_alliance_side(db, &search.alliance_id, search.team_size) else {
+        cancel_war_search(db, &search.alliance_id);
+        return;
+    };
+
+    let mut second_side = match opponent {
+        Some(opponent) => {
+            let Some(side) = create_alliance_side(db, &opponent.alliance_id, opponent.team_size)
+            else {
+                cancel_war_search(db, &opponent.alliance_id);
+                return;
+            };
+            side
+        }
+        None => create_bot_side(db, &search.alliance_id, &first_side.members),
+    };
+
+    // Members may have left since the search started.
+    let team_size = first_side.members.len().min(second_side.members.len());
+    if team_size == 0 {
+        warn!("war of {} has no members on one side", search.alliance_id);
+        for side in [&first_side, &second_side].into_iter().filter(|side| side.members.is_empty()) {
+            if let Some(alliance_id) = &side.alliance_id {
+                cancel_war_search(db, alliance_id);
+            }
+        }
+        return;
+    }
+    first_side.members.truncate(team_size);
+    second_side.members.truncate(team_size);
+
+    const MINUTE: i64 = 60;
+
+    let timestamp = time_util::get_current_timestamp();
+    let battle_start_timestamp = timestamp + war_data.get_preparation_minutes() as i64 * MINUTE;
+
+    let setup = WarSetup {
+        war_data_id: search.war_data_id,
+        battle_start_timestamp,
+        end_timestamp: battle_start_timestamp + war_data.get_war_minutes() as i64 * MINUTE,
+        sides: [first_side, second_side],
+    };
+
+    match db.create_war(&setup) {
+        Ok(war_id) => info!(
+            "war {war_id} started: {} vs {}, {team_size} members each",
+            setup.sides[0].name, setup.sides[1].name
+        ),
+        Err(err) => error!("failed to create war of {}: {err}", search.alliance_id),
+    }
+}
+
+fn cancel_war_search(db: &DatabaseConnection, alliance_id: &LogicLong) {
+    match db.cancel_war_search(alliance_id) {
+        Ok(()) => info!("war search of {alliance_id} cancelled"),
+        Err(err) => error!("failed to cancel war search of {alliance_id}: {err}"),
+    }
+}
+
+fn create_alliance_side(
+    db: &DatabaseConnection,
+    alliance_id: &LogicLong,
+    team_size: i32,
+) -> Option<WarSideSetup> {
+    let alliance = match db.fetch_alliance(alliance_id) {
+        Ok(Some(alliance)) => alliance,
+        Ok(None) => {
+            warn!("searching alliance {alliance_id} no longer exists");
+            return None;
+        }
+        Err(err) => {
+            error!("failed to fetch alliance {alliance_id}: {err}");
+            return None;
+        }
+    };
+
+    let members = match db.fetch_war_team(alliance_id, team_size) {
+        Ok(members) => members,
+        Err(err) => {
+            error!("failed to fetch war team of {alliance_id}: {err}");
+            return None;
+        }
+    };
+
+    Some(WarSideSetup {
+        alliance_id: Some(alliance.id),
+        name: alliance.name,
+        badge_id: alliance.settings.badge_id,
+        members,
+    })
+}
+
+/// Defended by the players closest in score to the alliance, and by copies
+/// of its own members if there aren't enough of them.
+fn create_bot_side(
+    db: &DatabaseConnection,
+    alliance_id: &LogicLong,
+    alliance_members: &[PlayerSaveData],
+) -> WarSideSetup {
+    let team_size = alliance_members.len();
+    let average_score = alliance_members
+        .iter()
+        .map(|member| member.score)
+        .sum::<i32>()
+        .checked_div(team_size as i32)
+        .unwrap_or(0);
+
+    let mut members = db
+        .fetch_war_bot_team(alliance_id, average_score, team_size as i32)
+        .unwrap_or_else(|err| {
+            error!("failed to fetch bot war team against {alliance_id}: {err}");
+            Vec::new()
+        });
+
+    members.extend(alliance_members.iter().skip(members.len()).cloned());
+    members.sort_by_key(|member| std::cmp::Reverse(member.score));
+
+    let name = BOT_ALLIANCE_NAMES[rand::random_range(0..BOT_ALLIANCE_NAMES.len())];
+
+    let badges = LogicDataTables::get_table(LogicDataTables::ALLIANCE_BADGE);
+    let badge_id = match badges.get_item_count() {
+        0 => 0,
+        count => LogicData(badges.get_item_at(rand::random_range(0..count))).get_global_id(),
+    };
+
+    WarSideSetup {
+        alliance_id: None,
+        name: name.to_string(),
+        badge_id,
+        members,
+    }
+}